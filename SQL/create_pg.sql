@@ -31,13 +31,40 @@ CREATE TABLE IF NOT EXISTS logs
 --  Create users table
 CREATE TABLE IF NOT EXISTS users
 (
-	id                  UUID DEFAULT gen_random_uuid() UNIQUE PRIMARY KEY,
-	foreign_instance_id VARCHAR,
-	foreign_user_id     UUID,
-	email               VARCHAR NOT NULL UNIQUE,
-	username            VARCHAR NOT NULL UNIQUE,
-	password            VARCHAR NOT NULL
+	id                     UUID DEFAULT gen_random_uuid() UNIQUE PRIMARY KEY,
+	foreign_instance_id    VARCHAR,
+	foreign_user_id        UUID,
+	email                  VARCHAR NOT NULL UNIQUE,
+	username               VARCHAR NOT NULL UNIQUE,
+	password               VARCHAR NOT NULL,
+	-- Registration inserts FALSE explicitly, the default covers accounts from before email verification.
+	email_verified         BOOLEAN NOT NULL DEFAULT TRUE,
+	-- Accounts registered while registration requires approval can't log in until approved.
+	pending_approval       BOOLEAN NOT NULL DEFAULT FALSE,
+	-- What usernames are unique by, see username_policy.rs. Filled in on startup for older accounts,
+	-- which may share one, so it is indexed but not UNIQUE.
+	username_skeleton      VARCHAR,
+	-- Set when the owner asked for the account to be deleted, which happens once this has passed.
+	deletion_scheduled_for TIMESTAMP WITH TIME ZONE,
+	-- 'username@instance' of the account this one moved to. Profile lookups are redirected there.
+	moved_to               VARCHAR,
+	-- 'user', 'moderator' or 'admin'
+	role                   VARCHAR NOT NULL DEFAULT 'user',
+	-- Suspended accounts can't log in until this has passed. 'infinity' for indefinite suspensions.
+	suspended_until        TIMESTAMP WITH TIME ZONE,
+	-- Set when an admin deactivated the account. It can't log in until it is reactivated.
+	deactivated_at         TIMESTAMP WITH TIME ZONE
 );
+ALTER TABLE users
+	ADD COLUMN IF NOT EXISTS email_verified         BOOLEAN NOT NULL DEFAULT TRUE,
+	ADD COLUMN IF NOT EXISTS pending_approval       BOOLEAN NOT NULL DEFAULT FALSE,
+	ADD COLUMN IF NOT EXISTS username_skeleton      VARCHAR,
+	ADD COLUMN IF NOT EXISTS deletion_scheduled_for TIMESTAMP WITH TIME ZONE,
+	ADD COLUMN IF NOT EXISTS moved_to               VARCHAR,
+	ADD COLUMN IF NOT EXISTS role                   VARCHAR NOT NULL DEFAULT 'user',
+	ADD COLUMN IF NOT EXISTS suspended_until        TIMESTAMP WITH TIME ZONE,
+	ADD COLUMN IF NOT EXISTS deactivated_at         TIMESTAMP WITH TIME ZONE;
+CREATE INDEX IF NOT EXISTS users_username_skeleton ON users (username_skeleton);
 
 -- Create timelines table
 CREATE TABLE IF NOT EXISTS timelines
@@ -58,11 +85,22 @@ CREATE TABLE IF NOT EXISTS itemtypelookupdb
 -- Create sesions table
 CREATE TABLE IF NOT EXISTS sessions
 (
-	id          UUID                              DEFAULT gen_random_uuid() PRIMARY KEY,
-	user_id     UUID                     NOT NULL REFERENCES users (id) ON DELETE CASCADE,
-	session_key VARCHAR                  NOT NULL UNIQUE,
-	created_at  TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+	id           UUID                              DEFAULT gen_random_uuid() PRIMARY KEY,
+	user_id      UUID                     NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	-- Keyed hash of the session token, the token itself is only known to the client.
+	token_hash   VARCHAR                  NOT NULL UNIQUE,
+	created_at   TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	-- Sessions expire after a period of not being used, rather than a fixed time after creation.
+	last_used_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	user_agent   VARCHAR,
+	ip_address   INET
 );
+-- Databases from before token hashing get this column filled in and `session_key` dropped on startup.
+ALTER TABLE sessions
+	ADD COLUMN IF NOT EXISTS token_hash   VARCHAR UNIQUE,
+	ADD COLUMN IF NOT EXISTS last_used_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	ADD COLUMN IF NOT EXISTS user_agent   VARCHAR,
+	ADD COLUMN IF NOT EXISTS ip_address   INET;
 
 -- Create table for posts in text type
 CREATE TABLE IF NOT EXISTS post_text
@@ -72,8 +110,12 @@ CREATE TABLE IF NOT EXISTS post_text
 	content             TEXT                     NOT NULL,
 	created_at          TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
 	foreign_instance_id VARCHAR,
-	foreign_post_id     VARCHAR
+	foreign_post_id     VARCHAR,
+	-- Set by filter rules, clients show these behind a warning.
+	sensitive           BOOLEAN                  NOT NULL DEFAULT FALSE
 );
+ALTER TABLE post_text
+	ADD COLUMN IF NOT EXISTS sensitive BOOLEAN NOT NULL DEFAULT FALSE;
 
 -- Create table for posts of media type
 CREATE TABLE IF NOT EXISTS post_media
@@ -84,8 +126,12 @@ CREATE TABLE IF NOT EXISTS post_media
 	caption             TEXT,
 	created_at          TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
 	foreign_instance_id VARCHAR,
-	foreign_post_id     VARCHAR
+	foreign_post_id     VARCHAR,
+	-- Set by filter rules, clients show these behind a warning.
+	sensitive           BOOLEAN                  NOT NULL DEFAULT FALSE
 );
+ALTER TABLE post_media
+	ADD COLUMN IF NOT EXISTS sensitive BOOLEAN NOT NULL DEFAULT FALSE;
 
 -- Create table for posts of article type
 CREATE TABLE IF NOT EXISTS post_article
@@ -96,5 +142,165 @@ CREATE TABLE IF NOT EXISTS post_article
 	content             TEXT                     NOT NULL,
 	created_at          TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
 	foreign_instance_id VARCHAR,
-	foreign_post_id     VARCHAR
+	foreign_post_id     VARCHAR,
+	-- Set by filter rules, clients show these behind a warning.
+	sensitive           BOOLEAN                  NOT NULL DEFAULT FALSE
+);
+ALTER TABLE post_article
+	ADD COLUMN IF NOT EXISTS sensitive BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Create table for follows between users
+CREATE TABLE IF NOT EXISTS follows
+(
+	follower_id UUID                     NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	followee_id UUID                     NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	created_at  TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	PRIMARY KEY (follower_id, followee_id)
+);
+
+-- Create table for blocks. Blocked users can't interact with the blocker, nor the other way around.
+CREATE TABLE IF NOT EXISTS user_blocks
+(
+	blocker_id UUID                     NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	blocked_id UUID                     NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	PRIMARY KEY (blocker_id, blocked_id)
+);
+CREATE INDEX IF NOT EXISTS user_blocks_blocked ON user_blocks (blocked_id);
+
+-- Create table for mutes. Muted users' posts are hidden from the muter, nothing else changes.
+CREATE TABLE IF NOT EXISTS user_mutes
+(
+	muter_id   UUID                     NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	muted_id   UUID                     NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	PRIMARY KEY (muter_id, muted_id)
+);
+
+-- Create table for reports, which make up the moderation queue. Reports without resolved_at are open.
+CREATE TABLE IF NOT EXISTS reports
+(
+	id             UUID PRIMARY KEY                  DEFAULT gen_random_uuid(),
+	reporter_id    UUID REFERENCES users (id) ON DELETE SET NULL,
+	-- The reported user, or the author of the reported item
+	target_user_id UUID REFERENCES users (id) ON DELETE CASCADE,
+	target_item_id UUID,
+	reason         VARCHAR                  NOT NULL,
+	comment        TEXT                     NOT NULL DEFAULT '',
+	created_at     TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	resolved_at    TIMESTAMP WITH TIME ZONE,
+	resolved_by    UUID REFERENCES users (id) ON DELETE SET NULL,
+	-- The action taken, see moderation.rs
+	resolution     VARCHAR
+);
+CREATE INDEX IF NOT EXISTS reports_open ON reports (created_at) WHERE resolved_at IS NULL;
+
+-- Create table for items hidden by moderators. They stay in place, but aren't shown to anyone.
+CREATE TABLE IF NOT EXISTS hidden_items
+(
+	item_id   UUID PRIMARY KEY,
+	hidden_by UUID REFERENCES users (id) ON DELETE SET NULL,
+	hidden_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Create table for filter rules, checked against all new and incoming posts
+CREATE TABLE IF NOT EXISTS filter_rules
+(
+	id         UUID PRIMARY KEY                  DEFAULT gen_random_uuid(),
+	-- A keyword, or a regular expression when is_regex is set
+	pattern    VARCHAR                  NOT NULL,
+	is_regex   BOOLEAN                  NOT NULL DEFAULT FALSE,
+	-- 'reject', 'hold' or 'mark_sensitive'
+	action     VARCHAR                  NOT NULL,
+	created_by UUID REFERENCES users (id) ON DELETE SET NULL,
+	created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Create table for follows of accounts on other instances, established when syncing with that instance
+CREATE TABLE IF NOT EXISTS remote_follows
+(
+	follower_id UUID                     NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	instance    VARCHAR                  NOT NULL,
+	username    VARCHAR                  NOT NULL,
+	created_at  TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	PRIMARY KEY (follower_id, instance, username)
+);
+
+-- Create table for data export jobs. Rows without completed_at are waiting to be built.
+CREATE TABLE IF NOT EXISTS data_exports
+(
+	id           UUID PRIMARY KEY                  DEFAULT gen_random_uuid(),
+	user_id      UUID                     NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	requested_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	completed_at TIMESTAMP WITH TIME ZONE,
+	-- Keyed hash of the download token, like sessions.token_hash. Set once the archive is built.
+	token_hash   VARCHAR UNIQUE,
+	-- The archive is deleted after this
+	expires_at   TIMESTAMP WITH TIME ZONE,
+	-- Failed attempts at building the archive
+	attempts     INTEGER                  NOT NULL DEFAULT 0
+);
+
+-- Create table for outgoing email, drained by the mail worker
+CREATE TABLE IF NOT EXISTS email_outbox
+(
+	id            UUID PRIMARY KEY                  DEFAULT gen_random_uuid(),
+	recipient     VARCHAR                  NOT NULL,
+	subject       VARCHAR                  NOT NULL,
+	body          TEXT                     NOT NULL,
+	attempts      INTEGER                  NOT NULL DEFAULT 0,
+	last_error    TEXT,
+	created_at    TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	sent_at       TIMESTAMP WITH TIME ZONE,
+	claimed_until TIMESTAMP WITH TIME ZONE
+);
+
+-- Create table for email verification tokens
+CREATE TABLE IF NOT EXISTS email_verification_tokens
+(
+	-- Keyed hash of the token, like sessions.token_hash
+	token      VARCHAR PRIMARY KEY,
+	user_id    UUID                     NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- Create table for password reset tokens
+CREATE TABLE IF NOT EXISTS password_reset_tokens
+(
+	-- Keyed hash of the token, like sessions.token_hash
+	token      VARCHAR PRIMARY KEY,
+	user_id    UUID                     NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- Create table for TOTP second factors. Until confirmed_at is set, the row is an enrolment in progress.
+CREATE TABLE IF NOT EXISTS user_totp
+(
+	user_id        UUID PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+	-- Base32 shared secret. Has to be stored as-is to compute codes from it.
+	secret         VARCHAR NOT NULL,
+	confirmed_at   TIMESTAMP WITH TIME ZONE,
+	-- Last TOTP time step a code was accepted for, so codes can't be replayed
+	last_used_step BIGINT
+);
+
+-- Create table for two-factor recovery codes. Used codes are deleted.
+CREATE TABLE IF NOT EXISTS user_recovery_codes
+(
+	user_id   UUID    NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+	-- Keyed hash of the code, like sessions.token_hash
+	code_hash VARCHAR NOT NULL,
+	PRIMARY KEY (user_id, code_hash)
+);
+
+-- Create table for invite codes, used when registration is invite-only
+CREATE TABLE IF NOT EXISTS invite_codes
+(
+	-- Keyed hash of the code, like sessions.token_hash
+	code_hash  VARCHAR PRIMARY KEY,
+	max_uses   INTEGER                  NOT NULL DEFAULT 1,
+	uses       INTEGER                  NOT NULL DEFAULT 0,
+	created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	-- NULL for codes that don't expire
+	expires_at TIMESTAMP WITH TIME ZONE
 );
@@ -0,0 +1,526 @@
+//! Lumina > Server > Account data
+//!
+//! Exporting everything an account holds, and deleting accounts once their grace period is over.
+//!
+//! Exports are jobs: asking for one queues a row in `data_exports`, which the maintenance loop in
+//! [`crate::database::maintain`] builds into a tar archive under `exports/`. The archive holds
+//! `account.json`, with the profile, posts, follows and sessions, and the files of the account's
+//! media posts under `media/`. The owner is mailed a link to download it, which works until the
+//! archive expires.
+
+/*
+ *     Lumina/Peonies
+ *     Copyright (C) 2018-2026 MLC 'Strawmelonjuice'  Bloeiman and contributors.
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::connections;
+use crate::database::DbConn;
+use crate::errors::LuminaError;
+use crate::helpers::events::EventLogger;
+use crate::helpers::tokens;
+use crate::user::{SessionInfo, User};
+use crate::{
+    AppState, email, error_elog, http_code_elog, info_elog, migration, timeline, two_factor,
+};
+use rocket::State;
+use rocket::fs::NamedFile;
+use rocket::http::Header;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Where finished archives are kept until they expire.
+const EXPORT_DIR: &str = "./exports";
+
+/// Where media files are stored, named by their object id.
+pub(crate) const MEDIA_DIR: &str = "./media";
+
+/// How often an account may ask for an export.
+const EXPORT_COOLDOWN_HOURS: i32 = 24;
+
+/// How long the download link of a finished export works.
+const EXPORT_DOWNLOAD_DAYS: i32 = 7;
+
+/// After this many failed attempts, an export is given up on.
+const EXPORT_MAX_ATTEMPTS: i32 = 5;
+
+/// The contents of `account.json` in an export archive.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) struct AccountExport {
+    /// Unix timestamp of the moment the export was built.
+    pub(crate) exported_at: i64,
+    /// The instance the account was exported from, as in `LUMINA_SYNC_IID`.
+    pub(crate) instance: String,
+    pub(crate) profile: ExportedProfile,
+    pub(crate) posts: Vec<ExportedPost>,
+    /// Usernames of the accounts this account follows.
+    pub(crate) following: Vec<String>,
+    /// Usernames of the accounts following this account.
+    pub(crate) followers: Vec<String>,
+    pub(crate) sessions: Vec<SessionInfo>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) struct ExportedProfile {
+    pub(crate) id: Uuid,
+    pub(crate) username: String,
+    pub(crate) email: String,
+    pub(crate) email_verified: bool,
+    pub(crate) two_factor_enabled: bool,
+}
+
+/// A post, with Unix timestamps.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum ExportedPost {
+    Text {
+        id: Uuid,
+        created_at: i64,
+        content: String,
+    },
+    Media {
+        id: Uuid,
+        created_at: i64,
+        caption: Option<String>,
+        object_id: String,
+        /// Path of the media file within the archive. Absent if this instance doesn't have
+        /// the file.
+        file: Option<String>,
+    },
+    Article {
+        id: Uuid,
+        created_at: i64,
+        title: String,
+        content: String,
+    },
+}
+
+/// Queue an export of this user's data. Returns whether it was queued, which it isn't if the
+/// user already asked for one recently.
+pub(crate) async fn request_export(user: &User, db: &DbConn) -> Result<bool, LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            let queued = client
+                .execute(
+                    "INSERT INTO data_exports (user_id) SELECT $1 WHERE NOT EXISTS (SELECT 1 FROM data_exports WHERE user_id = $1 AND requested_at > NOW() - make_interval(hours => $2))",
+                    &[&user.id, &EXPORT_COOLDOWN_HOURS],
+                )
+                .await?;
+            Ok(queued == 1)
+        }
+    }
+}
+
+/// The media file stored for an object id, if there is one. Object ids that aren't plain file
+/// names are never looked up.
+pub(crate) fn media_file(object_id: &str) -> Option<PathBuf> {
+    if Path::new(object_id).file_name()? != object_id {
+        return None;
+    }
+    let path = Path::new(MEDIA_DIR).join(object_id);
+    path.is_file().then_some(path)
+}
+
+/// Gather everything to export about a user.
+async fn collect(user: &User, db: &DbConn) -> Result<AccountExport, LuminaError> {
+    let sessions = user.list_sessions(db).await?;
+    let two_factor_enabled = two_factor::is_enabled(user.id, db).await?;
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            let email_verified: bool = client
+                .query_one(
+                    "SELECT email_verified FROM users WHERE id = $1",
+                    &[&user.id],
+                )
+                .await?
+                .get(0);
+            let mut posts = Vec::new();
+            for row in client
+                .query(
+                    "SELECT id, EXTRACT(EPOCH FROM created_at)::BIGINT, content FROM post_text WHERE author_id = $1 ORDER BY created_at",
+                    &[&user.id],
+                )
+                .await?
+            {
+                posts.push(ExportedPost::Text {
+                    id: row.get(0),
+                    created_at: row.get(1),
+                    content: row.get(2),
+                });
+            }
+            for row in client
+                .query(
+                    "SELECT id, EXTRACT(EPOCH FROM created_at)::BIGINT, caption, minio_object_id FROM post_media WHERE author_id = $1 ORDER BY created_at",
+                    &[&user.id],
+                )
+                .await?
+            {
+                let object_id: String = row.get(3);
+                posts.push(ExportedPost::Media {
+                    id: row.get(0),
+                    created_at: row.get(1),
+                    caption: row.get(2),
+                    file: media_file(&object_id).map(|_| format!("media/{}", object_id)),
+                    object_id,
+                });
+            }
+            for row in client
+                .query(
+                    "SELECT id, EXTRACT(EPOCH FROM created_at)::BIGINT, title, content FROM post_article WHERE author_id = $1 ORDER BY created_at",
+                    &[&user.id],
+                )
+                .await?
+            {
+                posts.push(ExportedPost::Article {
+                    id: row.get(0),
+                    created_at: row.get(1),
+                    title: row.get(2),
+                    content: row.get(3),
+                });
+            }
+            let following = client
+                .query(
+                    "SELECT users.username FROM follows JOIN users ON users.id = follows.followee_id WHERE follows.follower_id = $1 ORDER BY users.username",
+                    &[&user.id],
+                )
+                .await?
+                .into_iter()
+                .map(|row| row.get(0))
+                .collect();
+            let followers = client
+                .query(
+                    "SELECT users.username FROM follows JOIN users ON users.id = follows.follower_id WHERE follows.followee_id = $1 ORDER BY users.username",
+                    &[&user.id],
+                )
+                .await?
+                .into_iter()
+                .map(|row| row.get(0))
+                .collect();
+            Ok(AccountExport {
+                exported_at: time::OffsetDateTime::now_utc().unix_timestamp(),
+                instance: migration::own_instance(),
+                profile: ExportedProfile {
+                    id: user.id,
+                    username: user.username.clone(),
+                    email: user.email.clone(),
+                    email_verified,
+                    two_factor_enabled,
+                },
+                posts,
+                following,
+                followers,
+                sessions,
+            })
+        }
+    }
+}
+
+/// Write an export to a tar archive at `path`, taking the media files from `media_dir`.
+pub(crate) fn write_archive(
+    path: &Path,
+    export: &AccountExport,
+    media_dir: &Path,
+) -> Result<(), LuminaError> {
+    let mut archive = tar::Builder::new(std::fs::File::create(path)?);
+    let json = serde_json::to_vec_pretty(export)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(json.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(export.exported_at.max(0) as u64);
+    archive.append_data(&mut header, "account.json", json.as_slice())?;
+    for post in &export.posts {
+        if let ExportedPost::Media {
+            object_id,
+            file: Some(file),
+            ..
+        } = post
+        {
+            archive.append_path_with_name(media_dir.join(object_id), file)?;
+        }
+    }
+    archive.into_inner()?;
+    Ok(())
+}
+
+fn archive_path(export_id: Uuid) -> PathBuf {
+    Path::new(EXPORT_DIR).join(format!("{}.tar", export_id))
+}
+
+/// Build the archives of all queued exports, and mail their owners the download links.
+///
+/// An export that fails is logged and retried on the next run, so it doesn't hold up the ones
+/// queued after it, until it has failed [`EXPORT_MAX_ATTEMPTS`] times.
+pub(crate) async fn build_pending_exports(
+    db: &DbConn,
+    ev_log: &EventLogger,
+) -> Result<(), LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            let queued = client
+                .query(
+                    "SELECT id, user_id FROM data_exports WHERE completed_at IS NULL AND attempts < $1 ORDER BY requested_at",
+                    &[&EXPORT_MAX_ATTEMPTS],
+                )
+                .await?;
+            for row in queued {
+                let export_id: Uuid = row.get(0);
+                match build_export(export_id, row.get(1), db).await {
+                    Ok(username) => {
+                        info_elog!(ev_log, "Built data export for user {}.", username);
+                    }
+                    Err(e) => {
+                        error_elog!(ev_log, "While building data export {}: {:?}", export_id, e);
+                        client
+                            .execute(
+                                "UPDATE data_exports SET attempts = attempts + 1 WHERE id = $1",
+                                &[&export_id],
+                            )
+                            .await?;
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Build one queued export and mail its owner the download link. Returns the owner's username.
+async fn build_export(export_id: Uuid, user_id: Uuid, db: &DbConn) -> Result<String, LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let user = User::get_user_by_id(user_id, db).await?;
+            let export = collect(&user, db).await?;
+            let path = archive_path(export_id);
+            tokio::task::spawn_blocking(move || {
+                std::fs::create_dir_all(EXPORT_DIR)?;
+                write_archive(&path, &export, Path::new(MEDIA_DIR))
+            })
+            .await
+            .map_err(|_| LuminaError::JoinFaillure)??;
+            let token = tokens::generate_token();
+            let mut client = pg_pool.get().await?;
+            // Completed and mailed together, so a failure can't leave an export nobody hears of.
+            let transaction = client.transaction().await?;
+            transaction
+                .execute(
+                    "UPDATE data_exports SET completed_at = NOW(), token_hash = $2, expires_at = NOW() + make_interval(days => $3) WHERE id = $1",
+                    &[&export_id, &tokens::hash_token(&token), &EXPORT_DOWNLOAD_DAYS],
+                )
+                .await?;
+            let body = format!(
+                "Hi {},\n\nThe export of your Lumina account you asked for is ready. Download it here:\n\n{}/export/{}\n\nThis link expires in {} days. If you did not ask for this, change your password.",
+                user.username,
+                email::instance_base_url(),
+                token,
+                EXPORT_DOWNLOAD_DAYS
+            );
+            email::enqueue_with(&transaction, &user.email, "Your Lumina data export", &body)
+                .await?;
+            transaction.commit().await?;
+            Ok(user.username)
+        }
+    }
+}
+
+/// Delete the archives of exports whose download link has expired, and forget exports that
+/// were given up on once the account may ask again.
+pub(crate) async fn remove_expired_exports(db: &DbConn) -> Result<(), LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            let expired = client
+                .query(
+                    "DELETE FROM data_exports WHERE expires_at < NOW() OR (attempts >= $1 AND requested_at < NOW() - make_interval(hours => $2)) RETURNING id",
+                    &[&EXPORT_MAX_ATTEMPTS, &EXPORT_COOLDOWN_HOURS],
+                )
+                .await?;
+            for row in expired {
+                let _ = tokio::fs::remove_file(archive_path(row.get(0))).await;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Delete every account whose scheduled deletion has come.
+pub(crate) async fn delete_due_accounts(
+    db: &DbConn,
+    ev_log: &EventLogger,
+) -> Result<(), LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let due = pg_pool
+                .get()
+                .await?
+                .query(
+                    "SELECT id, username FROM users WHERE deletion_scheduled_for < NOW()",
+                    &[],
+                )
+                .await?;
+            for row in due {
+                let username: String = row.get(1);
+                match delete_account(row.get(0), db, ev_log).await {
+                    Ok(()) => info_elog!(ev_log, "Deleted the account of {}.", username),
+                    Err(e) => error_elog!(
+                        ev_log,
+                        "While deleting the account of {}: {:?}",
+                        username,
+                        e
+                    ),
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Delete an account with everything it posted, taking its posts off every timeline.
+///
+/// Sessions, follows, tokens and the like go along through `ON DELETE CASCADE`; posts don't,
+/// as they also have to leave the timelines and the item type lookup.
+pub(crate) async fn delete_account(
+    user_id: Uuid,
+    db: &DbConn,
+    ev_log: &EventLogger,
+) -> Result<(), LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, redis_pool) => {
+            let mut client = pg_pool.get().await?;
+            let transaction = client.transaction().await?;
+            let post_ids: Vec<Uuid> = transaction
+                .query(
+                    "SELECT id FROM post_text WHERE author_id = $1 UNION ALL SELECT id FROM post_media WHERE author_id = $1 UNION ALL SELECT id FROM post_article WHERE author_id = $1",
+                    &[&user_id],
+                )
+                .await?
+                .into_iter()
+                .map(|row| row.get(0))
+                .collect();
+            let mut timeline_ids: Vec<Uuid> = transaction
+                .query(
+                    "DELETE FROM timelines WHERE item_id = ANY($1) RETURNING tlid",
+                    &[&post_ids],
+                )
+                .await?
+                .into_iter()
+                .map(|row| row.get(0))
+                .collect();
+            timeline_ids.sort();
+            timeline_ids.dedup();
+            transaction
+                .execute(
+                    "DELETE FROM itemtypelookupdb WHERE item_id = ANY($1)",
+                    &[&post_ids],
+                )
+                .await?;
+            transaction
+                .execute(
+                    "DELETE FROM hidden_items WHERE item_id = ANY($1)",
+                    &[&post_ids],
+                )
+                .await?;
+            transaction
+                .execute("DELETE FROM post_text WHERE author_id = $1", &[&user_id])
+                .await?;
+            transaction
+                .execute("DELETE FROM post_article WHERE author_id = $1", &[&user_id])
+                .await?;
+            let media: Vec<String> = transaction
+                .query(
+                    "DELETE FROM post_media WHERE author_id = $1 RETURNING minio_object_id",
+                    &[&user_id],
+                )
+                .await?
+                .into_iter()
+                .map(|row| row.get(0))
+                .collect();
+            let exports: Vec<Uuid> = transaction
+                .query(
+                    "DELETE FROM data_exports WHERE user_id = $1 RETURNING id",
+                    &[&user_id],
+                )
+                .await?
+                .into_iter()
+                .map(|row| row.get(0))
+                .collect();
+            transaction
+                .execute("DELETE FROM users WHERE id = $1", &[&user_id])
+                .await?;
+            transaction.commit().await?;
+            connections::disconnect(redis_pool, user_id, None, "Account deleted").await;
+
+            // The rows are gone, what's left can only be cleaned up as well as possible.
+            let mut redis_conn = redis_pool.get().await?;
+            for timeline_id in timeline_ids {
+                if let Err(e) =
+                    timeline::invalidate_timeline_cache(&mut redis_conn, &timeline_id.to_string())
+                        .await
+                {
+                    error_elog!(
+                        ev_log,
+                        "Failed to invalidate cache for timeline {}: {:?}",
+                        timeline_id,
+                        e
+                    );
+                }
+            }
+            for path in media.iter().filter_map(|object_id| media_file(object_id)) {
+                let _ = tokio::fs::remove_file(path).await;
+            }
+            for export_id in exports {
+                let _ = tokio::fs::remove_file(archive_path(export_id)).await;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Serves a finished export archive as a download.
+#[derive(rocket::Responder)]
+#[response(content_type = "application/x-tar")]
+pub(crate) struct ExportArchive(NamedFile, Header<'static>);
+
+#[get("/export/<token>")]
+pub(crate) async fn download_export(token: &str, state: &State<AppState>) -> Option<ExportArchive> {
+    let appstate = state.0.clone();
+    let ev_log = appstate.event_logger.clone();
+    let export_id: Uuid = {
+        match &appstate.db {
+            DbConn::PgsqlConnection(pg_pool, _) => {
+                let client = pg_pool.get().await.ok()?;
+                client
+                    .query_opt(
+                        "SELECT id FROM data_exports WHERE token_hash = $1 AND expires_at > NOW()",
+                        &[&tokens::hash_token(token)],
+                    )
+                    .await
+                    .ok()??
+                    .get(0)
+            }
+        }
+    };
+    let file = NamedFile::open(archive_path(export_id)).await.ok()?;
+    http_code_elog!(ev_log, 200, "/export");
+    Some(ExportArchive(
+        file,
+        Header::new(
+            "Content-Disposition",
+            "attachment; filename=\"lumina-export.tar\"",
+        ),
+    ))
+}
@@ -0,0 +1,326 @@
+//! Lumina > Server > API
+//!
+//! A REST API next to the WebSocket protocol, for clients that can't keep a socket open. It is
+//! mounted under `/api/v1` and covers registration, logging in, timelines, posts and the user's
+//! own information.
+//!
+//! Every route runs the same handler as its WebSocket message (see
+//! [`crate::client_communication`]) and answers with the same JSON, so a response body is a
+//! [`ServerMessage`] with its `type` field. Only the status code is added on top.
+//!
+//! Logging in hands out the same session token as the WebSocket protocol does. Routes that need
+//! a user take it as `Authorization: Bearer <token>`.
+
+/*
+ *     Lumina/Peonies
+ *     Copyright (C) 2018-2026 MLC 'Strawmelonjuice'  Bloeiman and contributors.
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::client_communication::{
+    ErrorCode, ServerMessage, UserAgent, handle_login, handle_own_user_information,
+    handle_post_view, handle_register, handle_second_factor, handle_text_post_create,
+    handle_timeline, msgtojson,
+};
+use crate::rate_limiter::{AuthRateLimiter, GeneralRateLimiter, RateLimit};
+use crate::user::{SessionOrigin, User};
+use crate::{AppState, LuminaError, http_code_elog};
+use rocket::State;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::content::RawJson;
+use std::net::IpAddr;
+use uuid::Uuid;
+
+/// A JSON message with the status code that goes with it.
+type ApiResponse = (Status, RawJson<String>);
+
+/// The user behind the `Authorization: Bearer <token>` header. Fails with the message to answer
+/// with: `Unauthenticated` without a token, `AuthFailure` with a token that doesn't work, or
+/// `AccountLocked` when the account may not log in.
+pub(crate) struct Bearer {
+    pub(crate) user: User,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Bearer {
+    type Error = ServerMessage;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(token) = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "))
+        else {
+            return Outcome::Error((Status::Unauthorized, ServerMessage::Unauthenticated));
+        };
+        let state = match req.guard::<&State<AppState>>().await {
+            Outcome::Success(state) => state,
+            _ => return Outcome::Error((Status::InternalServerError, ServerMessage::AuthFailure)),
+        };
+        let appstate = state.0.clone();
+        let db = &appstate.db;
+        match User::revive_session_from_token(token.trim().to_string(), db).await {
+            Ok((_, user)) => Outcome::Success(Bearer { user }),
+            Err(LuminaError::AccountLocked(account_state)) => Outcome::Error((
+                Status::Forbidden,
+                ServerMessage::AccountLocked {
+                    account_state: *account_state,
+                },
+            )),
+            Err(_) => Outcome::Error((Status::Unauthorized, ServerMessage::AuthFailure)),
+        }
+    }
+}
+
+/// The status code to send a message with.
+pub(crate) fn status_of(msg: &ServerMessage) -> Status {
+    match msg {
+        ServerMessage::AuthFailure | ServerMessage::Unauthenticated => Status::Unauthorized,
+        ServerMessage::AccountLocked { .. } | ServerMessage::PermissionDenied => Status::Forbidden,
+        ServerMessage::PostNotFound { .. } => Status::NotFound,
+        ServerMessage::RegisterFailure { .. } => Status::BadRequest,
+        ServerMessage::PostCreateResponse { ok: false, .. } => Status::UnprocessableEntity,
+        ServerMessage::SerialisationError { .. }
+        | ServerMessage::ErrorResponse {
+            code: ErrorCode::Internal,
+            ..
+        } => Status::InternalServerError,
+        _ => Status::Ok,
+    }
+}
+
+async fn respond(state: &State<AppState>, path: &str, msg: ServerMessage) -> ApiResponse {
+    let status = status_of(&msg);
+    http_code_elog!(state.0.event_logger, status.code, "{}", path);
+    (status, RawJson(msgtojson(msg)))
+}
+
+/// A `SerialisationError` caused by the client rather than the server, such as a request body
+/// that isn't the JSON the route expects.
+async fn bad_request(state: &State<AppState>, path: &str, error: String) -> ApiResponse {
+    http_code_elog!(state.0.event_logger, 400, "{}", path);
+    (
+        Status::BadRequest,
+        RawJson(msgtojson(ServerMessage::SerialisationError { error })),
+    )
+}
+
+#[derive(serde::Deserialize)]
+struct RegisterBody {
+    email: String,
+    username: String,
+    password: String,
+    #[serde(default)]
+    invite_code: Option<String>,
+}
+
+#[post("/register", data = "<body>")]
+pub(crate) async fn register(
+    body: &str,
+    state: &State<AppState>,
+    _rate_limit: RateLimit,
+    client_ip: Option<IpAddr>,
+    user_agent: UserAgent,
+) -> ApiResponse {
+    let body: RegisterBody = match serde_json::from_str(body) {
+        Ok(body) => body,
+        Err(e) => return bad_request(state, "/api/v1/register", e.to_string()).await,
+    };
+    let appstate = state.0.clone();
+    let db = &appstate.db;
+    let origin = SessionOrigin {
+        user_agent: user_agent.0,
+        ip: client_ip,
+    };
+    let (msg, _) = handle_register(
+        body.email,
+        body.username,
+        body.password,
+        body.invite_code,
+        appstate.config.registration_mode,
+        db,
+        &appstate.event_logger,
+        &origin,
+    )
+    .await;
+    respond(state, "/api/v1/register", msg).await
+}
+
+#[derive(serde::Deserialize)]
+struct LoginBody {
+    email_username: String,
+    password: String,
+}
+
+#[post("/login", data = "<body>")]
+pub(crate) async fn login(
+    body: &str,
+    state: &State<AppState>,
+    auth_limiter: &State<AuthRateLimiter>,
+    client_ip: Option<IpAddr>,
+    user_agent: UserAgent,
+) -> ApiResponse {
+    let body: LoginBody = match serde_json::from_str(body) {
+        Ok(body) => body,
+        Err(e) => return bad_request(state, "/api/v1/login", e.to_string()).await,
+    };
+    let appstate = state.0.clone();
+    let db = &appstate.db;
+    let origin = SessionOrigin {
+        user_agent: user_agent.0,
+        ip: client_ip,
+    };
+    let (msg, _) = handle_login(
+        body.email_username,
+        body.password,
+        auth_limiter,
+        db,
+        &appstate.event_logger,
+        &origin,
+    )
+    .await;
+    respond(state, "/api/v1/login", msg).await
+}
+
+#[derive(serde::Deserialize)]
+struct SecondFactorBody {
+    challenge: String,
+    code: String,
+}
+
+#[post("/login/second-factor", data = "<body>")]
+pub(crate) async fn second_factor(
+    body: &str,
+    state: &State<AppState>,
+    auth_limiter: &State<AuthRateLimiter>,
+    client_ip: Option<IpAddr>,
+    user_agent: UserAgent,
+) -> ApiResponse {
+    let body: SecondFactorBody = match serde_json::from_str(body) {
+        Ok(body) => body,
+        Err(e) => return bad_request(state, "/api/v1/login/second-factor", e.to_string()).await,
+    };
+    let appstate = state.0.clone();
+    let db = &appstate.db;
+    let origin = SessionOrigin {
+        user_agent: user_agent.0,
+        ip: client_ip,
+    };
+    let (msg, _) = handle_second_factor(
+        body.challenge,
+        body.code,
+        auth_limiter,
+        db,
+        &appstate.event_logger,
+        &origin,
+    )
+    .await;
+    respond(state, "/api/v1/login/second-factor", msg).await
+}
+
+#[get("/me")]
+pub(crate) async fn me(
+    bearer: Result<Bearer, ServerMessage>,
+    state: &State<AppState>,
+    _rate_limit: RateLimit,
+) -> ApiResponse {
+    let msg = match bearer {
+        Ok(Bearer { user }) => {
+            let appstate = state.0.clone();
+            let db = &appstate.db;
+            handle_own_user_information(&user, db, &appstate.event_logger).await
+        }
+        Err(msg) => msg,
+    };
+    respond(state, "/api/v1/me", msg).await
+}
+
+#[get("/timelines/<name>?<page>")]
+pub(crate) async fn timeline(
+    name: &str,
+    page: Option<usize>,
+    bearer: Result<Bearer, ServerMessage>,
+    state: &State<AppState>,
+    _rate_limit: RateLimit,
+) -> ApiResponse {
+    let msg = match bearer {
+        Ok(Bearer { user }) => {
+            let appstate = state.0.clone();
+            let db = &appstate.db;
+            handle_timeline(name.to_string(), page, user, db, &appstate.event_logger).await
+        }
+        Err(msg) => msg,
+    };
+    respond(state, "/api/v1/timelines", msg).await
+}
+
+/// Posts can be viewed without logging in, but then blocks and mutes don't apply.
+#[get("/posts/<post_id>")]
+pub(crate) async fn post(
+    post_id: &str,
+    bearer: Result<Bearer, ServerMessage>,
+    state: &State<AppState>,
+    _rate_limit: RateLimit,
+) -> ApiResponse {
+    let Ok(post_id) = post_id.parse::<Uuid>() else {
+        return bad_request(state, "/api/v1/posts", "Not a post id".to_string()).await;
+    };
+    let viewer = match bearer {
+        Ok(Bearer { user }) => Some(user),
+        // Without a token, the post is viewed as a guest. A token that doesn't work is refused.
+        Err(ServerMessage::Unauthenticated) => None,
+        Err(msg) => return respond(state, "/api/v1/posts", msg).await,
+    };
+    let appstate = state.0.clone();
+    let db = &appstate.db;
+    let msg = handle_post_view(post_id, viewer.as_ref(), db, &appstate.event_logger).await;
+    respond(state, "/api/v1/posts", msg).await
+}
+
+#[derive(serde::Deserialize)]
+struct TextPostBody {
+    content: String,
+}
+
+#[post("/posts", data = "<body>")]
+pub(crate) async fn create_post(
+    body: &str,
+    bearer: Result<Bearer, ServerMessage>,
+    state: &State<AppState>,
+    limiter: &State<GeneralRateLimiter>,
+    client_ip: Option<IpAddr>,
+) -> ApiResponse {
+    let user = match bearer {
+        Ok(Bearer { user }) => user,
+        Err(msg) => return respond(state, "/api/v1/posts", msg).await,
+    };
+    let body: TextPostBody = match serde_json::from_str(body) {
+        Ok(body) => body,
+        Err(e) => return bad_request(state, "/api/v1/posts", e.to_string()).await,
+    };
+    let appstate = state.0.clone();
+    let db = &appstate.db;
+    let msg = handle_text_post_create(
+        body.content,
+        &user,
+        limiter,
+        client_ip,
+        db,
+        &appstate.event_logger,
+    )
+    .await;
+    respond(state, "/api/v1/posts", msg).await
+}
@@ -6,8 +6,8 @@
 //!
 //! For future clients that might not be web-based, this module is designed to be
 //! extensible and adaptable to different client types.
-//! It might for example be expanded to also feature a REST API for clients that
-//! cannot use WebSockets.
+//! Clients that cannot use WebSockets can use the REST API in [`crate::api`], which
+//! shares the `handle_*` functions below with the WebSocket handlers.
 
 /*
  *     Lumina/Peonies
@@ -28,30 +28,62 @@
  */
 
 extern crate rocket;
+use crate::account_data;
+use crate::connections::{self, Push};
+use crate::database::{DatabaseConnections, DbConn};
 use crate::errors::LuminaDbError;
-use crate::rate_limiter::RateLimit;
+use crate::filters::{self, FilterAction, FilterRule};
+use crate::helpers::events::EventLogger;
+use crate::migration;
+use crate::moderation::{self, ModerationAction, ReportInfo, ReportReason, ReportTarget};
+use crate::permissions::{self, Permission, Role};
+use crate::posts::{self, PostView};
+use crate::rate_limiter::{AuthRateLimiter, GeneralRateLimiter, RateLimit};
+use crate::registration::RegistrationMode;
+use crate::relationships::{self, Relationship};
 use crate::timeline::fetch_timeline_post_ids_by_timeline_name;
-use crate::user::User;
+use crate::user::{
+    AccountState, AuthenticationOutcome, RegisterError, RegisterErrorCode, SessionInfo,
+    SessionOrigin, SessionReference, User,
+};
 use crate::{
     AppState, LuminaError, authentication_error_elog, error_elog, http_code_elog, incoming_elog,
-    info_elog, registration_error_elog, warn_elog,
+    info_elog, moderation_elog, registration_error_elog, two_factor, warn_elog,
 };
 use base64::Engine;
 use base64::engine::general_purpose::STANDARD;
 use cynthia_con::{CynthiaColors, CynthiaStyles};
 use rocket::State;
+use rocket::request::{FromRequest, Outcome, Request};
+use serde::Deserialize;
 use std::net::IpAddr;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 use ws::frame::{CloseCode, CloseFrame};
 
+/// The `User-Agent` header of a request, if it has one. Never fails.
+pub(crate) struct UserAgent(pub(crate) Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UserAgent {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(UserAgent(
+            req.headers().get_one("User-Agent").map(str::to_string),
+        ))
+    }
+}
+
 #[get("/connection")]
 pub(crate) async fn wsconnection<'k>(
     ws: ws::WebSocket,
     state: &'k State<AppState>,
     _rate_limit: RateLimit,
-    _limiter: &'k State<crate::rate_limiter::GeneralRateLimiter>,
+    limiter: &'k State<crate::rate_limiter::GeneralRateLimiter>,
     auth_limiter: &'k State<crate::rate_limiter::AuthRateLimiter>,
     client_ip: Option<IpAddr>,
+    user_agent: UserAgent,
 ) -> ws::Channel<'k> {
     let ev_log = {
         let appstate = state.0.clone();
@@ -59,390 +91,247 @@ pub(crate) async fn wsconnection<'k>(
     };
     http_code_elog!(ev_log, 200, "/connection");
     use rocket::futures::{SinkExt, StreamExt};
+    // Checked at startup, so this only falls back to the defaults if the environment changed
+    // since.
+    let settings = SocketSettings::from_env().unwrap_or_default();
+    let ws = ws.config(ws::Config {
+        max_frame_size: Some(settings.max_frame_size),
+        max_message_size: Some(settings.max_frame_size),
+        ..Default::default()
+    });
 
     ws.channel(move |mut stream| {
-		Box::pin(async move {
-			http_code_elog!(ev_log, 101, "/connection");
-			let mut client_session_data: SessionData = SessionData {
-				client_type: None,
-				user: None,
-			};
-			while let Some(message) = stream.next().await {
-				match message? {
-					ws::Message::Text(msg) => {
-						match msg.as_str() {
-							"ping" => {
-								let _ = stream.send(ws::Message::Text("pong".to_string())).await;
-							}
-							possibly_json => {
-								match serde_json::from_str::<Message>(possibly_json) {
-									Ok(Message::PostViewRequest { post_id }) =>
-									{
-										info_elog!(
-										ev_log, "Post was requested: {}", post_id);
-										todo!("PostViewRequest not yet implemented!")
-									}
-									Ok(Message::Introduction { client_kind, try_revive }) => {
-										match client_kind.as_str() {
-											"web" => {
-												client_session_data.client_type = Some(ClientType::Web)
-											}
-											"mobile" => todo!(),
-											_ => {}
-										}
-										match try_revive {
-											Some(token) => {
-												let appstate = state.0.clone();
-												let db = &appstate.db.lock().await;
-												match User::revive_session_from_token(token.clone(), db).await {
-													Ok(user) => {
-														incoming_elog!(ev_log, "Session revived for user: {}",
-				                            user.clone().username.color_bright_cyan()
-			                            );
-														client_session_data.user = Some(user.clone());
-														let _ = stream
-															.send(ws::Message::from(msgtojson(Message::AuthSuccess {
-																token,
-																username: user.username,
-															})))
-															.await;
-													}
-													Err(e) => {
-														match e {
-															LuminaError::DbError(LuminaDbError::Postgres(postgres_error)) => {
-																// This is not the kind of practice I'd want, but it does the job
-																let postgres_error_dbg = format!("{:?}", postgres_error);
-																	// Check if it's a "no rows returned" type error
-																	// I wish I could just open those fields...
-																	if postgres_error_dbg.contains("kind: RowCount, cause: None") {
-																		info_elog!( ev_log,"Session revival failed: token not found or expired.");
-																	} else {
-																		info_elog!(ev_log,"Session revival failed: database error: {postgres_error_dbg}");
-																	}
-																}
-
-															_ => {
-																info_elog!(ev_log,"Session revival failed: {:?}", e);
-															}
-														}
-														let _ = stream
-															.send(ws::Message::from(msgtojson(Message::AuthFailure)))
-															.await;
-													}
-												}
-											}
-											None => {
-												let _ = stream
-													.send(ws::Message::from(msgtojson(Message::Greeting {
-														greeting: "Hello from server!".to_string(),
-													})))
-													.await;
-											}
-										}
-									}
-									Ok(Message::RegisterRequest {
-										   email,
-										   username,
-										   password,
-									   }) => {
-										incoming_elog!(
-                                                                ev_log,
-                                                                    "Register request: {} {}",
-                                                                    email.clone().color_orange(),
-                                                                    username.clone().color_bright_cyan()
-                                                                );
-
-										// register the user
-										{
-											let appstate = state.0.clone();
-											let db = &appstate.db.lock().await;
-											match User::create_user(email.clone(), username.clone(), password, db).await
-											{
-												Ok(user) => {
-													info_elog!(
-                                                                            ev_log,
-                                                                                "User created: {}",
-                                                                                user.clone().username.color_bright_cyan()
-                                                                            );
-													match User::create_session(user, db, ev_log.clone()).await {
-														Ok((session_reference, user)) => {
-															client_session_data.user =
-																Some(user.clone());
-															incoming_elog!(ev_log,"User {} authenticated.",
-                            user.clone().username.color_bright_cyan()
-				                            );
-															let _ = stream
-																.send(ws::Message::from(msgtojson(
-																	Message::AuthSuccess {
-																		token: session_reference.token,
-																		username: user.username,
-																	},
-																)))
-																.await;
-														}
-														Err(e) => {
-															match e {
-																LuminaError::DbError(crate::errors::LuminaDbError::Postgres(e)) =>
-																	error_elog!(ev_log,"While creating session token: {:?}", e),
-																LuminaError::Bb8RunErrorPg(e) =>
-																	warn_elog!(ev_log,"There was an error creating session token: {}", e),
-																_ => {}
-															}
-															// I would return a more specific error message
-															// to the client here, but if the server knows the
-															// error, the client should know the error twice as
-															// well.
-
-															let _ = stream
-																.send(ws::Message::from(msgtojson(
-																	Message::AuthFailure,
-																)))
-																.await;
-														}
-													}
-												}
-
-												Err(e) => {
-													match e {
-														LuminaError::RegisterUsernameInUse => {
-															registration_error_elog!(ev_log, "User {} already exists",
-                                                                                        username.clone().color_bright_cyan()
-                                                                                    );
-														}
-														LuminaError::RegisterEmailNotValid => {
-															registration_error_elog!(ev_log, "Email {} is not valid",
-                                                                                        email.clone().color_bright_cyan()
-                                                                                    );
-														}
-														LuminaError::RegisterUsernameInvalid(why) => {
-															registration_error_elog!(ev_log, "Username '{}' is not valid: {}",
-                                                                                        username.clone().color_bright_cyan(),
-                                                                                        why
-                                                                                    );
-														}
-														LuminaError::RegisterPasswordNotValid(why) => {
-															registration_error_elog!(ev_log, "Password is not valid: {}",
-                            why
-				                            );
-														}
-														e => {
-															registration_error_elog!(ev_log, "Error creating user: {:?}",
-                            e
-				                            );
-														}
-													}
-
-													// I would return a more specific error message
-													// to the client here, but if the server knows the
-													// error, the client should know the error twice as
-													// well.
-
-													let _ = stream
-														.send(ws::Message::from(msgtojson(
-															Message::AuthFailure,
-														)))
-														.await;
-												}
-											}
-										}
-									}
-									Ok(Message::RegisterPrecheck { email, username, password }) => {
-										let appstate = state.0.clone();
-										let db = &appstate.db.lock().await;
-										match crate::user::register_validitycheck(email, username, password, db).await {
-											Err(LuminaError::RegisterEmailInUse) => {
-												let _ = stream.send(ws::Message::from(msgtojson(Message::RegisterPrecheckResponse {
-													ok: false,
-													why: "Email already in use".to_string(),
-												}))).await;
-											}
-											Err(LuminaError::RegisterUsernameInUse) => {
-												let _ = stream.send(ws::Message::from(msgtojson(Message::RegisterPrecheckResponse {
-													ok: false,
-													why: "Username already in use".to_string(),
-												}))).await;
-											}
-											Err(LuminaError::RegisterEmailNotValid) => {
-												let _ = stream.send(ws::Message::from(msgtojson(Message::RegisterPrecheckResponse {
-													ok: false,
-													why: "Email not valid".to_string(),
-												}))).await;
-											}
-											Err(LuminaError::RegisterUsernameInvalid(why)) => {
-												let _ = stream.send(ws::Message::from(msgtojson(Message::RegisterPrecheckResponse {
-													ok: false,
-													why: format!("Username invalid: {}", why),
-												}))).await;
-											}
-											Err(LuminaError::RegisterPasswordNotValid(why)) => {
-												let _ = stream.send(ws::Message::from(msgtojson(Message::RegisterPrecheckResponse {
-													ok: false,
-													why: format!("Password invalid: {}", why),
-												}))).await;
-											}
-											Ok(_) => {
-												let _ = stream.send(ws::Message::from(msgtojson(Message::RegisterPrecheckResponse {
-													ok: true,
-													why: "".to_string(),
-												}))).await;
-											}
-											_ => {}
-										}
-									}
-									Ok(Message::LoginAuthenticationRequest { email_username, password }) => {
-										// Quick pre-check: if the limiter says this IP is blocked, avoid DB work.
-										if !auth_limiter.allow_ip(client_ip).await {
-											authentication_error_elog!(ev_log, "Rate-limited authentication attempt from IP: {:?}", client_ip);
-											let _ = stream.send(ws::Message::from(msgtojson(Message::AuthFailure))).await;
-										} else {
-											let appstate = state.0.clone();
-											let db = &appstate.db.lock().await;
-											let msgback = match User::authenticate(email_username.clone(), password, db, ev_log.clone()).await {
-												Ok((session_reference, user)) => {
-													incoming_elog!(ev_log,"User {} authenticated to session with id {}.\n{}", user.username.clone().color_bright_cyan(), session_reference.session_id.to_string().color_pink(), format!("(User id: {})", user.id).style_dim());
-													client_session_data.user = Some(user.clone());
-													Message::AuthSuccess { token: session_reference.token, username: user.username }
-												}
-												Err(s) => {
-													match s {
-														LuminaError::AuthenticationWrongPassword => {
-															authentication_error_elog!(ev_log,"User {} {} authenticated: Incorrect credentials", email_username.color_bright_cyan(), "not".color_red());
-														}
-														// LuminaError::AuthenticationUserNotFound => {
-														// 	authentication_error_elog!(ev_log,"User {} {} authenticated: User not found", email_username.color_bright_cyan(), "not".color_red());
-														// }
-														_ => {
-															authentication_error_elog!(ev_log,"User {} {} authenticated: {:?}", email_username.color_bright_cyan(), "not".color_red(), s);
-														}
-													}
-													Message::AuthFailure
-												}
-											};
-											let _ = stream.send(ws::Message::from(msgtojson(msgback))).await;
-										}
-									}
-									Ok(Message::OwnUserInformationRequest) => {
-										// Handle request for user's own information
-										match &client_session_data.user {
-											Some(user) => {
-												// For now, send back basic user info as a greeting
-												// This could be expanded to a proper user info response message type
-												let response = Message::OwnUserInformationResponse {
-													username: user.username.clone(),
-													email: user.email.clone(),
-													// Provide a compile-time included SVG placeholder avatar when none is available.
-													// The SVG file is included as bytes and base64-encoded here.
-													avatar: Some((
-														"image/svg+xml".to_string(),
-														// Encode the included SVG bytes as base64 at compile time.
-														STANDARD.encode(include_bytes!("../../assets/svgs/dummy_user_120px.svg")),
-													)),
-													uuid: user.id.to_string(),
-													//TODO: Fetch actual unread notification count
-													//Based on how many notifications are younger than last time user checked notifications. (WsMessage is sent when user opens notifications)
-													unread_notifications: 11
-												};
-												let msg_json = msgtojson(response);
-												// println!("Sending own user information response: {}", msg_json);
-												let _ = stream.send(ws::Message::from(msg_json)).await;
-											}
-											None => {
-												let _ = stream.send(ws::Message::from(msgtojson(Message::AuthFailure))).await;
-											}
-										}
-									}
-									Ok(Message::TimelineRequest { by_name: name, page }) => {
-										let appstate = state.0.clone();
-										let db = &appstate.db.lock().await;
-										// Fetch post IDs for the requested timeline
-										match fetch_timeline_post_ids_by_timeline_name(
-											ev_log.clone(),
-											db,
-											&name,
-											client_session_data.user.clone().unwrap(),
-											page,
-										).await {
-											Ok((tlid, post_ids, total_count, has_more)) => {
-												let response = Message::TimelineResponse {
-													post_ids,
-													timeline_name: name,
-													timeline_id: tlid,
-													total_count,
-													page: page.unwrap_or(0),
-													has_more,
-												};
-												let _ = stream.send(ws::Message::from(msgtojson(response))).await;
-											}
-											Err(e) => {
-												error_elog!(ev_log, "Error fetching timeline: {:?}", e);
-												let _ = stream.send(ws::Message::from(msgtojson(Message::SerialisationError {
-													error: format!("{:?}", e),
-												}))).await;
-											}
-										}
-									}
-									// Responding variants are not supposed to ever arrive here.
-									Ok(Message::ClientInit { .. }) |
-									Ok(Message::Greeting { .. }) | Ok(Message::SerialisationError { .. })
-									| Ok(Message::RegisterPrecheckResponse { .. })
-									| Ok(Message::AuthSuccess { .. })
-									| Ok(Message::AuthFailure)
-									| Ok(Message::MediaPostDataSent { .. })
-									| Ok(Message::TextPostDataSent { .. })
-									| Ok(Message::ArticlePostDataSent { .. })
-									| Ok(Message::OwnUserInformationResponse { .. })
-									| Ok(Message::TimelineResponse { .. }) => {
-										panic!("These messages should never arrive here.")
-									}
-									// This one makes sense.
-									Ok(Message::Unknown) => {
-										panic!("Unknown message received?")
-									}
-									// And to handle straight up errors:
-									Err(e) => {
-										error_elog!(ev_log, "Error deserialising message: {:?}\n\n{}" , e,
-                                                            format!("The message: {}", possibly_json).style_dim()
-                                                            );
-										let _ = stream.close(Some(CloseFrame {
-											code: CloseCode::Invalid,
-											reason: std::borrow::Cow::Borrowed("Serialisation error"),
-										})).await;
-									}
-								}
-							}
-						}
-					}
-					ws::Message::Close(_) => {
-						let _ = stream.send(ws::Message::Close(None)).await;
-						break;
-					}
-					_ => {
-						let _ = stream.send(ws::Message::from("unknown")).await;
-					}
-				}
-			}
-
-			Ok(())
-		})
-	})
+        Box::pin(async move {
+            http_code_elog!(ev_log, 101, "/connection");
+            let mut client_session_data = SessionData::new();
+            let (pusher, mut pushed) = tokio::sync::mpsc::unbounded_channel();
+            let redis_pool = state.0.db.get_redis_pool();
+            // The user and session this connection is in the registry with.
+            let mut registered: Option<(Uuid, Uuid)> = None;
+            // Presence outlives a heartbeat, so that it only runs out when the heartbeats stop.
+            let presence_ttl = settings.idle_timeout + settings.ping_interval;
+            let ctx = HandlerContext {
+                appstate: state.0.clone(),
+                limiter,
+                auth_limiter,
+                origin: SessionOrigin {
+                    user_agent: user_agent.0,
+                    ip: client_ip,
+                },
+                ev_log: ev_log.clone(),
+            };
+            let mut heartbeat = tokio::time::interval(settings.ping_interval);
+            heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            // The first tick is immediate, there's no need to ping a connection that just opened.
+            heartbeat.tick().await;
+            let mut last_seen = Instant::now();
+            let mut outcome = Ok(());
+            loop {
+                let message = tokio::select! {
+                    message = stream.next() => match message {
+                        Some(Ok(message)) => message,
+                        Some(Err(e)) => {
+                            outcome = Err(e);
+                            break;
+                        }
+                        None => break,
+                    },
+                    Some(push) = pushed.recv() => {
+                        match push {
+                            Push::Message { user_id, message } => {
+                                if registered.is_some_and(|(registered_user, _)| registered_user == user_id) {
+                                    let _ = stream
+                                        .send(client_session_data.encoding.encode(&None, message))
+                                        .await;
+                                }
+                            }
+                            Push::Disconnect { user_id, session_id, reason } => {
+                                // The connection may have logged out, or in as someone else,
+                                // since this was sent.
+                                if registered.is_some_and(|(registered_user, registered_session)| {
+                                    registered_user == user_id
+                                        && session_id.is_none_or(|id| id == registered_session)
+                                }) {
+                                    let _ = stream
+                                        .send(client_session_data.encoding.encode(&None, ServerMessage::LoggedOut))
+                                        .await;
+                                    let _ = stream
+                                        .close(Some(CloseFrame {
+                                            code: CloseCode::Normal,
+                                            reason: std::borrow::Cow::Owned(reason),
+                                        }))
+                                        .await;
+                                    break;
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                    _ = heartbeat.tick() => {
+                        if last_seen.elapsed() >= settings.idle_timeout {
+                            info_elog!(ev_log, "Closing a connection that went quiet.");
+                            let _ = stream
+                                .close(Some(CloseFrame {
+                                    code: CloseCode::Away,
+                                    reason: std::borrow::Cow::Borrowed("Idle timeout"),
+                                }))
+                                .await;
+                            break;
+                        }
+                        let _ = stream.send(ws::Message::Ping(Vec::new())).await;
+                        if let Some((user_id, _)) = registered {
+                            let _ = connections::touch(
+                                &redis_pool,
+                                user_id,
+                                client_session_data.connection_id,
+                                presence_ttl,
+                            )
+                            .await;
+                        }
+                        continue;
+                    }
+                };
+                // Anything the client sends, pongs included, shows the connection is alive.
+                last_seen = Instant::now();
+                let (request_id, decoded, received) = match message {
+                    ws::Message::Text(msg) if msg == "ping" => {
+                        let _ = stream.send(ws::Message::Text("pong".to_string())).await;
+                        continue;
+                    }
+                    ws::Message::Text(msg) => {
+                        let (request_id, decoded) = decode(&msg);
+                        (request_id, decoded, msg)
+                    }
+                    // Binary frames are MessagePack, whichever encoding the replies are in.
+                    ws::Message::Binary(bytes) => {
+                        let (request_id, decoded) = decode_msgpack(&bytes);
+                        (
+                            request_id,
+                            decoded,
+                            format!("{} bytes of MessagePack", bytes.len()),
+                        )
+                    }
+                    ws::Message::Close(_) => {
+                        let _ = stream.send(ws::Message::Close(None)).await;
+                        break;
+                    }
+                    // Pings are answered by the WebSocket library, pongs answer our heartbeat.
+                    ws::Message::Ping(_) | ws::Message::Pong(_) => continue,
+                    _ => {
+                        let _ = stream.send(ws::Message::from("unknown")).await;
+                        continue;
+                    }
+                };
+                // The reply to an `Introduction` still goes out in JSON, the encoding it picks
+                // is used from the next reply on.
+                let encoding = client_session_data.encoding;
+                let replies = match decoded {
+                    Ok(msg) => dispatch(msg, &mut client_session_data, &ctx).await,
+                    // Messages that can't be read, and messages only the server sends, get
+                    // a typed error. The connection stays open, so the requests queued after
+                    // this one still get their replies.
+                    Err(reply) => {
+                        warn_elog!(
+                            ev_log,
+                            "Could not handle message: {:?}\n\n{}",
+                            reply,
+                            format!("The message: {}", received).style_dim()
+                        );
+                        Ok(vec![reply])
+                    }
+                };
+                match replies {
+                    Ok(replies) => {
+                        for reply in replies {
+                            let reply =
+                                for_protocol_version(reply, client_session_data.protocol_version);
+                            let _ = stream.send(encoding.encode(&request_id, reply)).await;
+                        }
+                    }
+                    Err(why) => {
+                        let _ = stream
+                            .close(Some(CloseFrame {
+                                code: CloseCode::Protocol,
+                                reason: std::borrow::Cow::Owned(why),
+                            }))
+                            .await;
+                        break;
+                    }
+                }
+                update_registration(
+                    &mut registered,
+                    &client_session_data,
+                    &pusher,
+                    &redis_pool,
+                    presence_ttl,
+                )
+                .await;
+            }
+            client_session_data.user = None;
+            update_registration(
+                &mut registered,
+                &client_session_data,
+                &pusher,
+                &redis_pool,
+                presence_ttl,
+            )
+            .await;
+
+            outcome
+        })
+    })
+}
+
+/// Keep the registry in step with who the connection is logged in as, after a message may have
+/// logged it in or out.
+async fn update_registration(
+    registered: &mut Option<(Uuid, Uuid)>,
+    conn: &SessionData,
+    pusher: &connections::Pusher,
+    redis_pool: &bb8::Pool<bb8_redis::RedisConnectionManager>,
+    presence_ttl: Duration,
+) {
+    let current = conn
+        .user
+        .as_ref()
+        .zip(conn.session_id)
+        .map(|(user, session_id)| (user.id, session_id));
+    if current == *registered {
+        return;
+    }
+    if let Some((user_id, _)) = registered.take() {
+        connections::registry().unregister(user_id, conn.connection_id);
+        let _ = connections::leave(redis_pool, user_id, conn.connection_id).await;
+    }
+    if let Some((user_id, session_id)) = current {
+        connections::registry().register(user_id, session_id, conn.connection_id, pusher.clone());
+        let _ = connections::touch(redis_pool, user_id, conn.connection_id, presence_ttl).await;
+    }
+    *registered = current;
 }
 
+/// Messages a client sends. Each is handled by an arm of [`dispatch`], after its [`Access`] is
+/// checked.
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 #[serde(tag = "type", rename_all = "snake_case")]
-// An example of a JSON message that the client might send to the server:
-// {"type": "client-init", "data": "hi"}
-pub(crate) enum Message {
-    #[serde(rename = "client-init")]
-    ClientInit { data: String },
+pub(crate) enum ClientMessage {
+    /// The first message of a connection. See [`negotiate`].
     #[serde(rename = "introduction")]
     Introduction {
         client_kind: String,
         try_revive: Option<String>,
+        /// The protocol version the client speaks. Clients from before versioning leave this
+        /// out, and speak version 1.
+        #[serde(default)]
+        protocol_version: Option<u32>,
+        /// Optional features the client would like to use.
+        #[serde(default)]
+        features: Vec<String>,
+        /// How the server should encode its messages from now on: `"json"` (the default) or
+        /// `"msgpack"`.
+        #[serde(default)]
+        encoding: Option<String>,
     },
-    #[serde(rename = "greeting")]
-    Greeting { greeting: String },
-    #[serde(rename = "serialisation_error")]
-    SerialisationError { error: String },
     #[serde(rename = "login_authentication_request")]
     LoginAuthenticationRequest {
         email_username: String,
@@ -453,6 +342,9 @@ pub(crate) enum Message {
         email: String,
         username: String,
         password: String,
+        /// Required when the registration mode is `invite`.
+        #[serde(default)]
+        invite_code: Option<String>,
     },
     #[serde(rename = "register_precheck")]
     RegisterPrecheck {
@@ -460,12 +352,189 @@ pub(crate) enum Message {
         username: String,
         password: String,
     },
+    #[serde(rename = "own_user_information_request")]
+    /// Request for the server to send back the user's own information.
+    /// This is used to get the user's own information after logging in.
+    OwnUserInformationRequest,
+    /// Requests a list of strings to represent a certain timeline or bubble timeline.
+    #[serde(rename = "timeline_request")]
+    TimelineRequest {
+        by_name: String,
+        #[serde(default)]
+        page: Option<usize>,
+    },
+    /// User would like to view a post and its details.
+    #[serde(rename = "post_view_request")]
+    PostViewRequest { post_id: Uuid },
+    /// Confirms an email address using the token from the verification email.
+    #[serde(rename = "email_verification_request")]
+    EmailVerificationRequest { token: String },
+    /// Asks for a new verification email to be sent to the logged-in user.
+    #[serde(rename = "email_verification_resend_request")]
+    EmailVerificationResendRequest,
+    /// Asks for a password reset link to be mailed to this address.
+    #[serde(rename = "password_reset_request")]
+    PasswordResetRequest { email: String },
+    /// Sets a new password using the token from the password reset email.
+    #[serde(rename = "password_reset_confirm")]
+    PasswordResetConfirm { token: String, new_password: String },
+    /// Ends the session this connection is authenticated with.
+    #[serde(rename = "logout_request")]
+    LogoutRequest,
+    /// Ends every session of the logged-in user, including this one.
+    #[serde(rename = "logout_everywhere_request")]
+    LogoutEverywhereRequest,
+    /// Requests the list of active sessions of the logged-in user.
+    #[serde(rename = "session_list_request")]
+    SessionListRequest,
+    /// Ends one of the logged-in user's sessions. Revoking the current session logs out.
+    #[serde(rename = "session_revoke_request")]
+    SessionRevokeRequest { session_id: Uuid },
+    /// Answers a `SecondFactorRequired` with a TOTP code or a recovery code.
+    /// Answered with `AuthSuccess` or `AuthFailure`, like a login.
+    #[serde(rename = "second_factor_response")]
+    SecondFactorResponse { challenge: String, code: String },
+    /// Starts enabling two-factor authentication for the logged-in user.
+    #[serde(rename = "totp_enrol_request")]
+    TotpEnrolRequest,
+    /// Finishes enrolment with a first code from the authenticator app.
+    #[serde(rename = "totp_enrol_confirm")]
+    TotpEnrolConfirm { code: String },
+    /// Turns two-factor authentication off, which takes the password and a current code.
+    #[serde(rename = "totp_disable_request")]
+    TotpDisableRequest { password: String, code: String },
+    /// Asks for an archive of all of the logged-in user's data. Once it is built, a download
+    /// link is mailed to them.
+    #[serde(rename = "data_export_request")]
+    DataExportRequest,
+    /// Schedules the logged-in user's account for deletion, after a grace period.
+    #[serde(rename = "account_deletion_request")]
+    AccountDeletionRequest { password: String },
+    /// Calls off a scheduled deletion of the logged-in user's account.
+    #[serde(rename = "account_deletion_cancel_request")]
+    AccountDeletionCancelRequest,
+    /// Marks the logged-in user's account as moved to `username@instance`, so lookups of it
+    /// are redirected there. Without `moved_to`, the account is marked as not moved again.
+    #[serde(rename = "account_move_request")]
+    AccountMoveRequest {
+        password: String,
+        moved_to: Option<String>,
+    },
+    /// Imports the export archive of an account on another instance into the logged-in user's
+    /// account. That account has to be marked as moved here first.
+    #[serde(rename = "account_import_request")]
+    AccountImportRequest {
+        /// Base64 encoded export archive.
+        archive: String,
+    },
+    /// Blocks or mutes another user, or with `active` false, lifts the block or mute.
+    #[serde(rename = "relationship_request")]
+    RelationshipRequest {
+        username: String,
+        relationship: Relationship,
+        active: bool,
+    },
+    /// Requests the users the logged-in user blocked and muted.
+    #[serde(rename = "relationship_list_request")]
+    RelationshipListRequest,
+    /// Asks whether another user is online, and when they were last seen.
+    #[serde(rename = "presence_request")]
+    PresenceRequest { username: String },
+    /// Reports a user or post to the moderators.
+    #[serde(rename = "report_request")]
+    ReportRequest {
+        target: ReportTarget,
+        reason: ReportReason,
+        #[serde(default)]
+        comment: String,
+    },
+    /// Requests the moderation queue. Moderators only.
+    #[serde(rename = "moderation_report_list_request")]
+    ModerationReportListRequest {
+        #[serde(default)]
+        include_resolved: bool,
+    },
+    /// Resolves a report by taking an action on it. Moderators only.
+    #[serde(rename = "moderation_action_request")]
+    ModerationActionRequest {
+        report_id: Uuid,
+        action: ModerationAction,
+    },
+    /// Gives a user a role. Admins only.
+    #[serde(rename = "role_change_request")]
+    RoleChangeRequest { username: String, role: Role },
+    /// Writes a text post on the global timeline.
+    #[serde(rename = "text_post_create_request")]
+    TextPostCreateRequest {
+        /// Markdown content.
+        content: String,
+    },
+    /// Requests the filter rules. Admins only.
+    #[serde(rename = "filter_rule_list_request")]
+    FilterRuleListRequest,
+    /// Adds a filter rule. Admins only.
+    #[serde(rename = "filter_rule_add_request")]
+    FilterRuleAddRequest {
+        /// A keyword, or a regular expression when `is_regex` is set.
+        pattern: String,
+        #[serde(default)]
+        is_regex: bool,
+        action: FilterAction,
+    },
+    /// Removes a filter rule. Admins only.
+    #[serde(rename = "filter_rule_remove_request")]
+    FilterRuleRemoveRequest { id: Uuid },
+}
+
+/// Messages the server sends, in reply to a [`ClientMessage`] or over the REST API.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ServerMessage {
+    /// Sent in reply to an accepted `Introduction` that names a protocol version, before anything
+    /// else.
+    #[serde(rename = "introduction_response")]
+    IntroductionResponse {
+        /// The version this connection speaks from now on.
+        protocol_version: u32,
+        min_protocol_version: u32,
+        max_protocol_version: u32,
+        /// Everything optional this server supports.
+        capabilities: Vec<String>,
+        /// The features asked for in the `Introduction` that are now in use.
+        features: Vec<String>,
+        /// The encoding of every message after this one.
+        encoding: Encoding,
+    },
+    #[serde(rename = "greeting")]
+    Greeting { greeting: String },
+    #[serde(rename = "serialisation_error")]
+    SerialisationError { error: String },
     #[serde(rename = "register_precheck_response")]
-    RegisterPrecheckResponse { ok: bool, why: String },
+    RegisterPrecheckResponse {
+        ok: bool,
+        why: String,
+        /// The same failure as `why`, structured.
+        #[serde(default)]
+        error: Option<RegisterError>,
+        /// So clients know whether to ask for an invite code, or to say registration is closed.
+        #[serde(default)]
+        registration_mode: RegistrationMode,
+    },
+    /// Response to a `RegisterRequest` on an instance requiring approval: the account exists,
+    /// but can't log in until an admin approves it.
+    #[serde(rename = "register_pending_approval")]
+    RegisterPendingApproval,
+    /// Response to a `RegisterRequest` that was refused.
+    #[serde(rename = "register_failure")]
+    RegisterFailure { error: RegisterError },
     #[serde(rename = "auth_success")]
     AuthSuccess { token: String, username: String },
     #[serde(rename = "auth_failure")]
     AuthFailure,
+    /// Sent instead of `AuthFailure` when the credentials or token are right, but the account
+    /// can't log in because of its state. Its sessions have been ended.
+    #[serde(rename = "account_locked")]
+    AccountLocked { account_state: AccountState },
     #[serde(rename = "data_article_post")]
     ArticlePostDataSent {
         post_id: Uuid,
@@ -478,6 +547,8 @@ pub(crate) enum Message {
         /// User id of poster, which is why the source_instance matters.
         /// This means that client will do a lookup and stores the user once it gets it.
         author_id: String,
+        #[serde(default)]
+        sensitive: bool,
     },
     #[serde(rename = "data_embed_post")]
     MediaPostDataSent {
@@ -488,6 +559,8 @@ pub(crate) enum Message {
         description: String,
         /// Base64 encoded media strings, either webp or mp4.
         medias: Vec<String>,
+        #[serde(default)]
+        sensitive: bool,
     },
     #[serde(rename = "data_textual_post")]
     TextPostDataSent {
@@ -496,11 +569,12 @@ pub(crate) enum Message {
         source_instance: String,
         /// Markdown content.
         content: String,
+        #[serde(default)]
+        sensitive: bool,
     },
-    #[serde(rename = "own_user_information_request")]
-    /// Request for the server to send back the user's own information.
-    /// This is used to get the user's own information after logging in.
-    OwnUserInformationRequest,
+    /// Sent instead of post data when the post doesn't exist or is hidden from the user.
+    #[serde(rename = "post_not_found")]
+    PostNotFound { post_id: Uuid },
     #[serde(rename = "own_user_information_response")]
     /// Response to the `OwnUserInformationRequest` containing the user's own information.
     OwnUserInformationResponse {
@@ -510,13 +584,12 @@ pub(crate) enum Message {
         avatar: Option<(String, String)>,
         uuid: String,
         unread_notifications: u64,
-    },
-    /// Requests a list of strings to represent a certain timeline or bubble timeline.
-    #[serde(rename = "timeline_request")]
-    TimelineRequest {
-        by_name: String,
+        /// Unix timestamp of the moment the account will be deleted, if it is scheduled.
         #[serde(default)]
-        page: Option<usize>,
+        deletion_scheduled_for: Option<i64>,
+        /// The role the user had when logging in.
+        #[serde(default)]
+        role: Role,
     },
     TimelineResponse {
         timeline_name: String,
@@ -530,17 +603,1349 @@ pub(crate) enum Message {
         /// Whether there are more pages available
         has_more: bool,
     },
-    /// User would like to view a post and its details.
-    #[serde(rename = "post_view_request")]
-    PostViewRequest { post_id: Uuid },
-    /// "Yeah I don't know what I'm sending either!"
-    #[serde(rename = "unknown")]
-    Unknown,
+    #[serde(rename = "email_verification_response")]
+    EmailVerificationResponse { ok: bool },
+    /// Response to an `EmailVerificationResendRequest`. Not ok when the address is verified
+    /// already, or after too many requests.
+    #[serde(rename = "email_verification_resend_response")]
+    EmailVerificationResendResponse { ok: bool, why: String },
+    /// Response to every `PasswordResetRequest`, whether the address is known or not.
+    #[serde(rename = "password_reset_requested")]
+    PasswordResetRequested,
+    #[serde(rename = "password_reset_response")]
+    PasswordResetResponse { ok: bool, why: String },
+    /// Response to both logout requests. The connection is unauthenticated afterwards.
+    #[serde(rename = "logged_out")]
+    LoggedOut,
+    #[serde(rename = "session_list_response")]
+    SessionListResponse {
+        sessions: Vec<SessionInfo>,
+        /// The session of the connection asking, so clients can mark it.
+        current_session_id: Option<Uuid>,
+    },
+    #[serde(rename = "session_revoke_response")]
+    SessionRevokeResponse { session_id: Uuid, ok: bool },
+    /// Sent instead of `AuthSuccess` when the account has two-factor authentication enabled.
+    /// The challenge is to be sent back with a code in a `SecondFactorResponse`.
+    #[serde(rename = "second_factor_required")]
+    SecondFactorRequired { challenge: String },
+    #[serde(rename = "totp_enrol_response")]
+    TotpEnrolResponse {
+        ok: bool,
+        /// `otpauth://` URI, usually shown as a QR code.
+        provisioning_uri: String,
+        /// The same secret in base32, for typing into an authenticator app.
+        secret: String,
+    },
+    #[serde(rename = "totp_enrol_confirm_response")]
+    TotpEnrolConfirmResponse {
+        ok: bool,
+        /// Single-use codes for when the authenticator is lost. Only ever sent this once.
+        recovery_codes: Vec<String>,
+    },
+    #[serde(rename = "totp_disable_response")]
+    TotpDisableResponse { ok: bool },
+    /// Whether the export was queued. It isn't if one was asked for recently.
+    #[serde(rename = "data_export_response")]
+    DataExportResponse { ok: bool },
+    /// Response to both deletion requests.
+    #[serde(rename = "account_deletion_response")]
+    AccountDeletionResponse {
+        ok: bool,
+        /// Unix timestamp of the moment the account will be deleted, if it is scheduled.
+        scheduled_for: Option<i64>,
+    },
+    #[serde(rename = "account_move_response")]
+    AccountMoveResponse { ok: bool },
+    #[serde(rename = "account_import_response")]
+    AccountImportResponse {
+        ok: bool,
+        why: String,
+        imported_posts: usize,
+        /// Posts left out because a filter rule rejects them.
+        #[serde(default)]
+        rejected_posts: usize,
+        restored_follows: usize,
+    },
+    #[serde(rename = "relationship_response")]
+    RelationshipResponse {
+        username: String,
+        relationship: Relationship,
+        ok: bool,
+    },
+    #[serde(rename = "relationship_list_response")]
+    RelationshipListResponse {
+        blocked: Vec<String>,
+        muted: Vec<String>,
+    },
+    /// Users that don't exist, and users blocked either way, are always offline and never seen.
+    #[serde(rename = "presence_response")]
+    PresenceResponse {
+        username: String,
+        online: bool,
+        /// Unix timestamp of when the user was last connected.
+        last_seen: Option<i64>,
+    },
+    #[serde(rename = "report_response")]
+    ReportResponse { ok: bool },
+    #[serde(rename = "moderation_report_list_response")]
+    ModerationReportListResponse { reports: Vec<ReportInfo> },
+    #[serde(rename = "moderation_action_response")]
+    ModerationActionResponse { report_id: Uuid, ok: bool },
+    #[serde(rename = "role_change_response")]
+    RoleChangeResponse {
+        username: String,
+        role: Role,
+        ok: bool,
+    },
+    #[serde(rename = "post_create_response")]
+    PostCreateResponse {
+        ok: bool,
+        why: String,
+        post_id: Option<Uuid>,
+        /// Held for review by a filter rule, hidden until a moderator releases it.
+        held: bool,
+        /// Marked sensitive by a filter rule.
+        sensitive: bool,
+    },
+    #[serde(rename = "filter_rule_list_response")]
+    FilterRuleListResponse { rules: Vec<FilterRule> },
+    #[serde(rename = "filter_rule_add_response")]
+    FilterRuleAddResponse {
+        ok: bool,
+        why: String,
+        id: Option<Uuid>,
+    },
+    #[serde(rename = "filter_rule_remove_response")]
+    FilterRuleRemoveResponse { id: Uuid, ok: bool },
+    /// Sent when the user isn't allowed to do what they asked.
+    #[serde(rename = "permission_denied")]
+    PermissionDenied,
+    /// Sent in reply to a message that needs a logged-in session, when there is none.
+    #[serde(rename = "unauthenticated")]
+    Unauthenticated,
+    /// Sent when a message can't be handled at all, so there is no reply of its own to send.
+    #[serde(rename = "error")]
+    ErrorResponse { code: ErrorCode, message: String },
+}
+
+/// What a message needs before its handler runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Access {
+    /// Anyone, logged in or not.
+    Public,
+    /// A logged-in session.
+    Session,
+    /// A logged-in session, of a user with this permission.
+    Permitted(Permission),
+}
+
+impl ClientMessage {
+    /// What this message needs, enforced by [`dispatch`] before its handler runs.
+    pub(crate) fn access(&self) -> Access {
+        match self {
+            ClientMessage::Introduction { .. }
+            | ClientMessage::RegisterRequest { .. }
+            | ClientMessage::RegisterPrecheck { .. }
+            | ClientMessage::LoginAuthenticationRequest { .. }
+            | ClientMessage::SecondFactorResponse { .. }
+            | ClientMessage::EmailVerificationRequest { .. }
+            | ClientMessage::PasswordResetRequest { .. }
+            | ClientMessage::PasswordResetConfirm { .. }
+            | ClientMessage::LogoutRequest
+            | ClientMessage::LogoutEverywhereRequest
+            | ClientMessage::PostViewRequest { .. } => Access::Public,
+            ClientMessage::OwnUserInformationRequest
+            | ClientMessage::TimelineRequest { .. }
+            | ClientMessage::EmailVerificationResendRequest
+            | ClientMessage::SessionListRequest
+            | ClientMessage::SessionRevokeRequest { .. }
+            | ClientMessage::TotpEnrolRequest
+            | ClientMessage::TotpEnrolConfirm { .. }
+            | ClientMessage::TotpDisableRequest { .. }
+            | ClientMessage::DataExportRequest
+            | ClientMessage::AccountDeletionRequest { .. }
+            | ClientMessage::AccountDeletionCancelRequest
+            | ClientMessage::AccountMoveRequest { .. }
+            | ClientMessage::AccountImportRequest { .. }
+            | ClientMessage::RelationshipRequest { .. }
+            | ClientMessage::RelationshipListRequest
+            | ClientMessage::PresenceRequest { .. }
+            | ClientMessage::ReportRequest { .. }
+            | ClientMessage::TextPostCreateRequest { .. } => Access::Session,
+            // Suspending users needs `SuspendUsers` as well, which depends on the action and is
+            // checked by `moderation::act`.
+            ClientMessage::ModerationReportListRequest { .. }
+            | ClientMessage::ModerationActionRequest { .. } => {
+                Access::Permitted(Permission::ModerateContent)
+            }
+            ClientMessage::RoleChangeRequest { .. } => Access::Permitted(Permission::ManageRoles),
+            ClientMessage::FilterRuleListRequest
+            | ClientMessage::FilterRuleAddRequest { .. }
+            | ClientMessage::FilterRuleRemoveRequest { .. } => {
+                Access::Permitted(Permission::ManageFilters)
+            }
+        }
+    }
+}
+
+/// Everything handlers use, besides the connection they run for.
+pub(crate) struct HandlerContext<'a> {
+    pub(crate) appstate: std::sync::Arc<crate::InnerAppState>,
+    pub(crate) limiter: &'a GeneralRateLimiter,
+    pub(crate) auth_limiter: &'a AuthRateLimiter,
+    pub(crate) origin: SessionOrigin,
+    pub(crate) ev_log: EventLogger,
+}
+
+/// Check that `conn` may send `msg`, and hand it to its handler. Answers with the messages to
+/// send back, or fails with the reason to close the connection with.
+///
+/// Messages that need a session are answered with `Unauthenticated` without one, and messages
+/// that need a permission with `PermissionDenied` if the user doesn't have it.
+pub(crate) async fn dispatch(
+    msg: ClientMessage,
+    conn: &mut SessionData,
+    ctx: &HandlerContext<'_>,
+) -> Result<Vec<ServerMessage>, String> {
+    let ev_log = &ctx.ev_log;
+    let access = msg.access();
+    let user = match (access, &conn.user) {
+        (Access::Public, user) => user.clone(),
+        (_, None) => return Ok(vec![ServerMessage::Unauthenticated]),
+        (Access::Session, Some(user)) => Some(user.clone()),
+        (Access::Permitted(permission), Some(user)) => {
+            let db = &ctx.appstate.db;
+            match permissions::ensure_permitted(user, permission, db).await {
+                Ok(_) => Some(user.clone()),
+                Err(LuminaError::NotPermitted) => {
+                    moderation_elog!(
+                        ev_log,
+                        "User {} was refused {:?}: not permitted",
+                        user.username.clone().color_bright_cyan(),
+                        permission
+                    );
+                    return Ok(vec![ServerMessage::PermissionDenied]);
+                }
+                Err(e) => {
+                    error_elog!(ev_log, "While checking permissions: {:?}", e);
+                    return Ok(vec![internal_error()]);
+                }
+            }
+        }
+    };
+    let client_ip = ctx.origin.ip;
+    let limiter = ctx.limiter;
+    let auth_limiter = ctx.auth_limiter;
+    let session_origin = &ctx.origin;
+    let mut replies = vec![];
+    match (msg, user.as_ref()) {
+        (ClientMessage::PostViewRequest { post_id }, viewer) => {
+            let db = &ctx.appstate.db;
+            let msgback = handle_post_view(post_id, viewer, db, ev_log).await;
+            replies.push(msgback);
+        }
+        (
+            ClientMessage::Introduction {
+                client_kind,
+                try_revive,
+                protocol_version,
+                features,
+                encoding,
+            },
+            _,
+        ) => {
+            match negotiate(
+                &client_kind,
+                protocol_version,
+                &features,
+                encoding.as_deref(),
+            ) {
+                Ok(negotiated) => {
+                    info_elog!(
+                        ev_log,
+                        "Client introduced itself: {} client, protocol version {}.",
+                        client_kind,
+                        negotiated.protocol_version
+                    );
+                    conn.client_type = Some(negotiated.client_type);
+                    conn.protocol_version = negotiated.protocol_version;
+                    conn.features = negotiated.features.clone();
+                    conn.encoding = negotiated.encoding;
+                    // Clients from before versioning don't know the response.
+                    if protocol_version.is_some() {
+                        replies.push(ServerMessage::IntroductionResponse {
+                            protocol_version: negotiated.protocol_version,
+                            min_protocol_version: MIN_PROTOCOL_VERSION,
+                            max_protocol_version: MAX_PROTOCOL_VERSION,
+                            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+                            features: negotiated.features,
+                            encoding: negotiated.encoding,
+                        });
+                    }
+                }
+                Err(why) => {
+                    info_elog!(ev_log, "Client refused: {}", why);
+                    return Err(why);
+                }
+            }
+            match try_revive {
+                Some(token) => {
+                    let db = &ctx.appstate.db;
+                    match User::revive_session_from_token(token.clone(), db).await {
+                        Ok((session_reference, user)) => {
+                            incoming_elog!(
+                                ev_log,
+                                "Session revived for user: {}",
+                                user.clone().username.color_bright_cyan()
+                            );
+                            conn.user = Some(user.clone());
+                            conn.session_id = Some(session_reference.session_id);
+                            replies.push(ServerMessage::AuthSuccess {
+                                token,
+                                username: user.username,
+                            });
+                        }
+                        Err(LuminaError::AccountLocked(account_state)) => {
+                            info_elog!(
+                                ev_log,
+                                "Session revival refused: account state {:?}, sessions ended.",
+                                account_state
+                            );
+                            replies.push(ServerMessage::AccountLocked {
+                                account_state: *account_state,
+                            });
+                        }
+                        Err(e) => {
+                            match e {
+                                LuminaError::DbError(LuminaDbError::Postgres(postgres_error)) => {
+                                    // This is not the kind of practice I'd want, but it does the job
+                                    let postgres_error_dbg = format!("{:?}", postgres_error);
+                                    // Check if it's a "no rows returned" type error
+                                    // I wish I could just open those fields...
+                                    if postgres_error_dbg.contains("kind: RowCount, cause: None") {
+                                        info_elog!(
+                                            ev_log,
+                                            "Session revival failed: token not found or expired."
+                                        );
+                                    } else {
+                                        info_elog!(
+                                            ev_log,
+                                            "Session revival failed: database error: {postgres_error_dbg}"
+                                        );
+                                    }
+                                }
+
+                                _ => {
+                                    info_elog!(ev_log, "Session revival failed: {:?}", e);
+                                }
+                            }
+                            replies.push(ServerMessage::AuthFailure);
+                        }
+                    }
+                }
+                None => {
+                    replies.push(ServerMessage::Greeting {
+                        greeting: "Hello from server!".to_string(),
+                    });
+                }
+            }
+        }
+        (
+            ClientMessage::RegisterRequest {
+                email,
+                username,
+                password,
+                invite_code,
+            },
+            _,
+        ) => {
+            let db = &ctx.appstate.db;
+            let (msgback, session) = handle_register(
+                email,
+                username,
+                password,
+                invite_code,
+                ctx.appstate.config.registration_mode,
+                db,
+                ev_log,
+                session_origin,
+            )
+            .await;
+            if let Some((session_reference, user)) = session {
+                conn.session_id = Some(session_reference.session_id);
+                conn.user = Some(user);
+            }
+            replies.push(msgback);
+        }
+        (
+            ClientMessage::RegisterPrecheck {
+                email,
+                username,
+                password,
+            },
+            _,
+        ) => {
+            let db = &ctx.appstate.db;
+            let msgback =
+                match crate::user::register_validitycheck(email, username, password, db).await {
+                    Ok(_) => ServerMessage::RegisterPrecheckResponse {
+                        ok: true,
+                        why: "".to_string(),
+                        error: None,
+                        registration_mode: ctx.appstate.config.registration_mode,
+                    },
+                    Err(e) => {
+                        let error = RegisterError::from(&e);
+                        if error.code == RegisterErrorCode::Internal {
+                            error_elog!(ev_log, "While prechecking registration: {:?}", e);
+                        }
+                        ServerMessage::RegisterPrecheckResponse {
+                            ok: false,
+                            why: error.message.clone(),
+                            error: Some(error),
+                            registration_mode: ctx.appstate.config.registration_mode,
+                        }
+                    }
+                };
+            replies.push(msgback);
+        }
+        (
+            ClientMessage::LoginAuthenticationRequest {
+                email_username,
+                password,
+            },
+            _,
+        ) => {
+            let db = &ctx.appstate.db;
+            let (msgback, session) = handle_login(
+                email_username,
+                password,
+                auth_limiter,
+                db,
+                ev_log,
+                session_origin,
+            )
+            .await;
+            if let Some((session_reference, user)) = session {
+                conn.session_id = Some(session_reference.session_id);
+                conn.user = Some(user);
+            }
+            replies.push(msgback);
+        }
+        (ClientMessage::SecondFactorResponse { challenge, code }, _) => {
+            let db = &ctx.appstate.db;
+            let (msgback, session) =
+                handle_second_factor(challenge, code, auth_limiter, db, ev_log, session_origin)
+                    .await;
+            if let Some((session_reference, user)) = session {
+                conn.session_id = Some(session_reference.session_id);
+                conn.user = Some(user);
+            }
+            replies.push(msgback);
+        }
+        (ClientMessage::OwnUserInformationRequest, Some(user)) => {
+            let db = &ctx.appstate.db;
+            let msgback = handle_own_user_information(user, db, ev_log).await;
+            replies.push(msgback);
+        }
+        (
+            ClientMessage::TimelineRequest {
+                by_name: name,
+                page,
+            },
+            Some(user),
+        ) => {
+            let db = &ctx.appstate.db;
+            let msgback = handle_timeline(name, page, user.clone(), db, ev_log).await;
+            replies.push(msgback);
+        }
+        (ClientMessage::EmailVerificationRequest { token }, _) => {
+            let db = &ctx.appstate.db;
+            let ok = match User::verify_email(token, db).await {
+                Ok(user) => {
+                    info_elog!(
+                        ev_log,
+                        "Email address verified for user: {}",
+                        user.username.color_bright_cyan()
+                    );
+                    true
+                }
+                Err(LuminaError::TokenInvalid) => {
+                    info_elog!(
+                        ev_log,
+                        "Email verification failed: token not found or expired."
+                    );
+                    false
+                }
+                Err(e) => {
+                    error_elog!(ev_log, "While verifying email address: {:?}", e);
+                    false
+                }
+            };
+            replies.push(ServerMessage::EmailVerificationResponse { ok });
+        }
+        (ClientMessage::EmailVerificationResendRequest, Some(user)) => {
+            // Resends send mail, so they count against the stricter auth limiter.
+            let msgback = if !auth_limiter.allow_ip(client_ip).await {
+                authentication_error_elog!(
+                    ev_log,
+                    "Rate-limited verification email request from IP: {:?}",
+                    client_ip
+                );
+                ServerMessage::EmailVerificationResendResponse {
+                    ok: false,
+                    why: "Too many attempts, try again later.".to_string(),
+                }
+            } else {
+                let db = &ctx.appstate.db;
+                match user.is_email_verified(db).await {
+                    Ok(true) => ServerMessage::EmailVerificationResendResponse {
+                        ok: false,
+                        why: "Email address already verified".to_string(),
+                    },
+                    Ok(false) => match user.send_email_verification(db).await {
+                        Ok(()) => ServerMessage::EmailVerificationResendResponse {
+                            ok: true,
+                            why: String::new(),
+                        },
+                        Err(e) => {
+                            error_elog!(ev_log, "While queueing verification email: {:?}", e);
+                            internal_error()
+                        }
+                    },
+                    Err(e) => {
+                        error_elog!(ev_log, "While checking email verification: {:?}", e);
+                        internal_error()
+                    }
+                }
+            };
+            replies.push(msgback);
+        }
+        (ClientMessage::PasswordResetRequest { email }, _) => {
+            // Reset requests send mail, so they count against the stricter auth limiter.
+            if !auth_limiter.allow_ip(client_ip).await {
+                authentication_error_elog!(
+                    ev_log,
+                    "Rate-limited password reset request from IP: {:?}",
+                    client_ip
+                );
+            } else {
+                let db = &ctx.appstate.db;
+                match User::request_password_reset(email.clone(), db).await {
+                    Ok(()) => incoming_elog!(
+                        ev_log,
+                        "Password reset requested for {}",
+                        email.color_orange()
+                    ),
+                    Err(e) => error_elog!(ev_log, "While requesting password reset: {:?}", e),
+                }
+            }
+            // Always the same answer, to not leak which addresses have accounts.
+            replies.push(ServerMessage::PasswordResetRequested);
+        }
+        (
+            ClientMessage::PasswordResetConfirm {
+                token,
+                new_password,
+            },
+            _,
+        ) => {
+            let msgback = if !auth_limiter.allow_ip(client_ip).await {
+                authentication_error_elog!(
+                    ev_log,
+                    "Rate-limited password reset from IP: {:?}",
+                    client_ip
+                );
+                ServerMessage::PasswordResetResponse {
+                    ok: false,
+                    why: "Too many attempts, try again later".to_string(),
+                }
+            } else {
+                let db = &ctx.appstate.db;
+                match User::reset_password(token, new_password, db).await {
+                    Ok(user) => {
+                        info_elog!(
+                            ev_log,
+                            "Password reset for user: {}",
+                            user.username.color_bright_cyan()
+                        );
+                        ServerMessage::PasswordResetResponse {
+                            ok: true,
+                            why: "".to_string(),
+                        }
+                    }
+                    Err(LuminaError::RegisterPasswordNotValid(why)) => {
+                        ServerMessage::PasswordResetResponse {
+                            ok: false,
+                            why: format!("Password invalid: {}", why),
+                        }
+                    }
+                    Err(LuminaError::TokenInvalid) => ServerMessage::PasswordResetResponse {
+                        ok: false,
+                        why: "Reset link invalid or expired".to_string(),
+                    },
+                    Err(e) => {
+                        error_elog!(ev_log, "While resetting password: {:?}", e);
+                        ServerMessage::PasswordResetResponse {
+                            ok: false,
+                            why: "Could not reset password".to_string(),
+                        }
+                    }
+                }
+            };
+            replies.push(msgback);
+        }
+        (ClientMessage::LogoutRequest, _) => {
+            if let (Some(user), Some(session_id)) = (&conn.user, conn.session_id) {
+                let db = &ctx.appstate.db;
+                match user.revoke_session(session_id, db).await {
+                    Ok(_) => incoming_elog!(
+                        ev_log,
+                        "User {} logged out.",
+                        user.username.clone().color_bright_cyan()
+                    ),
+                    Err(e) => error_elog!(ev_log, "While ending session: {:?}", e),
+                }
+            }
+            conn.user = None;
+            conn.session_id = None;
+            replies.push(ServerMessage::LoggedOut);
+        }
+        (ClientMessage::LogoutEverywhereRequest, _) => {
+            if let Some(user) = &conn.user {
+                let db = &ctx.appstate.db;
+                match user.revoke_all_sessions(db).await {
+                    Ok(count) => incoming_elog!(
+                        ev_log,
+                        "User {} logged out everywhere, ending {} sessions.",
+                        user.username.clone().color_bright_cyan(),
+                        count
+                    ),
+                    Err(e) => error_elog!(ev_log, "While ending sessions: {:?}", e),
+                }
+            }
+            conn.user = None;
+            conn.session_id = None;
+            replies.push(ServerMessage::LoggedOut);
+        }
+        (ClientMessage::SessionListRequest, Some(user)) => {
+            let db = &ctx.appstate.db;
+            match user.list_sessions(db).await {
+                Ok(sessions) => {
+                    replies.push(ServerMessage::SessionListResponse {
+                        sessions,
+                        current_session_id: conn.session_id,
+                    });
+                }
+                Err(e) => {
+                    error_elog!(ev_log, "While listing sessions: {:?}", e);
+                    replies.push(internal_error());
+                }
+            }
+        }
+        (ClientMessage::SessionRevokeRequest { session_id }, Some(user)) => {
+            let db = &ctx.appstate.db;
+            let ok = match user.revoke_session(session_id, db).await {
+                Ok(ok) => ok,
+                Err(e) => {
+                    error_elog!(ev_log, "While revoking session: {:?}", e);
+                    false
+                }
+            };
+            replies.push(ServerMessage::SessionRevokeResponse { session_id, ok });
+            if ok && conn.session_id == Some(session_id) {
+                conn.user = None;
+                conn.session_id = None;
+                replies.push(ServerMessage::LoggedOut);
+            }
+        }
+        (ClientMessage::TotpEnrolRequest, Some(user)) => {
+            let db = &ctx.appstate.db;
+            let msgback = match two_factor::begin_enrolment(user, db).await {
+                Ok((provisioning_uri, secret)) => ServerMessage::TotpEnrolResponse {
+                    ok: true,
+                    provisioning_uri,
+                    secret,
+                },
+                Err(e) => {
+                    if !matches!(e, LuminaError::TwoFactorAlreadyEnabled) {
+                        error_elog!(ev_log, "While starting two-factor enrolment: {:?}", e);
+                    }
+                    ServerMessage::TotpEnrolResponse {
+                        ok: false,
+                        provisioning_uri: "".to_string(),
+                        secret: "".to_string(),
+                    }
+                }
+            };
+            replies.push(msgback);
+        }
+        (ClientMessage::TotpEnrolConfirm { code }, Some(user)) => {
+            let msgback = if !auth_limiter.allow_ip(client_ip).await {
+                ServerMessage::TotpEnrolConfirmResponse {
+                    ok: false,
+                    recovery_codes: vec![],
+                }
+            } else {
+                let db = &ctx.appstate.db;
+                match two_factor::confirm_enrolment(user, &code, db).await {
+                    Ok(recovery_codes) => {
+                        info_elog!(
+                            ev_log,
+                            "User {} enabled two-factor authentication.",
+                            user.username.clone().color_bright_cyan()
+                        );
+                        ServerMessage::TotpEnrolConfirmResponse {
+                            ok: true,
+                            recovery_codes,
+                        }
+                    }
+                    Err(LuminaError::AuthenticationWrongSecondFactor)
+                    | Err(LuminaError::TwoFactorAlreadyEnabled) => {
+                        ServerMessage::TotpEnrolConfirmResponse {
+                            ok: false,
+                            recovery_codes: vec![],
+                        }
+                    }
+                    Err(e) => {
+                        error_elog!(ev_log, "While confirming two-factor enrolment: {:?}", e);
+                        ServerMessage::TotpEnrolConfirmResponse {
+                            ok: false,
+                            recovery_codes: vec![],
+                        }
+                    }
+                }
+            };
+            replies.push(msgback);
+        }
+        (ClientMessage::TotpDisableRequest { password, code }, Some(user)) => {
+            let ok = if !auth_limiter.allow_ip(client_ip).await {
+                authentication_error_elog!(
+                    ev_log,
+                    "Rate-limited two-factor disable attempt from IP: {:?}",
+                    client_ip
+                );
+                false
+            } else {
+                let db = &ctx.appstate.db;
+                match user.disable_two_factor(password, code, db).await {
+                    Ok(()) => {
+                        info_elog!(
+                            ev_log,
+                            "User {} disabled two-factor authentication.",
+                            user.username.clone().color_bright_cyan()
+                        );
+                        true
+                    }
+                    Err(e) => {
+                        authentication_error_elog!(
+                            ev_log,
+                            "User {} {} disabled two-factor authentication: {:?}",
+                            user.username.clone().color_bright_cyan(),
+                            "not".color_red(),
+                            e
+                        );
+                        false
+                    }
+                }
+            };
+            replies.push(ServerMessage::TotpDisableResponse { ok });
+        }
+        (ClientMessage::DataExportRequest, Some(user)) => {
+            let db = &ctx.appstate.db;
+            let ok = match account_data::request_export(user, db).await {
+                Ok(ok) => ok,
+                Err(e) => {
+                    error_elog!(ev_log, "While queueing data export: {:?}", e);
+                    false
+                }
+            };
+            if ok {
+                incoming_elog!(
+                    ev_log,
+                    "User {} asked for a data export.",
+                    user.username.clone().color_bright_cyan()
+                );
+            }
+            replies.push(ServerMessage::DataExportResponse { ok });
+        }
+        (ClientMessage::AccountDeletionRequest { password }, Some(user)) => {
+            let scheduled_for = if !auth_limiter.allow_ip(client_ip).await {
+                authentication_error_elog!(
+                    ev_log,
+                    "Rate-limited account deletion attempt from IP: {:?}",
+                    client_ip
+                );
+                None
+            } else {
+                let db = &ctx.appstate.db;
+                match user.schedule_deletion(password, db).await {
+                    Ok(at) => {
+                        info_elog!(
+                            ev_log,
+                            "User {} scheduled their account for deletion.",
+                            user.username.clone().color_bright_cyan()
+                        );
+                        Some(at)
+                    }
+                    Err(e) => {
+                        authentication_error_elog!(
+                            ev_log,
+                            "User {} {} scheduled their account for deletion: {:?}",
+                            user.username.clone().color_bright_cyan(),
+                            "not".color_red(),
+                            e
+                        );
+                        None
+                    }
+                }
+            };
+            replies.push(ServerMessage::AccountDeletionResponse {
+                ok: scheduled_for.is_some(),
+                scheduled_for,
+            });
+        }
+        (ClientMessage::AccountDeletionCancelRequest, Some(user)) => {
+            let db = &ctx.appstate.db;
+            let ok = match user.cancel_deletion(db).await {
+                Ok(ok) => ok,
+                Err(e) => {
+                    error_elog!(ev_log, "While cancelling account deletion: {:?}", e);
+                    false
+                }
+            };
+            if ok {
+                info_elog!(
+                    ev_log,
+                    "User {} cancelled the deletion of their account.",
+                    user.username.clone().color_bright_cyan()
+                );
+            }
+            replies.push(ServerMessage::AccountDeletionResponse {
+                ok,
+                scheduled_for: None,
+            });
+        }
+        (ClientMessage::AccountMoveRequest { password, moved_to }, Some(user)) => {
+            let ok = if !auth_limiter.allow_ip(client_ip).await {
+                authentication_error_elog!(
+                    ev_log,
+                    "Rate-limited account move attempt from IP: {:?}",
+                    client_ip
+                );
+                false
+            } else {
+                let db = &ctx.appstate.db;
+                match user.set_moved_to(password, moved_to.clone(), db).await {
+                    Ok(()) => {
+                        info_elog!(
+                            ev_log,
+                            "User {} marked their account as moved to {:?}.",
+                            user.username.clone().color_bright_cyan(),
+                            moved_to
+                        );
+                        true
+                    }
+                    Err(e) => {
+                        authentication_error_elog!(
+                            ev_log,
+                            "User {} {} marked their account as moved: {:?}",
+                            user.username.clone().color_bright_cyan(),
+                            "not".color_red(),
+                            e
+                        );
+                        false
+                    }
+                }
+            };
+            replies.push(ServerMessage::AccountMoveResponse { ok });
+        }
+        (ClientMessage::AccountImportRequest { archive }, Some(user)) => {
+            let msgback = if !auth_limiter.allow_ip(client_ip).await {
+                ServerMessage::AccountImportResponse {
+                    ok: false,
+                    why: "Too many attempts, try again later.".to_string(),
+                    imported_posts: 0,
+                    rejected_posts: 0,
+                    restored_follows: 0,
+                }
+            } else {
+                let result = match STANDARD.decode(archive) {
+                    Ok(archive) => {
+                        let db = &ctx.appstate.db;
+                        migration::import_archive(user, archive, db, ev_log).await
+                    }
+                    Err(_) => Err(LuminaError::MigrationArchiveInvalid),
+                };
+                match result {
+                    Ok(summary) => ServerMessage::AccountImportResponse {
+                        ok: true,
+                        why: String::new(),
+                        imported_posts: summary.posts,
+                        rejected_posts: summary.rejected,
+                        restored_follows: summary.follows,
+                    },
+                    Err(e) => {
+                        warn_elog!(
+                            ev_log,
+                            "Import into the account of {} failed: {:?}",
+                            user.username.clone().color_bright_cyan(),
+                            e
+                        );
+                        ServerMessage::AccountImportResponse {
+                            ok: false,
+                            why: e.to_string(),
+                            imported_posts: 0,
+                            rejected_posts: 0,
+                            restored_follows: 0,
+                        }
+                    }
+                }
+            };
+            replies.push(msgback);
+        }
+        (
+            ClientMessage::RelationshipRequest {
+                username,
+                relationship,
+                active,
+            },
+            Some(user),
+        ) => {
+            let db = &ctx.appstate.db;
+            let result = match User::get_user_by_identifier(username.clone(), db).await {
+                Ok(target) if active => relationships::add(user, &target, relationship, db)
+                    .await
+                    .map(|_| true),
+                Ok(target) => relationships::remove(user, &target, relationship, db).await,
+                Err(e) => Err(e),
+            };
+            let ok = match result {
+                Ok(ok) => ok,
+                Err(e) => {
+                    warn_elog!(
+                        ev_log,
+                        "User {} could not update their {:?} of {}: {:?}",
+                        user.username.clone().color_bright_cyan(),
+                        relationship,
+                        username,
+                        e
+                    );
+                    false
+                }
+            };
+            if ok {
+                // The user's other devices show blocks and mutes too.
+                connections::push(
+                    &db.get_redis_pool(),
+                    user.id,
+                    ServerMessage::RelationshipResponse {
+                        username: username.clone(),
+                        relationship,
+                        ok,
+                    },
+                    Some(conn.connection_id),
+                )
+                .await;
+            }
+            replies.push(ServerMessage::RelationshipResponse {
+                username,
+                relationship,
+                ok,
+            });
+        }
+        (ClientMessage::PresenceRequest { username }, Some(user)) => {
+            let db = &ctx.appstate.db;
+            let presence = match User::get_user_by_identifier(username.clone(), db).await {
+                Ok(target) => {
+                    match relationships::ensure_may_interact(user.id, target.id, db).await {
+                        Ok(()) => connections::presence(&db.get_redis_pool(), target.id).await,
+                        Err(e) => Err(e),
+                    }
+                }
+                Err(e) => Err(e),
+            };
+            let presence = match presence {
+                Ok(presence) => presence,
+                Err(e) => {
+                    if !matches!(e, LuminaError::Blocked) {
+                        warn_elog!(
+                            ev_log,
+                            "Could not look up the presence of {} for {}: {:?}",
+                            username,
+                            user.username.clone().color_bright_cyan(),
+                            e
+                        );
+                    }
+                    connections::Presence {
+                        online: false,
+                        last_seen: None,
+                    }
+                }
+            };
+            replies.push(ServerMessage::PresenceResponse {
+                username,
+                online: presence.online,
+                last_seen: presence.last_seen,
+            });
+        }
+        (ClientMessage::RelationshipListRequest, Some(user)) => {
+            let db = &ctx.appstate.db;
+            match (
+                relationships::list(user, Relationship::Block, db).await,
+                relationships::list(user, Relationship::Mute, db).await,
+            ) {
+                (Ok(blocked), Ok(muted)) => {
+                    replies.push(ServerMessage::RelationshipListResponse { blocked, muted });
+                }
+                (Err(e), _) | (_, Err(e)) => {
+                    error_elog!(ev_log, "While listing blocks and mutes: {:?}", e);
+                    replies.push(internal_error());
+                }
+            }
+        }
+        (
+            ClientMessage::ReportRequest {
+                target,
+                reason,
+                comment,
+            },
+            Some(user),
+        ) => {
+            let ok = if !limiter.allow_ip(client_ip).await {
+                warn_elog!(ev_log, "Rate-limited report from IP: {:?}", client_ip);
+                false
+            } else {
+                let db = &ctx.appstate.db;
+                match moderation::create_report(user, target.clone(), reason, comment, db).await {
+                    Ok(()) => {
+                        info_elog!(
+                            ev_log,
+                            "User {} reported {:?} for {}.",
+                            user.username.clone().color_bright_cyan(),
+                            target,
+                            reason.as_str()
+                        );
+                        true
+                    }
+                    Err(e) => {
+                        warn_elog!(
+                            ev_log,
+                            "User {} could not report {:?}: {:?}",
+                            user.username.clone().color_bright_cyan(),
+                            target,
+                            e
+                        );
+                        false
+                    }
+                }
+            };
+            replies.push(ServerMessage::ReportResponse { ok });
+        }
+        (ClientMessage::ModerationReportListRequest { include_resolved }, Some(_)) => {
+            let db = &ctx.appstate.db;
+            let msgback = match moderation::list_reports(include_resolved, db).await {
+                Ok(reports) => ServerMessage::ModerationReportListResponse { reports },
+                Err(e) => {
+                    error_elog!(ev_log, "While listing reports: {:?}", e);
+                    internal_error()
+                }
+            };
+            replies.push(msgback);
+        }
+        (ClientMessage::ModerationActionRequest { report_id, action }, Some(user)) => {
+            let db = &ctx.appstate.db;
+            let msgback = match moderation::act(user, report_id, action, db, ev_log.clone()).await {
+                Ok(()) => ServerMessage::ModerationActionResponse {
+                    report_id,
+                    ok: true,
+                },
+                Err(LuminaError::NotPermitted) => {
+                    moderation_elog!(
+                        ev_log,
+                        "User {} was refused {:?} on report {}: not permitted",
+                        user.username.clone().color_bright_cyan(),
+                        action,
+                        report_id
+                    );
+                    ServerMessage::PermissionDenied
+                }
+                Err(e) => {
+                    warn_elog!(
+                        ev_log,
+                        "{} could not resolve report {}: {:?}",
+                        user.username.clone().color_bright_cyan(),
+                        report_id,
+                        e
+                    );
+                    ServerMessage::ModerationActionResponse {
+                        report_id,
+                        ok: false,
+                    }
+                }
+            };
+            replies.push(msgback);
+        }
+        (ClientMessage::RoleChangeRequest { username, role }, Some(user)) => {
+            let db = &ctx.appstate.db;
+            let msgback = match permissions::change_role(user, &username, role, db).await {
+                Ok(ok) => {
+                    if ok {
+                        moderation_elog!(
+                            ev_log,
+                            "{} gave {} the role {}",
+                            user.username.clone().color_bright_cyan(),
+                            username.clone().color_bright_cyan(),
+                            role.as_str()
+                        );
+                    }
+                    ServerMessage::RoleChangeResponse { username, role, ok }
+                }
+                Err(LuminaError::NotPermitted) => {
+                    moderation_elog!(
+                        ev_log,
+                        "User {} was refused giving {} the role {}: not permitted",
+                        user.username.clone().color_bright_cyan(),
+                        username.clone().color_bright_cyan(),
+                        role.as_str()
+                    );
+                    ServerMessage::PermissionDenied
+                }
+                Err(e) => {
+                    warn_elog!(
+                        ev_log,
+                        "{} could not change the role of {}: {:?}",
+                        user.username.clone().color_bright_cyan(),
+                        username,
+                        e
+                    );
+                    ServerMessage::RoleChangeResponse {
+                        username,
+                        role,
+                        ok: false,
+                    }
+                }
+            };
+            replies.push(msgback);
+        }
+        (ClientMessage::TextPostCreateRequest { content }, Some(user)) => {
+            let db = &ctx.appstate.db;
+            let msgback =
+                handle_text_post_create(content, user, limiter, client_ip, db, ev_log).await;
+            replies.push(msgback);
+        }
+        (ClientMessage::FilterRuleListRequest, Some(_)) => {
+            let db = &ctx.appstate.db;
+            let msgback = match filters::list_rules(db).await {
+                Ok(rules) => ServerMessage::FilterRuleListResponse { rules },
+                Err(e) => {
+                    error_elog!(ev_log, "While listing filter rules: {:?}", e);
+                    internal_error()
+                }
+            };
+            replies.push(msgback);
+        }
+        (
+            ClientMessage::FilterRuleAddRequest {
+                pattern,
+                is_regex,
+                action,
+            },
+            Some(user),
+        ) => {
+            let db = &ctx.appstate.db;
+            let msgback = match filters::add_rule(user, pattern.clone(), is_regex, action, db).await
+            {
+                Ok(id) => {
+                    moderation_elog!(
+                        ev_log,
+                        "{} added filter rule {} ({}{:?}): {}",
+                        user.username.clone().color_bright_cyan(),
+                        id,
+                        if is_regex { "regex, " } else { "" },
+                        pattern,
+                        action.as_str()
+                    );
+                    ServerMessage::FilterRuleAddResponse {
+                        ok: true,
+                        why: String::new(),
+                        id: Some(id),
+                    }
+                }
+                Err(e) => ServerMessage::FilterRuleAddResponse {
+                    ok: false,
+                    why: e.to_string(),
+                    id: None,
+                },
+            };
+            replies.push(msgback);
+        }
+        (ClientMessage::FilterRuleRemoveRequest { id }, Some(user)) => {
+            let db = &ctx.appstate.db;
+            let ok = match filters::remove_rule(id, db).await {
+                Ok(ok) => {
+                    if ok {
+                        moderation_elog!(
+                            ev_log,
+                            "{} removed filter rule {}",
+                            user.username.clone().color_bright_cyan(),
+                            id
+                        );
+                    }
+                    ok
+                }
+                Err(e) => {
+                    warn_elog!(ev_log, "Could not remove filter rule {}: {:?}", id, e);
+                    false
+                }
+            };
+            replies.push(ServerMessage::FilterRuleRemoveResponse { id, ok });
+        }
+        // Only reached when a message is registered as public, but its handler needs a user.
+        (_, None) => replies.push(ServerMessage::Unauthenticated),
+    }
+    Ok(replies)
+}
+
+/// Why a message got an [`ServerMessage::ErrorResponse`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ErrorCode {
+    /// The message could not be read.
+    InvalidMessage,
+    /// The message is one only the server sends.
+    UnexpectedMessage,
+    /// The server failed to handle the message. What went wrong is logged, not sent.
+    Internal,
+}
+
+/// A [`ServerMessage`] on a WebSocket, with the `request_id` the client tagged its request with,
+/// if any. Replies carry the id of the request they answer, so clients can send requests without
+/// waiting for the replies to the ones before.
+///
+/// On the wire the id is a field next to `type`:
+/// `{"type": "timeline_request", "request_id": "7", "by_name": "global", "page": 0}`.
+#[derive(serde::Serialize, Debug, Clone)]
+pub(crate) struct Envelope {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) request_id: Option<String>,
+    #[serde(flatten)]
+    pub(crate) message: ServerMessage,
 }
 
-pub(crate) fn msgtojson(msg: Message) -> String {
+/// Read a message from a client, with the id it tagged it with. The id is read on its own, so
+/// that even a message that can't be read gets a reply with it. Fails with the
+/// [`ServerMessage::ErrorResponse`] to reply with.
+pub(crate) fn decode(text: &str) -> (Option<String>, Result<ClientMessage, ServerMessage>) {
+    let value = match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(value) => value,
+        Err(e) => return (None, Err(invalid_message(e))),
+    };
+    let request_id = value
+        .get("request_id")
+        .and_then(|id| id.as_str())
+        .map(str::to_string);
+    let decoded = match ClientMessage::deserialize(&value) {
+        Ok(msg) => Ok(msg),
+        Err(_) if ServerMessage::deserialize(&value).is_ok() => Err(unexpected_message()),
+        Err(e) => Err(invalid_message(e)),
+    };
+    (request_id, decoded)
+}
+
+/// Like [`decode`], for a binary frame holding the same message as a MessagePack map.
+pub(crate) fn decode_msgpack(
+    bytes: &[u8],
+) -> (Option<String>, Result<ClientMessage, ServerMessage>) {
+    #[derive(Deserialize)]
+    struct Tagged {
+        #[serde(default)]
+        request_id: Option<String>,
+    }
+    let request_id = rmp_serde::from_slice::<Tagged>(bytes)
+        .ok()
+        .and_then(|tagged| tagged.request_id);
+    let decoded = match rmp_serde::from_slice::<ClientMessage>(bytes) {
+        Ok(msg) => Ok(msg),
+        Err(_) if rmp_serde::from_slice::<ServerMessage>(bytes).is_ok() => {
+            Err(unexpected_message())
+        }
+        Err(e) => Err(invalid_message(e)),
+    };
+    (request_id, decoded)
+}
+
+fn invalid_message(e: impl std::fmt::Display) -> ServerMessage {
+    ServerMessage::ErrorResponse {
+        code: ErrorCode::InvalidMessage,
+        message: e.to_string(),
+    }
+}
+
+fn unexpected_message() -> ServerMessage {
+    ServerMessage::ErrorResponse {
+        code: ErrorCode::UnexpectedMessage,
+        message: "This message is only sent by the server.".to_string(),
+    }
+}
+
+/// The reply to a message the server failed to handle, after logging why.
+pub(crate) fn internal_error() -> ServerMessage {
+    ServerMessage::ErrorResponse {
+        code: ErrorCode::Internal,
+        message: "Something went wrong on the server.".to_string(),
+    }
+}
+
+/// How messages to a client are encoded, agreed on in the `Introduction`. JSON goes in text
+/// frames, MessagePack in binary frames. Either way it's the same [`Envelope`], so a MessagePack
+/// message is a map with the same keys as the JSON object.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Encoding {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+impl Encoding {
+    /// A reply as a WebSocket frame in this encoding.
+    pub(crate) fn encode(self, request_id: &Option<String>, msg: ServerMessage) -> ws::Message {
+        match self {
+            Encoding::Json => ws::Message::from(replytojson(request_id, msg)),
+            Encoding::Msgpack => match rmp_serde::to_vec_named(&Envelope {
+                request_id: request_id.clone(),
+                message: msg,
+            }) {
+                Ok(bytes) => ws::Message::Binary(bytes),
+                Err(e) => ws::Message::from(msgtojson(ServerMessage::SerialisationError {
+                    error: format!("{:?}", e),
+                })),
+            },
+        }
+    }
+}
+
+/// Like [`msgtojson`], tagged with the id of the request the message answers.
+pub(crate) fn replytojson(request_id: &Option<String>, msg: ServerMessage) -> String {
+    match request_id {
+        None => msgtojson(msg),
+        Some(_) => serde_json::to_string(&Envelope {
+            request_id: request_id.clone(),
+            message: msg,
+        })
+        .unwrap_or_else(|e| {
+            msgtojson(ServerMessage::SerialisationError {
+                error: format!("{:?}", e),
+            })
+        }),
+    }
+}
+
+pub(crate) fn msgtojson(msg: ServerMessage) -> String {
     serde_json::to_string(&msg).unwrap_or_else(|e| -> String {
-        serde_json::to_string(&Message::SerialisationError {
+        serde_json::to_string(&ServerMessage::SerialisationError {
             error: format!("{:?}", e),
         })
         .unwrap_or_else(|e| {
@@ -552,11 +1957,611 @@ pub(crate) fn msgtojson(msg: Message) -> String {
     })
 }
 
+// The handlers below are shared by the WebSocket protocol and the REST API (see
+// [`crate::api`]). Each answers with the message to send back, and the ones that log in also
+// hand back the session they started.
+
+/// Create an account, and unless it waits for approval, log it in.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn handle_register(
+    email: String,
+    username: String,
+    password: String,
+    invite_code: Option<String>,
+    registration_mode: RegistrationMode,
+    db: &DbConn,
+    ev_log: &EventLogger,
+    origin: &SessionOrigin,
+) -> (ServerMessage, Option<(SessionReference, User)>) {
+    incoming_elog!(
+        ev_log,
+        "Register request: {} {}",
+        email.clone().color_orange(),
+        username.clone().color_bright_cyan()
+    );
+    match User::create_user(
+        email.clone(),
+        username.clone(),
+        password,
+        registration_mode,
+        invite_code,
+        db,
+    )
+    .await
+    {
+        Ok(user) if registration_mode == RegistrationMode::Approval => {
+            info_elog!(
+                ev_log,
+                "User created, waiting for approval: {}",
+                user.username.clone().color_bright_cyan()
+            );
+            (ServerMessage::RegisterPendingApproval, None)
+        }
+        Ok(user) => {
+            info_elog!(
+                ev_log,
+                "User created: {}",
+                user.clone().username.color_bright_cyan()
+            );
+            match User::create_session(user, db, ev_log.clone(), origin).await {
+                Ok((session_reference, user)) => {
+                    incoming_elog!(
+                        ev_log,
+                        "User {} authenticated.",
+                        user.clone().username.color_bright_cyan()
+                    );
+                    (
+                        ServerMessage::AuthSuccess {
+                            token: session_reference.token.clone(),
+                            username: user.username.clone(),
+                        },
+                        Some((session_reference, user)),
+                    )
+                }
+                Err(e) => {
+                    match e {
+                        LuminaError::DbError(LuminaDbError::Postgres(e)) => {
+                            error_elog!(ev_log, "While creating session token: {:?}", e)
+                        }
+                        LuminaError::Bb8RunErrorPg(e) => {
+                            warn_elog!(ev_log, "There was an error creating session token: {}", e)
+                        }
+                        _ => {}
+                    }
+                    // I would return a more specific error message
+                    // to the client here, but if the server knows the
+                    // error, the client should know the error twice as
+                    // well.
+                    (ServerMessage::AuthFailure, None)
+                }
+            }
+        }
+        Err(e) => {
+            match &e {
+                LuminaError::RegisterUsernameInUse => {
+                    registration_error_elog!(
+                        ev_log,
+                        "User {} already exists",
+                        username.clone().color_bright_cyan()
+                    );
+                }
+                LuminaError::RegisterEmailNotValid => {
+                    registration_error_elog!(
+                        ev_log,
+                        "Email {} is not valid",
+                        email.clone().color_bright_cyan()
+                    );
+                }
+                LuminaError::RegisterUsernameInvalid(why) => {
+                    registration_error_elog!(
+                        ev_log,
+                        "Username '{}' is not valid: {}",
+                        username.clone().color_bright_cyan(),
+                        why
+                    );
+                }
+                LuminaError::RegisterPasswordNotValid(why) => {
+                    registration_error_elog!(ev_log, "Password is not valid: {}", why);
+                }
+                other => {
+                    registration_error_elog!(ev_log, "Error creating user: {:?}", other);
+                }
+            }
+            (
+                ServerMessage::RegisterFailure {
+                    error: RegisterError::from(&e),
+                },
+                None,
+            )
+        }
+    }
+}
+
+/// Check a password, and unless the account has a second factor, log in.
+pub(crate) async fn handle_login(
+    email_username: String,
+    password: String,
+    auth_limiter: &AuthRateLimiter,
+    db: &DbConn,
+    ev_log: &EventLogger,
+    origin: &SessionOrigin,
+) -> (ServerMessage, Option<(SessionReference, User)>) {
+    // Quick pre-check: if the limiter says this IP is blocked, avoid DB work.
+    if !auth_limiter.allow_ip(origin.ip).await {
+        authentication_error_elog!(
+            ev_log,
+            "Rate-limited authentication attempt from IP: {:?}",
+            origin.ip
+        );
+        return (ServerMessage::AuthFailure, None);
+    }
+    match User::authenticate(email_username.clone(), password, db, ev_log.clone(), origin).await {
+        Ok(AuthenticationOutcome::SecondFactorRequired { challenge, user }) => {
+            incoming_elog!(
+                ev_log,
+                "User {} passed the password check, waiting for a second factor.",
+                user.username.clone().color_bright_cyan()
+            );
+            (ServerMessage::SecondFactorRequired { challenge }, None)
+        }
+        Ok(AuthenticationOutcome::Authenticated(session_reference, user)) => {
+            logged_in(session_reference, user, ev_log).await
+        }
+        Err(LuminaError::AuthenticationWrongPassword) => {
+            authentication_error_elog!(
+                ev_log,
+                "User {} {} authenticated: Incorrect credentials",
+                email_username.color_bright_cyan(),
+                "not".color_red()
+            );
+            (ServerMessage::AuthFailure, None)
+        }
+        Err(LuminaError::AccountLocked(account_state)) => {
+            authentication_error_elog!(
+                ev_log,
+                "User {} {} authenticated: Account state {:?}",
+                email_username.color_bright_cyan(),
+                "not".color_red(),
+                account_state
+            );
+            (
+                ServerMessage::AccountLocked {
+                    account_state: *account_state,
+                },
+                None,
+            )
+        }
+        Err(e) => {
+            authentication_error_elog!(
+                ev_log,
+                "User {} {} authenticated: {:?}",
+                email_username.color_bright_cyan(),
+                "not".color_red(),
+                e
+            );
+            (ServerMessage::AuthFailure, None)
+        }
+    }
+}
+
+/// Answer a second factor challenge handed out by [`handle_login`], logging in if it is right.
+pub(crate) async fn handle_second_factor(
+    challenge: String,
+    code: String,
+    auth_limiter: &AuthRateLimiter,
+    db: &DbConn,
+    ev_log: &EventLogger,
+    origin: &SessionOrigin,
+) -> (ServerMessage, Option<(SessionReference, User)>) {
+    if !auth_limiter.allow_ip(origin.ip).await {
+        authentication_error_elog!(
+            ev_log,
+            "Rate-limited second factor attempt from IP: {:?}",
+            origin.ip
+        );
+        return (ServerMessage::AuthFailure, None);
+    }
+    match User::complete_second_factor(challenge, code, db, ev_log.clone(), origin).await {
+        Ok((session_reference, user)) => logged_in(session_reference, user, ev_log).await,
+        Err(LuminaError::AccountLocked(account_state)) => {
+            authentication_error_elog!(
+                ev_log,
+                "Second factor accepted, but {} authenticated: Account state {:?}",
+                "not".color_red(),
+                account_state
+            );
+            (
+                ServerMessage::AccountLocked {
+                    account_state: *account_state,
+                },
+                None,
+            )
+        }
+        Err(e) => {
+            authentication_error_elog!(
+                ev_log,
+                "Second factor {} accepted: {:?}",
+                "not".color_red(),
+                e
+            );
+            (ServerMessage::AuthFailure, None)
+        }
+    }
+}
+
+async fn logged_in(
+    session_reference: SessionReference,
+    user: User,
+    ev_log: &EventLogger,
+) -> (ServerMessage, Option<(SessionReference, User)>) {
+    incoming_elog!(
+        ev_log,
+        "User {} authenticated to session with id {}.\n{}",
+        user.username.clone().color_bright_cyan(),
+        session_reference.session_id.to_string().color_pink(),
+        format!("(User id: {})", user.id).style_dim()
+    );
+    (
+        ServerMessage::AuthSuccess {
+            token: session_reference.token.clone(),
+            username: user.username.clone(),
+        },
+        Some((session_reference, user)),
+    )
+}
+
+/// The logged-in user's own information.
+pub(crate) async fn handle_own_user_information(
+    user: &User,
+    db: &DbConn,
+    ev_log: &EventLogger,
+) -> ServerMessage {
+    let deletion_scheduled_for = match user.deletion_scheduled_for(db).await {
+        Ok(at) => at,
+        Err(e) => {
+            error_elog!(ev_log, "While looking up account deletion: {:?}", e);
+            None
+        }
+    };
+    ServerMessage::OwnUserInformationResponse {
+        username: user.username.clone(),
+        email: user.email.clone(),
+        // Provide a compile-time included SVG placeholder avatar when none is available.
+        // The SVG file is included as bytes and base64-encoded here.
+        avatar: Some((
+            "image/svg+xml".to_string(),
+            // Encode the included SVG bytes as base64 at compile time.
+            STANDARD.encode(include_bytes!("../../assets/svgs/dummy_user_120px.svg")),
+        )),
+        uuid: user.id.to_string(),
+        //TODO: Fetch actual unread notification count
+        //Based on how many notifications are younger than last time user checked notifications. (WsMessage is sent when user opens notifications)
+        unread_notifications: 11,
+        deletion_scheduled_for,
+        role: user.role,
+    }
+}
+
+/// A page of a timeline, as seen by `user`.
+pub(crate) async fn handle_timeline(
+    name: String,
+    page: Option<usize>,
+    user: User,
+    db: &DbConn,
+    ev_log: &EventLogger,
+) -> ServerMessage {
+    // Fetch post IDs for the requested timeline
+    match fetch_timeline_post_ids_by_timeline_name(ev_log.clone(), db, &name, user, page).await {
+        Ok((tlid, post_ids, total_count, has_more)) => ServerMessage::TimelineResponse {
+            post_ids,
+            timeline_name: name,
+            timeline_id: tlid,
+            total_count,
+            page: page.unwrap_or(0),
+            has_more,
+        },
+        Err(e) => {
+            error_elog!(ev_log, "Error fetching timeline: {:?}", e);
+            internal_error()
+        }
+    }
+}
+
+/// A single post, as seen by `viewer`.
+pub(crate) async fn handle_post_view(
+    post_id: Uuid,
+    viewer: Option<&User>,
+    db: &DbConn,
+    ev_log: &EventLogger,
+) -> ServerMessage {
+    info_elog!(ev_log, "Post was requested: {}", post_id);
+    match posts::view_post(post_id, viewer.map(|user| user.id), db).await {
+        Ok(Some(PostView::Text {
+            post_id,
+            source_instance,
+            content,
+            sensitive,
+        })) => ServerMessage::TextPostDataSent {
+            post_id,
+            source_instance,
+            content,
+            sensitive,
+        },
+        Ok(Some(PostView::Media {
+            post_id,
+            source_instance,
+            description,
+            medias,
+            sensitive,
+        })) => ServerMessage::MediaPostDataSent {
+            post_id,
+            source_instance,
+            description,
+            medias,
+            sensitive,
+        },
+        Ok(Some(PostView::Article {
+            post_id,
+            source_instance,
+            title,
+            content,
+            timestamp,
+            author_id,
+            sensitive,
+        })) => ServerMessage::ArticlePostDataSent {
+            post_id,
+            source_instance,
+            title,
+            content,
+            timestamp,
+            author_id: author_id.to_string(),
+            sensitive,
+        },
+        Ok(None) => ServerMessage::PostNotFound { post_id },
+        Err(e) => {
+            error_elog!(ev_log, "While looking up post {}: {:?}", post_id, e);
+            internal_error()
+        }
+    }
+}
+
+/// Write a text post for `user`.
+pub(crate) async fn handle_text_post_create(
+    content: String,
+    user: &User,
+    limiter: &GeneralRateLimiter,
+    client_ip: Option<IpAddr>,
+    db: &DbConn,
+    ev_log: &EventLogger,
+) -> ServerMessage {
+    if !limiter.allow_ip(client_ip).await {
+        return ServerMessage::PostCreateResponse {
+            ok: false,
+            why: "Too many posts, try again later.".to_string(),
+            post_id: None,
+            held: false,
+            sensitive: false,
+        };
+    }
+    match posts::create_text_post(user, content, db, ev_log.clone()).await {
+        Ok(created) => {
+            info_elog!(
+                ev_log,
+                "User {} posted {}{}.",
+                user.username.clone().color_bright_cyan(),
+                created.post_id,
+                if created.held {
+                    ", held for review"
+                } else {
+                    ""
+                }
+            );
+            ServerMessage::PostCreateResponse {
+                ok: true,
+                why: String::new(),
+                post_id: Some(created.post_id),
+                held: created.held,
+                sensitive: created.sensitive,
+            }
+        }
+        Err(e) => {
+            warn_elog!(
+                ev_log,
+                "User {} could not post: {:?}",
+                user.username.clone().color_bright_cyan(),
+                e
+            );
+            ServerMessage::PostCreateResponse {
+                ok: false,
+                why: e.to_string(),
+                post_id: None,
+                held: false,
+                sensitive: false,
+            }
+        }
+    }
+}
+
 pub(crate) struct SessionData {
+    /// Tells this connection apart from the other connections of the same user.
+    pub(crate) connection_id: Uuid,
     pub(crate) client_type: Option<ClientType>,
+    /// The protocol version agreed on in the `Introduction`.
+    pub(crate) protocol_version: u32,
+    /// The optional features agreed on in the `Introduction`.
+    pub(crate) features: Vec<String>,
+    /// The encoding of the messages sent to this client.
+    pub(crate) encoding: Encoding,
     pub(crate) user: Option<User>,
+    /// The session this connection is authenticated with, once it is.
+    pub(crate) session_id: Option<Uuid>,
+}
+
+impl SessionData {
+    /// A connection that hasn't introduced itself yet.
+    pub(crate) fn new() -> Self {
+        SessionData {
+            connection_id: Uuid::new_v4(),
+            client_type: None,
+            protocol_version: MIN_PROTOCOL_VERSION,
+            features: vec![],
+            encoding: Encoding::Json,
+            user: None,
+            session_id: None,
+        }
+    }
+}
+
+/// How the WebSocket connections are kept, set through `LUMINA_WS_*`.
+///
+/// The server pings every connection every `ping_interval`, and closes the ones it hasn't heard
+/// anything from, pongs included, for `idle_timeout`. Frames and messages larger than
+/// `max_frame_size` end the connection.
+///
+/// There's no per-message compression: `rocket_ws` is built on tungstenite 0.21, which doesn't
+/// implement permessage-deflate, so it is never offered in the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SocketSettings {
+    pub(crate) ping_interval: Duration,
+    pub(crate) idle_timeout: Duration,
+    pub(crate) max_frame_size: usize,
+}
+
+/// The largest export archive an `AccountImportRequest` can carry with the default frame size.
+/// The archive is the largest thing a client sends, everything else fits in a few KiB.
+pub(crate) const DEFAULT_IMPORT_ARCHIVE_SIZE: usize = 6 * 1024 * 1024;
+
+impl Default for SocketSettings {
+    fn default() -> Self {
+        SocketSettings {
+            ping_interval: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(90),
+            // Base64 makes the archive a third larger, and leave some room for the rest of the
+            // message.
+            max_frame_size: DEFAULT_IMPORT_ARCHIVE_SIZE / 3 * 4 + 64 * 1024,
+        }
+    }
+}
+
+impl SocketSettings {
+    pub(crate) fn from_env() -> Result<Self, LuminaError> {
+        let defaults = SocketSettings::default();
+        let ping_interval = std::env::var("LUMINA_WS_PING_INTERVAL_SECS")
+            .map(|s| s.parse::<u64>().map(Duration::from_secs))
+            .unwrap_or(Ok(defaults.ping_interval))
+            .map_err(|_| LuminaError::ConfInvalid(crate::EnvVar::LUMINA_WS_PING_INTERVAL_SECS))?;
+        let idle_timeout = std::env::var("LUMINA_WS_IDLE_TIMEOUT_SECS")
+            .map(|s| s.parse::<u64>().map(Duration::from_secs))
+            .unwrap_or(Ok(defaults.idle_timeout))
+            .map_err(|_| LuminaError::ConfInvalid(crate::EnvVar::LUMINA_WS_IDLE_TIMEOUT_SECS))?;
+        let max_frame_size = std::env::var("LUMINA_WS_MAX_FRAME_KIB")
+            .map(|s| s.parse::<usize>().map(|kib| kib.saturating_mul(1024)))
+            .unwrap_or(Ok(defaults.max_frame_size))
+            .map_err(|_| LuminaError::ConfInvalid(crate::EnvVar::LUMINA_WS_MAX_FRAME_KIB))?;
+        // A client can only answer a ping it was sent, so pings have to come more often than
+        // the timeout.
+        if ping_interval.is_zero() || ping_interval >= idle_timeout {
+            return Err(LuminaError::ConfInvalid(
+                crate::EnvVar::LUMINA_WS_PING_INTERVAL_SECS,
+            ));
+        }
+        if max_frame_size == 0 {
+            return Err(LuminaError::ConfInvalid(
+                crate::EnvVar::LUMINA_WS_MAX_FRAME_KIB,
+            ));
+        }
+        Ok(SocketSettings {
+            ping_interval,
+            idle_timeout,
+            max_frame_size,
+        })
+    }
+}
+
+/// The oldest protocol version this server still speaks.
+pub(crate) const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// The newest protocol version this server speaks. Version 2 clients understand the replies
+/// that version 1 clients get as `AuthFailure`, see [`for_protocol_version`].
+pub(crate) const MAX_PROTOCOL_VERSION: u32 = 2;
+
+/// Optional parts of the protocol this server supports. Clients can ask to use some of them in
+/// their `Introduction`, and should not send messages for ones missing here.
+pub(crate) const CAPABILITIES: &[&str] = &[
+    "two_factor",
+    "sessions",
+    "data_export",
+    "account_migration",
+    "relationships",
+    "reports",
+    "moderation",
+    "filter_rules",
+    "text_posts",
+    "rest_api_v1",
+    "msgpack",
+    "presence",
+];
+
+/// A reply as a client speaking `protocol_version` understands it. Version 1 clients only know
+/// `AuthFailure`, both for a registration that failed and for a message sent without a session.
+pub(crate) fn for_protocol_version(msg: ServerMessage, protocol_version: u32) -> ServerMessage {
+    match msg {
+        ServerMessage::RegisterFailure { .. } | ServerMessage::Unauthenticated
+            if protocol_version < 2 =>
+        {
+            ServerMessage::AuthFailure
+        }
+        msg => msg,
+    }
+}
+
+/// What a client and this server agreed on in the `Introduction`.
+#[derive(Debug)]
+pub(crate) struct Negotiated {
+    pub(crate) client_type: ClientType,
+    pub(crate) protocol_version: u32,
+    /// The features asked for that this server has, in the order they were asked for.
+    pub(crate) features: Vec<String>,
+    pub(crate) encoding: Encoding,
+}
+
+/// Agree on how to talk to a client, or explain why we can't. The explanation is sent as the
+/// reason of a close frame, so it stays short and never repeats what the client sent.
+pub(crate) fn negotiate(
+    client_kind: &str,
+    protocol_version: Option<u32>,
+    features: &[String],
+    encoding: Option<&str>,
+) -> Result<Negotiated, String> {
+    let client_type = match client_kind {
+        "web" => ClientType::Web,
+        "native" | "mobile" => return Err("Native clients are not supported yet".to_string()),
+        _ => return Err("Unknown client kind".to_string()),
+    };
+    let protocol_version = protocol_version.unwrap_or(1);
+    if !(MIN_PROTOCOL_VERSION..=MAX_PROTOCOL_VERSION).contains(&protocol_version) {
+        return Err(format!(
+            "Protocol version {protocol_version} is not supported, this server speaks {MIN_PROTOCOL_VERSION} to {MAX_PROTOCOL_VERSION}"
+        ));
+    }
+    let encoding = match encoding {
+        None | Some("json") => Encoding::Json,
+        Some("msgpack") => Encoding::Msgpack,
+        Some(_) => return Err("Encoding is not supported".to_string()),
+    };
+    Ok(Negotiated {
+        client_type,
+        protocol_version,
+        features: features
+            .iter()
+            .filter(|feature| CAPABILITIES.contains(&feature.as_str()))
+            .cloned()
+            .collect(),
+        encoding,
+    })
 }
 
+#[derive(Debug, PartialEq, Eq)]
 pub enum ClientType {
     Web,
     // NativeApp will one day mean a native application, like a mobile app.
@@ -0,0 +1,407 @@
+//! Lumina > Server > Connections
+//!
+//! Who is connected. Every WebSocket connection of a logged-in user is kept in a registry, by
+//! user and session, so that the server can push messages to all devices of a user and close
+//! the connections of sessions that ended.
+//!
+//! The registry only knows the connections to this node, so it is mirrored to Redis for setups
+//! with more than one: presence is kept in Redis, and pushes and disconnects are published on a
+//! channel that every node listens on. The same listener picks up changes to the filter rules,
+//! see [`crate::filters`].
+
+/*
+ *     Lumina/Peonies
+ *     Copyright (C) 2018-2026 MLC 'Strawmelonjuice'  Bloeiman and contributors.
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::client_communication::ServerMessage;
+use crate::database::DbConn;
+use crate::errors::LuminaError;
+use crate::filters;
+use crate::helpers::events::EventLogger;
+use crate::{error_elog, info_elog};
+use bb8::Pool;
+use bb8_redis::RedisConnectionManager;
+use rocket::futures::StreamExt;
+use std::collections::HashMap;
+use std::sync::{LazyLock, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// The Redis channel pushes and disconnects are published on.
+const CHANNEL: &str = "connections";
+
+fn presence_key(user_id: Uuid) -> String {
+    format!("presence:{}", user_id)
+}
+
+fn last_seen_key(user_id: Uuid) -> String {
+    format!("last_seen:{}", user_id)
+}
+
+fn now() -> i64 {
+    time::OffsetDateTime::now_utc().unix_timestamp()
+}
+
+/// Something for a connection to do, sent to it from outside.
+#[derive(Debug, Clone)]
+pub(crate) enum Push {
+    /// A message to send to the client, if it is still logged in as `user_id`.
+    Message {
+        user_id: Uuid,
+        message: ServerMessage,
+    },
+    /// Log the client out and close the connection. Only for connections still logged in as
+    /// `user_id`, and with a `session_id`, only for those on that session.
+    Disconnect {
+        user_id: Uuid,
+        session_id: Option<Uuid>,
+        reason: String,
+    },
+}
+
+/// Where a connection takes its [`Push`]es from.
+pub(crate) type Pusher = mpsc::UnboundedSender<Push>;
+
+struct Connection {
+    id: Uuid,
+    session_id: Uuid,
+    pusher: Pusher,
+}
+
+/// The connections of logged-in users to this node.
+#[derive(Default)]
+pub(crate) struct Registry {
+    users: RwLock<HashMap<Uuid, Vec<Connection>>>,
+}
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::default);
+
+/// Tells the events this node publishes apart from those of other nodes.
+static NODE_ID: LazyLock<Uuid> = LazyLock::new(Uuid::new_v4);
+
+/// The registry of this node.
+pub(crate) fn registry() -> &'static Registry {
+    &REGISTRY
+}
+
+impl Registry {
+    /// Add a connection that just logged in.
+    pub(crate) fn register(
+        &self,
+        user_id: Uuid,
+        session_id: Uuid,
+        connection_id: Uuid,
+        pusher: Pusher,
+    ) {
+        self.users
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entry(user_id)
+            .or_default()
+            .push(Connection {
+                id: connection_id,
+                session_id,
+                pusher,
+            });
+    }
+
+    /// Remove a connection that logged out or closed.
+    pub(crate) fn unregister(&self, user_id: Uuid, connection_id: Uuid) {
+        let mut users = self
+            .users
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(connections) = users.get_mut(&user_id) {
+            connections.retain(|connection| connection.id != connection_id);
+            if connections.is_empty() {
+                users.remove(&user_id);
+            }
+        }
+    }
+
+    /// How many connections the user has to this node.
+    pub(crate) fn connection_count(&self, user_id: Uuid) -> usize {
+        self.users
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(&user_id)
+            .map_or(0, Vec::len)
+    }
+
+    /// Send `push` to the connections of a user, or with a `session_id`, to those on that
+    /// session. Returns to how many.
+    fn send(
+        &self,
+        user_id: Uuid,
+        session_id: Option<Uuid>,
+        except: Option<Uuid>,
+        push: &Push,
+    ) -> usize {
+        let users = self
+            .users
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        users.get(&user_id).map_or(0, |connections| {
+            connections
+                .iter()
+                .filter(|connection| session_id.is_none_or(|id| id == connection.session_id))
+                .filter(|connection| Some(connection.id) != except)
+                // A connection that closed and hasn't unregistered yet can't be sent to anymore.
+                .filter(|connection| connection.pusher.send(push.clone()).is_ok())
+                .count()
+        })
+    }
+
+    fn apply(&self, event: Event) -> usize {
+        match event.kind {
+            EventKind::Push {
+                message,
+                except_connection,
+            } => self.send(
+                event.user_id,
+                None,
+                except_connection,
+                &Push::Message {
+                    user_id: event.user_id,
+                    message,
+                },
+            ),
+            EventKind::Disconnect { session_id, reason } => self.send(
+                event.user_id,
+                session_id,
+                None,
+                &Push::Disconnect {
+                    user_id: event.user_id,
+                    session_id,
+                    reason,
+                },
+            ),
+        }
+    }
+}
+
+/// A push or disconnect, as published to the other nodes.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct Event {
+    node: Uuid,
+    user_id: Uuid,
+    #[serde(flatten)]
+    kind: EventKind,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum EventKind {
+    Push {
+        message: ServerMessage,
+        except_connection: Option<Uuid>,
+    },
+    Disconnect {
+        session_id: Option<Uuid>,
+        reason: String,
+    },
+}
+
+/// Apply an event here, and publish it for the other nodes. Publishing is best effort: the
+/// event has happened on this node either way, and callers have no way to undo it.
+async fn broadcast(redis_pool: &Pool<RedisConnectionManager>, user_id: Uuid, kind: EventKind) {
+    let event = Event {
+        node: *NODE_ID,
+        user_id,
+        kind,
+    };
+    let Ok(payload) = serde_json::to_string(&event) else {
+        return;
+    };
+    REGISTRY.apply(event);
+    if let Ok(mut redis_conn) = redis_pool.get().await {
+        let _: Result<(), _> = redis::cmd("PUBLISH")
+            .arg(CHANNEL)
+            .arg(payload)
+            .query_async(&mut *redis_conn)
+            .await;
+    }
+}
+
+/// Send a message to every device the user is connected on, except the connection
+/// `except_connection`, usually the one that caused it.
+pub(crate) async fn push(
+    redis_pool: &Pool<RedisConnectionManager>,
+    user_id: Uuid,
+    message: ServerMessage,
+    except_connection: Option<Uuid>,
+) {
+    broadcast(
+        redis_pool,
+        user_id,
+        EventKind::Push {
+            message,
+            except_connection,
+        },
+    )
+    .await;
+}
+
+/// Close the connections on a session that ended, or without a `session_id`, all connections
+/// of the user. Called wherever sessions are deleted.
+pub(crate) async fn disconnect(
+    redis_pool: &Pool<RedisConnectionManager>,
+    user_id: Uuid,
+    session_id: Option<Uuid>,
+    reason: &str,
+) {
+    broadcast(
+        redis_pool,
+        user_id,
+        EventKind::Disconnect {
+            session_id,
+            reason: reason.to_string(),
+        },
+    )
+    .await;
+}
+
+/// Apply the events the other nodes publish, and reload the filter rules when they change, for
+/// as long as the server runs. Subscribing takes a connection of its own, so this doesn't use the
+/// pool.
+pub(crate) async fn listen(redis_url: String, db: DbConn, ev_log: EventLogger) {
+    loop {
+        if let Err(e) = listen_until_dropped(&redis_url, &db, &ev_log).await {
+            error_elog!(ev_log, "While listening for connection events: {:?}", e);
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        info_elog!(ev_log, "Listening for connection events again.");
+    }
+}
+
+async fn listen_until_dropped(
+    redis_url: &str,
+    db: &DbConn,
+    ev_log: &EventLogger,
+) -> Result<(), LuminaError> {
+    let client = redis::Client::open(redis_url)?;
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(&[CHANNEL, filters::CHANNEL]).await?;
+    // The rules may have changed while nobody was listening.
+    if let Err(e) = filters::refresh(db).await {
+        error_elog!(ev_log, "While reloading the filter rules: {:?}", e);
+    }
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        if message.get_channel_name() == filters::CHANNEL {
+            if let Err(e) = filters::refresh(db).await {
+                error_elog!(ev_log, "While reloading the filter rules: {:?}", e);
+            }
+            continue;
+        }
+        let Ok(payload) = message.get_payload::<String>() else {
+            continue;
+        };
+        if let Ok(event) = serde_json::from_str::<Event>(&payload)
+            && event.node != *NODE_ID
+        {
+            REGISTRY.apply(event);
+        }
+    }
+    Ok(())
+}
+
+/// Whether a user is connected, and when they last were.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Presence {
+    pub(crate) online: bool,
+    /// Unix timestamp of when the user was last seen connected, if ever.
+    pub(crate) last_seen: Option<i64>,
+}
+
+/// Mark a connection as alive for another `ttl`. Called when a user logs in on a connection, and
+/// on every heartbeat after, so the connections of a node that went down drop out of presence
+/// by themselves.
+pub(crate) async fn touch(
+    redis_pool: &Pool<RedisConnectionManager>,
+    user_id: Uuid,
+    connection_id: Uuid,
+    ttl: Duration,
+) -> Result<(), LuminaError> {
+    let mut redis_conn = redis_pool.get().await?;
+    let now = now();
+    let ttl = ttl.as_secs().max(1) as i64;
+    let _: () = redis::pipe()
+        .cmd("ZADD")
+        .arg(presence_key(user_id))
+        .arg(now + ttl)
+        .arg(connection_id.to_string())
+        .ignore()
+        .cmd("EXPIRE")
+        .arg(presence_key(user_id))
+        .arg(ttl)
+        .ignore()
+        .cmd("SET")
+        .arg(last_seen_key(user_id))
+        .arg(now)
+        .ignore()
+        .query_async(&mut *redis_conn)
+        .await?;
+    Ok(())
+}
+
+/// Take a connection out of presence, when it logs out or closes.
+pub(crate) async fn leave(
+    redis_pool: &Pool<RedisConnectionManager>,
+    user_id: Uuid,
+    connection_id: Uuid,
+) -> Result<(), LuminaError> {
+    let mut redis_conn = redis_pool.get().await?;
+    let _: () = redis::pipe()
+        .cmd("ZREM")
+        .arg(presence_key(user_id))
+        .arg(connection_id.to_string())
+        .ignore()
+        .cmd("SET")
+        .arg(last_seen_key(user_id))
+        .arg(now())
+        .ignore()
+        .query_async(&mut *redis_conn)
+        .await?;
+    Ok(())
+}
+
+/// Whether the user is connected to any node, and when they last were.
+pub(crate) async fn presence(
+    redis_pool: &Pool<RedisConnectionManager>,
+    user_id: Uuid,
+) -> Result<Presence, LuminaError> {
+    let mut redis_conn = redis_pool.get().await?;
+    // Connections whose time ran out are on a node that stopped touching them.
+    let (live, last_seen): (u64, Option<i64>) = redis::pipe()
+        .cmd("ZREMRANGEBYSCORE")
+        .arg(presence_key(user_id))
+        .arg("-inf")
+        .arg(now())
+        .ignore()
+        .cmd("ZCARD")
+        .arg(presence_key(user_id))
+        .cmd("GET")
+        .arg(last_seen_key(user_id))
+        .query_async(&mut *redis_conn)
+        .await?;
+    Ok(Presence {
+        online: live > 0 || REGISTRY.connection_count(user_id) > 0,
+        last_seen,
+    })
+}
@@ -20,17 +20,23 @@
  *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 use crate::EnvVar::*;
+use crate::account_data;
+use crate::connections;
 use crate::errors::LuminaError::{self};
 use crate::helpers::events::EventLogger;
+use crate::helpers::tokens;
 use crate::postgres;
 use crate::timeline;
-use crate::{info_elog, success_elog, warn_elog};
+use crate::user;
+use crate::username_policy;
+use crate::{error_elog, info_elog, success_elog, warn_elog};
 use bb8::Pool;
 use bb8_postgres::PostgresConnectionManager;
 use bb8_redis::RedisConnectionManager;
 use cynthia_con::{CynthiaColors, CynthiaStyles};
 use std::time::Duration;
 use tokio_postgres::NoTls;
+use uuid::Uuid;
 
 pub(crate) async fn setup() -> Result<PgConn, LuminaError> {
     let ev_log = EventLogger::new(&None);
@@ -101,6 +107,12 @@ pub(crate) async fn setup() -> Result<PgConn, LuminaError> {
                     pg_config.host("localhost");
                 }
             };
+            if std::env::var("LUMINA_DB_SALT").is_err() {
+                warn_elog!(
+                    ev_log,
+                    "No salt provided under environment variable 'LUMINA_DB_SALT'. Using the default, which makes stored tokens easier to attack."
+                );
+            }
             match std::env::var("LUMINA_POSTGRES_PASSWORD") {
                 Ok(val) => {
                     pg_config.password(&val);
@@ -126,17 +138,19 @@ pub(crate) async fn setup() -> Result<PgConn, LuminaError> {
         let pg_manager = PostgresConnectionManager::new(pg_config.clone(), NoTls);
         let pg_pool = Pool::builder().build(pg_manager).await?;
         {
-            let pg_conn = pg_pool.get().await?;
+            let mut pg_conn = pg_pool.get().await?;
             pg_conn
                 .batch_execute(include_str!("../../SQL/create_pg.sql"))
                 .await?;
+            migrate_session_tokens(&mut pg_conn, &ev_log).await?;
+            fill_username_skeletons(&pg_conn, &ev_log).await?;
             // Populate bloom filters
             let mut redis_conn = redis_pool.get().await?;
             let email_key = "bloom:email";
             let username_key = "bloom:username";
 
             let rows = pg_conn
-                .query("SELECT email, username FROM users", &[])
+                .query("SELECT email, username_skeleton FROM users", &[])
                 .await?;
             for row in rows {
                 let email: String = row.get(0);
@@ -154,6 +168,11 @@ pub(crate) async fn setup() -> Result<PgConn, LuminaError> {
             }
             info_elog!(ev_log, "Bloom filters populated from PostgreSQL.",);
         };
+        tokio::spawn(connections::listen(
+            redis_url,
+            DbConn::PgsqlConnection(pg_pool.clone(), redis_pool.clone()),
+            ev_log.clone(),
+        ));
         let pg_pool_clone = pg_pool.clone();
         let redis_pool_clone = redis_pool.clone();
         tokio::spawn(async move {
@@ -170,6 +189,73 @@ pub(crate) async fn setup() -> Result<PgConn, LuminaError> {
     }
 }
 
+/// Replaces the raw `session_key` column of databases from before token hashing with
+/// `token_hash`, hashing the tokens of existing sessions so they can still be revived.
+async fn migrate_session_tokens(
+    client: &mut bb8::PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+    ev_log: &EventLogger,
+) -> Result<(), LuminaError> {
+    let has_raw_tokens = client
+        .query_opt(
+            "SELECT 1 FROM information_schema.columns WHERE table_schema = current_schema() AND table_name = 'sessions' AND column_name = 'session_key'",
+            &[],
+        )
+        .await?
+        .is_some();
+    if !has_raw_tokens {
+        return Ok(());
+    }
+    let transaction = client.transaction().await?;
+    let rows = transaction
+        .query("SELECT id, session_key FROM sessions", &[])
+        .await?;
+    for row in &rows {
+        let id: Uuid = row.get(0);
+        let session_key: String = row.get(1);
+        transaction
+            .execute(
+                "UPDATE sessions SET token_hash = $2 WHERE id = $1",
+                &[&id, &tokens::hash_token(&session_key)],
+            )
+            .await?;
+    }
+    transaction
+        .batch_execute(
+            "ALTER TABLE sessions DROP COLUMN session_key; ALTER TABLE sessions ALTER COLUMN token_hash SET NOT NULL;",
+        )
+        .await?;
+    transaction.commit().await?;
+    info_elog!(ev_log, "Migrated {} sessions to hashed tokens.", rows.len());
+    Ok(())
+}
+
+/// Computes the username skeletons of accounts from before the username policy.
+async fn fill_username_skeletons(
+    client: &bb8::PooledConnection<'_, PostgresConnectionManager<NoTls>>,
+    ev_log: &EventLogger,
+) -> Result<(), LuminaError> {
+    let rows = client
+        .query(
+            "SELECT id, username FROM users WHERE username_skeleton IS NULL",
+            &[],
+        )
+        .await?;
+    for row in &rows {
+        let id: Uuid = row.get(0);
+        let username: String = row.get(1);
+        client
+            .execute(
+                "UPDATE users SET username_skeleton = $2 WHERE id = $1",
+                &[&id, &username_policy::skeleton(&username)],
+            )
+            .await?;
+    }
+    if !rows.is_empty() {
+        info_elog!(ev_log, "Filled in {} username skeletons.", rows.len());
+    }
+    Ok(())
+}
+
 /// This enum contains the postgres and redis connection and pool respectively. It used to have more variants before, and maybe it will once again.
 #[derive()]
 pub enum DbConn {
@@ -262,8 +348,9 @@ impl Clone for PgConn {
 // This function will be used to maintain the database, such as deleting old sessions
 // and managing timeline caches
 pub async fn maintain(db: PgConn) {
+    let ev_log = EventLogger::new(&Some(db.clone()));
     let db = DbConn::from(db);
-    match db {
+    match &db {
         DbConn::PgsqlConnection(pg_pool, redis_pool) => {
             let mut session_interval = tokio::time::interval(std::time::Duration::from_secs(60));
             let mut cache_interval = tokio::time::interval(std::time::Duration::from_secs(300)); // 5 minutes
@@ -271,15 +358,36 @@ pub async fn maintain(db: PgConn) {
             loop {
                 tokio::select! {
                     _ = session_interval.tick() => {
-                        // Delete any sessions older than 20 days
+                        // Delete any sessions that have not been used for a while
                         if let Ok(client) = pg_pool.get().await {
                             let _ = client
                                 .execute(
-                                    "DELETE FROM sessions WHERE created_at < NOW() - INTERVAL '20 days'",
+                                    "DELETE FROM sessions WHERE last_used_at < NOW() - make_interval(days => $1)",
+                                    &[&user::SESSION_IDLE_EXPIRY_DAYS],
+                                )
+                                .await;
+                            // Expired verification and reset links can't be used anymore
+                            let _ = client
+                                .batch_execute(
+                                    "DELETE FROM email_verification_tokens WHERE expires_at < NOW(); DELETE FROM password_reset_tokens WHERE expires_at < NOW();",
+                                )
+                                .await;
+                            // Keep sent mail around for a week, for troubleshooting
+                            let _ = client
+                                .execute(
+                                    "DELETE FROM email_outbox WHERE sent_at < NOW() - INTERVAL '7 days'",
                                     &[],
                                 )
                                 .await;
                         }
+                        // Queued data exports, and accounts past their grace period
+                        if let Err(e) = account_data::build_pending_exports(&db, &ev_log).await {
+                            error_elog!(ev_log, "While building data exports: {:?}", e);
+                        }
+                        let _ = account_data::remove_expired_exports(&db).await;
+                        if let Err(e) = account_data::delete_due_accounts(&db, &ev_log).await {
+                            error_elog!(ev_log, "While deleting accounts: {:?}", e);
+                        }
                     }
                     _ = cache_interval.tick() => {
                         // Clean up expired timeline caches and manage cache invalidation
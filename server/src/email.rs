@@ -0,0 +1,262 @@
+//! Lumina > Server > Email
+//!
+//! Outgoing email for Lumina. Mail is never sent from a request handler directly: handlers
+//! queue messages in the `email_outbox` table, and a background worker hands them to an SMTP
+//! relay. In development a local catcher like MailHog can act as that relay.
+
+/*
+ *     Lumina/Peonies
+ *     Copyright (C) 2018-2026 MLC 'Strawmelonjuice'  Bloeiman and contributors.
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::EnvVar::*;
+use crate::database::{DbConn, PgConn};
+use crate::errors::LuminaError;
+use crate::helpers::events::EventLogger;
+use crate::postgres::GenericClient;
+use crate::{error_elog, info_elog, warn_elog};
+use cynthia_con::CynthiaColors;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// How often the outbox worker looks for unsent mail.
+const OUTBOX_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many messages the outbox worker claims per round.
+const OUTBOX_BATCH_SIZE: i64 = 20;
+
+/// After this many failed attempts, a message stays in the outbox but is no longer retried.
+const OUTBOX_MAX_ATTEMPTS: i32 = 5;
+
+/// How long a claimed message is left to the worker that claimed it, before another may retry it.
+const OUTBOX_CLAIM_MINUTES: i32 = 5;
+
+/// SMTP relay settings, read from the environment.
+#[derive(Debug, Clone)]
+pub(crate) struct MailerConfig {
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+    starttls: bool,
+    from: String,
+}
+
+impl MailerConfig {
+    /// Reads the SMTP settings from the `LUMINA_SMTP_*` environment variables.
+    pub(crate) fn from_env() -> Result<Self, LuminaError> {
+        let host = std::env::var("LUMINA_SMTP_HOST").unwrap_or(String::from("localhost"));
+        let port = std::env::var("LUMINA_SMTP_PORT")
+            .unwrap_or(String::from("1025"))
+            .parse::<u16>()
+            .map_err(|_| LuminaError::ConfInvalid(LUMINA_SMTP_PORT))?;
+        let credentials = match (
+            std::env::var("LUMINA_SMTP_USERNAME"),
+            std::env::var("LUMINA_SMTP_PASSWORD"),
+        ) {
+            (Ok(username), Ok(password)) => Some((username, password)),
+            _ => None,
+        };
+        let starttls = std::env::var("LUMINA_SMTP_STARTTLS")
+            .unwrap_or(String::from("false"))
+            .to_lowercase()
+            == "true";
+        let from = std::env::var("LUMINA_SMTP_FROM").unwrap_or(format!(
+            "Lumina <noreply@{}>",
+            std::env::var("LUMINA_SYNC_IID").unwrap_or(String::from("localhost"))
+        ));
+        from.parse::<Mailbox>()
+            .map_err(|_| LuminaError::ConfInvalid(LUMINA_SMTP_FROM))?;
+        Ok(MailerConfig {
+            host,
+            port,
+            credentials,
+            starttls,
+            from,
+        })
+    }
+
+    pub(crate) fn transport(
+        &self,
+    ) -> Result<AsyncSmtpTransport<Tokio1Executor>, lettre::transport::smtp::Error> {
+        let mut builder = if self.starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&self.host)?
+        } else {
+            // Plain SMTP, which is what local catchers like MailHog speak.
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.host)
+        };
+        builder = builder.port(self.port);
+        if let Some((username, password)) = &self.credentials {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        Ok(builder.build())
+    }
+
+    /// The configured sender. Already validated by [`MailerConfig::from_env`].
+    pub(crate) fn sender(&self) -> Result<Mailbox, lettre::address::AddressError> {
+        self.from.parse()
+    }
+}
+
+/// The scheme of links to this instance, set through `LUMINA_SERVER_HTTPS`.
+pub(crate) fn url_scheme() -> &'static str {
+    if std::env::var("LUMINA_SERVER_HTTPS")
+        .unwrap_or(String::from("false"))
+        .to_lowercase()
+        == "true"
+    {
+        "https"
+    } else {
+        "http"
+    }
+}
+
+/// The public URL this instance is reachable on, used to build links in outgoing mail.
+pub(crate) fn instance_base_url() -> String {
+    format!(
+        "{}://{}",
+        url_scheme(),
+        std::env::var("LUMINA_SYNC_IID").unwrap_or(String::from("localhost"))
+    )
+}
+
+/// Queue a plain-text email for delivery by the outbox worker.
+pub(crate) async fn enqueue(
+    db: &DbConn,
+    recipient: &str,
+    subject: &str,
+    body: &str,
+) -> Result<(), LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            enqueue_with(&*client, recipient, subject, body).await
+        }
+    }
+}
+
+/// Like [`enqueue`], but takes a client so the mail can be part of a transaction, and is only
+/// queued if that commits.
+pub(crate) async fn enqueue_with(
+    client: &impl GenericClient,
+    recipient: &str,
+    subject: &str,
+    body: &str,
+) -> Result<(), LuminaError> {
+    client
+        .execute(
+            "INSERT INTO email_outbox (recipient, subject, body) VALUES ($1, $2, $3)",
+            &[&recipient, &subject, &body],
+        )
+        .await?;
+    Ok(())
+}
+
+/// Drains the email outbox forever. Spawned once at startup.
+///
+/// Rows are claimed for [`OUTBOX_CLAIM_MINUTES`] with `FOR UPDATE SKIP LOCKED`, so several
+/// Lumina instances can share one outbox without sending the same message twice.
+pub(crate) async fn run_outbox_worker(db: PgConn, config: MailerConfig, ev_log: EventLogger) {
+    let transport = match config.transport() {
+        Ok(t) => t,
+        Err(e) => {
+            error_elog!(
+                ev_log,
+                "Could not set up SMTP transport to {}: {}",
+                config.host,
+                e
+            );
+            return;
+        }
+    };
+    let Ok(from) = config.sender() else {
+        return;
+    };
+    info_elog!(
+        ev_log,
+        "Mail worker sending through {}.",
+        format!("{}:{}", config.host, config.port).color_bright_cyan()
+    );
+    let mut interval = tokio::time::interval(OUTBOX_POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = drain_outbox_once(&db, &transport, &from, &ev_log).await {
+            warn_elog!(ev_log, "Mail worker round failed: {:?}", e);
+        }
+    }
+}
+
+/// Send one batch of queued mail. Returns once the batch has been attempted.
+pub(crate) async fn drain_outbox_once(
+    db: &PgConn,
+    transport: &AsyncSmtpTransport<Tokio1Executor>,
+    from: &Mailbox,
+    ev_log: &EventLogger,
+) -> Result<(), LuminaError> {
+    let client = db.postgres_pool.get().await?;
+    // Claiming is a statement of its own, so no transaction stays open while talking to the
+    // relay. A worker that dies mid-batch leaves its claims to run out.
+    let rows = client
+        .query(
+            "UPDATE email_outbox SET claimed_until = NOW() + make_interval(mins => $3) WHERE id IN (SELECT id FROM email_outbox WHERE sent_at IS NULL AND attempts < $1 AND (claimed_until IS NULL OR claimed_until < NOW()) ORDER BY created_at LIMIT $2 FOR UPDATE SKIP LOCKED) RETURNING id, recipient, subject, body",
+            &[&OUTBOX_MAX_ATTEMPTS, &OUTBOX_BATCH_SIZE, &OUTBOX_CLAIM_MINUTES],
+        )
+        .await?;
+    for row in rows {
+        let id: Uuid = row.get(0);
+        let recipient: String = row.get(1);
+        let subject: String = row.get(2);
+        let body: String = row.get(3);
+        let outcome = match recipient.parse::<Mailbox>() {
+            Ok(to) => match lettre::Message::builder()
+                .from(from.clone())
+                .to(to)
+                .subject(subject)
+                .body(body)
+            {
+                Ok(message) => transport
+                    .send(message)
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            },
+            Err(e) => Err(e.to_string()),
+        };
+        match outcome {
+            Ok(()) => {
+                client
+                    .execute(
+                        "UPDATE email_outbox SET sent_at = NOW(), attempts = attempts + 1, last_error = NULL, claimed_until = NULL WHERE id = $1",
+                        &[&id],
+                    )
+                    .await?;
+            }
+            Err(why) => {
+                warn_elog!(ev_log, "Could not send email {}: {}", id, why);
+                client
+                    .execute(
+                        "UPDATE email_outbox SET attempts = attempts + 1, last_error = $2, claimed_until = NULL WHERE id = $1",
+                        &[&id, &why],
+                    )
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
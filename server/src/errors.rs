@@ -29,15 +29,41 @@ pub(crate) enum LuminaError {
     Unknown,
     RocketFaillure(Box<rocket::Error>),
     BcryptError,
+    PasswordHashError,
     RegisterEmailInUse,
     RegisterUsernameInUse,
     RegisterEmailNotValid,
     RegisterUsernameInvalid(crate::user::OnRegisterUsernameInvalid),
     RegisterPasswordNotValid(crate::user::OnRegisterPasswordNotValid),
+    RegisterInviteInvalid,
+    RegistrationClosed,
     AuthenticationWrongPassword,
+    AuthenticationWrongSecondFactor,
+    /// Too many wrong second factors for this account lately.
+    AuthenticationTooManyAttempts,
+    /// The account exists and the credentials are right, but its state doesn't allow logging in.
+    AccountLocked(Box<crate::user::AccountState>),
+    TwoFactorAlreadyEnabled,
+    EmailNotVerified,
+    TokenInvalid,
     UUidError,
     RegexError,
     SerializationError(serde_json::Error),
+    IoError(std::io::Error),
+    PeerRequestFailed(reqwest::Error),
+    MigrationAddressInvalid,
+    MigrationArchiveInvalid,
+    MigrationPeerNotAllowed,
+    MigrationPeerUnexpected,
+    MigrationNotMoved,
+    RelationshipWithSelf,
+    Blocked,
+    ReportTargetNotFound,
+    SuspensionLengthInvalid,
+    NotPermitted,
+    LastAdmin,
+    FilterRuleInvalid,
+    PostRejected,
     JoinFaillure,
 }
 
@@ -65,6 +91,18 @@ impl From<serde_json::Error> for LuminaError {
     }
 }
 
+impl From<std::io::Error> for LuminaError {
+    fn from(err: std::io::Error) -> Self {
+        LuminaError::IoError(err)
+    }
+}
+
+impl From<reqwest::Error> for LuminaError {
+    fn from(err: reqwest::Error) -> Self {
+        LuminaError::PeerRequestFailed(err)
+    }
+}
+
 impl From<crate::postgres::Error> for LuminaError {
     fn from(err: crate::postgres::Error) -> Self {
         LuminaError::DbError(LuminaDbError::Postgres(err))
@@ -100,6 +138,32 @@ impl std::fmt::Display for LuminaError {
                         "LUMINA_SERVER_PORT is not a valid port number".to_string(),
                     crate::EnvVar::LUMINA_POSTGRES_PORT =>
                         "LUMINA_POSTGRES_PORT is not a valid port number".to_string(),
+                    crate::EnvVar::LUMINA_SMTP_PORT =>
+                        "LUMINA_SMTP_PORT is not a valid port number".to_string(),
+                    crate::EnvVar::LUMINA_SMTP_FROM =>
+                        "LUMINA_SMTP_FROM is not a valid mailbox".to_string(),
+                    crate::EnvVar::LUMINA_ARGON2_MEMORY_KIB =>
+                        "LUMINA_ARGON2_MEMORY_KIB is not a valid amount of memory for Argon2"
+                            .to_string(),
+                    crate::EnvVar::LUMINA_ARGON2_ITERATIONS =>
+                        "LUMINA_ARGON2_ITERATIONS is not a valid iteration count for Argon2"
+                            .to_string(),
+                    crate::EnvVar::LUMINA_REGISTRATION_MODE =>
+                        "LUMINA_REGISTRATION_MODE is not one of 'open', 'invite', 'approval' or 'closed'"
+                            .to_string(),
+                    crate::EnvVar::LUMINA_DISCRIMINATORS =>
+                        "LUMINA_DISCRIMINATORS is not 'none' or a list of digit counts".to_string(),
+                    crate::EnvVar::LUMINA_ARGON2_LANES =>
+                        "LUMINA_ARGON2_LANES is not a valid lane count for Argon2".to_string(),
+                    crate::EnvVar::LUMINA_WS_PING_INTERVAL_SECS =>
+                        "LUMINA_WS_PING_INTERVAL_SECS is not a number of seconds shorter than LUMINA_WS_IDLE_TIMEOUT_SECS"
+                            .to_string(),
+                    crate::EnvVar::LUMINA_WS_IDLE_TIMEOUT_SECS =>
+                        "LUMINA_WS_IDLE_TIMEOUT_SECS is not a number of seconds".to_string(),
+                    crate::EnvVar::LUMINA_WS_MAX_FRAME_KIB =>
+                        "LUMINA_WS_MAX_FRAME_KIB is not a valid size in KiB".to_string(),
+                    crate::EnvVar::LUMINA_RATE_LIMITER =>
+                        "LUMINA_RATE_LIMITER is not one of 'memory' or 'redis'".to_string(),
                 },
 
                 LuminaError::DbError(e) => match e {
@@ -110,15 +174,49 @@ impl std::fmt::Display for LuminaError {
                 LuminaError::Bb8RunErrorRedis(e) => format!("Redis connection pool error: {}", e),
                 LuminaError::RocketFaillure(e) => format!("Rocket error: {}", e),
                 LuminaError::BcryptError => "Bcrypt error".to_string(),
+                LuminaError::PasswordHashError => "Password hashing error".to_string(),
                 LuminaError::RegisterEmailInUse => "Email already in use".to_string(),
                 LuminaError::RegisterUsernameInUse => "Username already in use".to_string(),
                 LuminaError::RegisterEmailNotValid => "Email not valid".to_string(),
                 LuminaError::RegisterUsernameInvalid(s) => format!("Username invalid: {}", s),
                 LuminaError::RegisterPasswordNotValid(s) => format!("Password not valid: {}", s),
+                LuminaError::RegisterInviteInvalid =>
+                    "Invite code invalid, used up or expired".to_string(),
+                LuminaError::RegistrationClosed =>
+                    "Registration is closed on this instance".to_string(),
                 LuminaError::AuthenticationWrongPassword => "Wrong password".to_string(),
+                LuminaError::AuthenticationWrongSecondFactor => "Wrong second factor".to_string(),
+                LuminaError::AuthenticationTooManyAttempts =>
+                    "Too many wrong second factors, try again later".to_string(),
+                LuminaError::AccountLocked(state) => format!("Account can't log in: {:?}", state),
+                LuminaError::TwoFactorAlreadyEnabled =>
+                    "Two-factor authentication already enabled".to_string(),
+                LuminaError::EmailNotVerified => "Email address not verified".to_string(),
+                LuminaError::TokenInvalid => "Token invalid or expired".to_string(),
                 LuminaError::UUidError => "UUID error".to_string(),
                 LuminaError::RegexError => "Regex error".to_string(),
                 LuminaError::SerializationError(s) => format!("Serialization error: {}", s),
+                LuminaError::IoError(e) => format!("IO error: {}", e),
+                LuminaError::PeerRequestFailed(e) => format!("Request to peer failed: {}", e),
+                LuminaError::MigrationAddressInvalid =>
+                    "Not an address like 'username@instance'".to_string(),
+                LuminaError::MigrationArchiveInvalid => "Not a Lumina export archive".to_string(),
+                LuminaError::MigrationPeerNotAllowed =>
+                    "Instance is not a peer of this instance".to_string(),
+                LuminaError::MigrationPeerUnexpected =>
+                    "Peer gave an unexpected answer".to_string(),
+                LuminaError::MigrationNotMoved =>
+                    "Account did not move to this instance".to_string(),
+                LuminaError::RelationshipWithSelf => "Can't block or mute oneself".to_string(),
+                LuminaError::Blocked => "Blocked".to_string(),
+                LuminaError::ReportTargetNotFound => "Reported user or item not found".to_string(),
+                LuminaError::SuspensionLengthInvalid =>
+                    "A suspension lasts at least a day".to_string(),
+                LuminaError::NotPermitted => "Not permitted".to_string(),
+                LuminaError::LastAdmin => "Can't take the role of the last admin".to_string(),
+                LuminaError::FilterRuleInvalid =>
+                    "Filter rule is empty or not a valid regular expression".to_string(),
+                LuminaError::PostRejected => "Post rejected by a filter rule".to_string(),
                 LuminaError::JoinFaillure => "Process join failure".to_string(),
                 LuminaError::Unknown => "Unknown error".to_string(),
             }
@@ -0,0 +1,240 @@
+//! Lumina > Server > Filters
+//!
+//! Instance-wide filter rules, checked against every post written here and every post coming
+//! in from other instances. A rule is a keyword or a regular expression, and an action to take
+//! on posts it matches: reject them, hold them for review, or mark them sensitive.
+//!
+//! All rules are compiled into one [`RegexSet`], shared by everything that checks posts and
+//! rebuilt whenever the rules change. Changes are announced on a Redis channel, so that every node
+//! rebuilds its set, not only the one the change was made on.
+
+/*
+ *     Lumina/Peonies
+ *     Copyright (C) 2018-2026 MLC 'Strawmelonjuice'  Bloeiman and contributors.
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::database::DbConn;
+use crate::errors::LuminaError;
+use crate::user::User;
+use bb8::Pool;
+use bb8_redis::RedisConnectionManager;
+use regex::RegexSet;
+use std::sync::{Arc, LazyLock, RwLock};
+use uuid::Uuid;
+
+/// What happens to a post a rule matches. When several rules match, the strictest action wins,
+/// which is the last one here.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterAction {
+    /// Publish the post, marked sensitive.
+    MarkSensitive,
+    /// Publish the post hidden, and report it so a moderator can release it.
+    Hold,
+    /// Refuse the post.
+    Reject,
+}
+
+impl FilterAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FilterAction::MarkSensitive => "mark_sensitive",
+            FilterAction::Hold => "hold",
+            FilterAction::Reject => "reject",
+        }
+    }
+
+    /// Unknown actions are treated as the strictest one.
+    pub fn from_db(action: &str) -> Self {
+        match action {
+            "mark_sensitive" => FilterAction::MarkSensitive,
+            "hold" => FilterAction::Hold,
+            _ => FilterAction::Reject,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct FilterRule {
+    pub id: Uuid,
+    /// A keyword, or a regular expression when `is_regex` is set.
+    pub pattern: String,
+    pub is_regex: bool,
+    pub action: FilterAction,
+}
+
+impl FilterRule {
+    /// The regular expression this rule matches with. Keywords match as whole words, ignoring
+    /// case.
+    pub fn expression(&self) -> String {
+        if self.is_regex {
+            self.pattern.clone()
+        } else {
+            format!(r"(?i)\b{}\b", regex::escape(&self.pattern))
+        }
+    }
+}
+
+/// The rules, ready to be matched against.
+pub(crate) struct CompiledFilters {
+    set: RegexSet,
+    rules: Vec<(Uuid, FilterAction)>,
+}
+
+impl CompiledFilters {
+    pub(crate) fn compile(rules: &[FilterRule]) -> Result<Self, LuminaError> {
+        let set = RegexSet::new(rules.iter().map(FilterRule::expression))
+            .map_err(|_| LuminaError::FilterRuleInvalid)?;
+        Ok(CompiledFilters {
+            set,
+            rules: rules.iter().map(|rule| (rule.id, rule.action)).collect(),
+        })
+    }
+
+    /// The strictest action of the rules `text` matches, and the rule it comes from.
+    pub(crate) fn check(&self, text: &str) -> Option<(FilterAction, Uuid)> {
+        self.set
+            .matches(text)
+            .into_iter()
+            .map(|i| (self.rules[i].1, self.rules[i].0))
+            .max_by_key(|(action, _)| *action)
+    }
+}
+
+static FILTERS: LazyLock<RwLock<Arc<CompiledFilters>>> = LazyLock::new(|| {
+    RwLock::new(Arc::new(CompiledFilters {
+        set: RegexSet::empty(),
+        rules: vec![],
+    }))
+});
+
+/// Check `text` against the current rules. See [`CompiledFilters::check`].
+pub(crate) fn check(text: &str) -> Option<(FilterAction, Uuid)> {
+    let filters = FILTERS
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone();
+    filters.check(text)
+}
+
+/// The Redis channel changes to the rules are announced on. Listened to by
+/// [`crate::connections::listen`].
+pub(crate) const CHANNEL: &str = "filter_rules";
+
+/// Tell every node, this one included, to recompile the rules. Best effort: a node that misses
+/// it reloads the rules when it subscribes again.
+async fn announce(redis_pool: &Pool<RedisConnectionManager>) {
+    if let Ok(mut redis_conn) = redis_pool.get().await {
+        let _: Result<(), _> = redis::cmd("PUBLISH")
+            .arg(CHANNEL)
+            .arg("changed")
+            .query_async(&mut *redis_conn)
+            .await;
+    }
+}
+
+/// Recompile the shared rules from the database. Called on startup, after every change, and when
+/// another node announces one.
+pub(crate) async fn refresh(db: &DbConn) -> Result<(), LuminaError> {
+    let compiled = Arc::new(CompiledFilters::compile(&list_rules(db).await?)?);
+    *FILTERS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = compiled;
+    Ok(())
+}
+
+/// All rules, oldest first.
+pub(crate) async fn list_rules(db: &DbConn) -> Result<Vec<FilterRule>, LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            let rows = client
+                .query(
+                    "SELECT id, pattern, is_regex, action FROM filter_rules ORDER BY created_at",
+                    &[],
+                )
+                .await?;
+            Ok(rows
+                .into_iter()
+                .map(|row| FilterRule {
+                    id: row.get(0),
+                    pattern: row.get(1),
+                    is_regex: row.get(2),
+                    action: FilterAction::from_db(row.get(3)),
+                })
+                .collect())
+        }
+    }
+}
+
+/// Add a rule and start applying it. Fails with [`LuminaError::FilterRuleInvalid`] on a regular
+/// expression that doesn't compile, alone or together with the other rules.
+pub(crate) async fn add_rule(
+    admin: &User,
+    pattern: String,
+    is_regex: bool,
+    action: FilterAction,
+    db: &DbConn,
+) -> Result<Uuid, LuminaError> {
+    let rule = FilterRule {
+        id: Uuid::nil(),
+        pattern,
+        is_regex,
+        action,
+    };
+    // An empty pattern would match every post.
+    if rule.pattern.trim().is_empty() {
+        return Err(LuminaError::FilterRuleInvalid);
+    }
+    // All rules are compiled into one set, which has a size limit a rule can push it over.
+    let mut rules = list_rules(db).await?;
+    rules.push(rule.clone());
+    CompiledFilters::compile(&rules)?;
+    let id = match db {
+        DbConn::PgsqlConnection(pg_pool, redis_pool) => {
+            let client = pg_pool.get().await?;
+            let id = client
+                .query_one(
+                    "INSERT INTO filter_rules (pattern, is_regex, action, created_by) VALUES ($1, $2, $3, $4) RETURNING id",
+                    &[&rule.pattern, &rule.is_regex, &rule.action.as_str(), &admin.id],
+                )
+                .await?
+                .get(0);
+            announce(redis_pool).await;
+            id
+        }
+    };
+    refresh(db).await?;
+    Ok(id)
+}
+
+/// Remove a rule and stop applying it. Returns whether there was such a rule.
+pub(crate) async fn remove_rule(id: Uuid, db: &DbConn) -> Result<bool, LuminaError> {
+    let removed = match db {
+        DbConn::PgsqlConnection(pg_pool, redis_pool) => {
+            let client = pg_pool.get().await?;
+            let removed = client
+                .execute("DELETE FROM filter_rules WHERE id = $1", &[&id])
+                .await?;
+            announce(redis_pool).await;
+            removed
+        }
+    };
+    refresh(db).await?;
+    Ok(removed == 1)
+}
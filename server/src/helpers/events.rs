@@ -42,6 +42,8 @@ pub enum EventType {
     AuthenticationError,
     SoftError,
     HTTPCode(u16),
+    /// Actions taken by moderators. These make up the moderation audit trail.
+    Moderation,
 }
 
 /// A reusable logger that logs messages to stdout with colored prefixes
@@ -106,6 +108,7 @@ impl EventLogger {
                 "[AuthenticationError]".color_bright_red().style_bold(),
                 true,
             ),
+            EventType::Moderation => ("[MODERATION]".color_lilac().style_bold(), false),
             EventType::HTTPCode(code) => {
                 let codestring = match code {
                     101 => format!("[HTTP/{} (Switching Protocols)]", code)
@@ -155,6 +158,7 @@ impl EventLogger {
                     EventType::RegistrationError => String::from("REGISTRATION_ERROR"),
                     EventType::AuthenticationError => String::from("AUTHENTICATION_ERROR"),
                     EventType::HTTPCode(code) => format!("HTTP/{}", code),
+                    EventType::Moderation => String::from("MODERATION"),
                 };
                 let ansi_regex = regex::Regex::new(r"\x1B\[[0-?]*[ -/]*[@-~]")
                     .map_err(|_| LuminaError::RegexError)
@@ -243,6 +247,11 @@ impl EventLogger {
     pub async fn http_code(&self, code: u16, message: &str) {
         self.log(EventType::HTTPCode(code), message).await
     }
+
+    /// Convenience method to log a moderator action to the audit trail.
+    pub async fn moderation(&self, message: &str) {
+        self.log(EventType::Moderation, message).await
+    }
 }
 #[macro_export]
 macro_rules! info_elog {
@@ -333,3 +342,12 @@ macro_rules! http_code_elog {
             $logger.http_code($code, &format!($($arg)*)).await
         };
 }
+
+#[macro_export]
+/// Takes an event log object and then runs .moderation on it, formatting using the other
+/// arguments.
+macro_rules! moderation_elog {
+    ($logger:expr, $($arg:tt)*) => {
+        $logger.moderation(&format!($($arg)*)).await
+    };
+}
@@ -21,3 +21,5 @@
  */
 /// Shared helper functions and utilities for the server.
 pub mod events;
+pub(crate) mod passwords;
+pub(crate) mod tokens;
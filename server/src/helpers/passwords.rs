@@ -0,0 +1,122 @@
+//! Lumina > Server > Helpers > Passwords
+//!
+//! Password hashing. New hashes are Argon2id, with parameters from the environment. Hashes made
+//! before that are bcrypt, which is still verified, and replaced on the next successful login.
+//!
+//! The algorithm is read from the stored hash itself: PHC strings (`$argon2id$...`) for Argon2
+//! and `$2b$` style prefixes for bcrypt.
+
+/*
+ *     Lumina/Peonies
+ *     Copyright (C) 2018-2026 MLC 'Strawmelonjuice'  Bloeiman and contributors.
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::EnvVar::*;
+use crate::errors::LuminaError;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+
+/// The OWASP recommended minimum for Argon2id: 19 MiB of memory, 2 iterations, 1 lane.
+const DEFAULT_MEMORY_KIB: u32 = 19 * 1024;
+const DEFAULT_ITERATIONS: u32 = 2;
+const DEFAULT_LANES: u32 = 1;
+
+/// The Argon2id parameters set through `LUMINA_ARGON2_*`.
+pub(crate) fn params() -> Result<Params, LuminaError> {
+    let memory = std::env::var("LUMINA_ARGON2_MEMORY_KIB")
+        .map(|s| s.parse::<u32>())
+        .unwrap_or(Ok(DEFAULT_MEMORY_KIB))
+        .map_err(|_| LuminaError::ConfInvalid(LUMINA_ARGON2_MEMORY_KIB))?;
+    let iterations = std::env::var("LUMINA_ARGON2_ITERATIONS")
+        .map(|s| s.parse::<u32>())
+        .unwrap_or(Ok(DEFAULT_ITERATIONS))
+        .map_err(|_| LuminaError::ConfInvalid(LUMINA_ARGON2_ITERATIONS))?;
+    let lanes = std::env::var("LUMINA_ARGON2_LANES")
+        .map(|s| s.parse::<u32>())
+        .unwrap_or(Ok(DEFAULT_LANES))
+        .map_err(|_| LuminaError::ConfInvalid(LUMINA_ARGON2_LANES))?;
+    // Params::new checks each value against the bounds of the algorithm, and memory against the
+    // parallelism. Blame the variable that is out of bounds on its own, or else the memory.
+    Params::new(memory, iterations, lanes, None).map_err(|e| {
+        LuminaError::ConfInvalid(match e {
+            argon2::Error::TimeTooSmall => LUMINA_ARGON2_ITERATIONS,
+            argon2::Error::ThreadsTooFew | argon2::Error::ThreadsTooMany => LUMINA_ARGON2_LANES,
+            _ => LUMINA_ARGON2_MEMORY_KIB,
+        })
+    })
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+/// Hash a password with Argon2id. Runs on the blocking thread pool, as hashing is slow on
+/// purpose.
+pub(crate) async fn hash_password(password: String) -> Result<String, LuminaError> {
+    let params = params()?;
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|_| LuminaError::PasswordHashError)
+    })
+    .await
+    .map_err(|_| LuminaError::JoinFaillure)?
+}
+
+/// Check a password against a stored hash of either algorithm.
+pub(crate) async fn verify_password(password: String, hash: String) -> Result<bool, LuminaError> {
+    tokio::task::spawn_blocking(move || {
+        if is_bcrypt(&hash) {
+            return bcrypt::verify(password, &hash).map_err(|_| LuminaError::BcryptError);
+        }
+        let parsed = PasswordHash::new(&hash).map_err(|_| LuminaError::PasswordHashError)?;
+        // The parameters are taken from the hash, so older Argon2 hashes keep verifying.
+        match Argon2::default().verify_password(password.as_bytes(), &parsed) {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(_) => Err(LuminaError::PasswordHashError),
+        }
+    })
+    .await
+    .map_err(|_| LuminaError::JoinFaillure)?
+}
+
+/// Whether a stored hash should be replaced once the password is known: bcrypt hashes, and
+/// Argon2 hashes made with other parameters than the current ones.
+pub(crate) fn needs_rehash(hash: &str) -> bool {
+    if is_bcrypt(hash) {
+        return true;
+    }
+    let Ok(current) = params() else {
+        return false;
+    };
+    match PasswordHash::new(hash) {
+        Ok(parsed) => {
+            parsed.algorithm != Algorithm::Argon2id.ident()
+                || Params::try_from(&parsed).is_ok_and(|p| {
+                    p.m_cost() != current.m_cost()
+                        || p.t_cost() != current.t_cost()
+                        || p.p_cost() != current.p_cost()
+                })
+        }
+        Err(_) => false,
+    }
+}
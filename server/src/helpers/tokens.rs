@@ -0,0 +1,70 @@
+//! Lumina > Server > Helpers > Tokens
+//!
+//! Generation and hashing of secret tokens, like session tokens.
+//!
+//! Tokens are handed to clients once and only a keyed hash of them is stored, so that a leaked
+//! database does not leak live sessions. The key is `LUMINA_DB_SALT`, which never touches the
+//! database itself.
+
+/*
+ *     Lumina/Peonies
+ *     Copyright (C) 2018-2026 MLC 'Strawmelonjuice'  Bloeiman and contributors.
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::sync::OnceLock;
+
+/// Number of random bytes in a token. 256 bits, against the 122 random bits of a v4 UUID.
+const TOKEN_BYTES: usize = 32;
+
+/// The salt used when `LUMINA_DB_SALT` is not set.
+pub(crate) const DEFAULT_DB_SALT: &str = "sal";
+
+fn hashing_key() -> &'static [u8] {
+    static KEY: OnceLock<Vec<u8>> = OnceLock::new();
+    KEY.get_or_init(|| {
+        std::env::var("LUMINA_DB_SALT")
+            .unwrap_or(String::from(DEFAULT_DB_SALT))
+            .into_bytes()
+    })
+}
+
+/// Generate a new secret token from the operating system seeded CSPRNG, encoded as URL-safe
+/// base64.
+pub(crate) fn generate_token() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    rand::rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// The keyed hash (HMAC-SHA256) of a token, hex encoded. This is what gets stored.
+pub(crate) fn hash_token(token: &str) -> String {
+    // HMAC accepts keys of any length, so this can't fail.
+    let mut mac = match Hmac::<Sha256>::new_from_slice(hashing_key()) {
+        Ok(mac) => mac,
+        Err(_) => unreachable!("HMAC takes keys of any size"),
+    };
+    mac.update(token.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
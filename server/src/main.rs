@@ -25,19 +25,29 @@
 extern crate dotenv;
 #[macro_use]
 extern crate rocket;
+mod account_data;
+mod api;
 mod client_communication;
+mod connections;
 mod database;
+mod email;
 pub mod errors;
+mod filters;
 pub mod helpers;
+mod migration;
+mod moderation;
+mod permissions;
+mod posts;
 mod staticroutes;
 #[cfg(test)]
 mod tests;
 mod timeline;
+mod two_factor;
+mod username_policy;
 use helpers::events::EventLogger;
 use rocket::config::LogLevel;
 use std::io::ErrorKind;
 use std::{net::IpAddr, process, sync::Arc};
-use tokio::sync::Mutex;
 use uuid::Uuid;
 mod user;
 use tokio_postgres as postgres;
@@ -45,16 +55,20 @@ struct AppState(Arc<InnerAppState>);
 struct InnerAppState {
     #[allow(dead_code)]
     config: ServerConfig,
-    db: Mutex<DbConn>,
+    db: DbConn,
     event_logger: EventLogger,
 }
 mod rate_limiter;
+mod registration;
+mod relationships;
 use database::DbConn;
 use rate_limiter::{AuthRateLimiter, GeneralRateLimiter};
+use registration::RegistrationMode;
 #[derive(Debug, Clone)]
 struct ServerConfig {
     port: u16,
     host: IpAddr,
+    registration_mode: RegistrationMode,
 }
 
 #[allow(non_camel_case_types)]
@@ -63,6 +77,17 @@ enum EnvVar {
     LUMINA_SERVER_ADDR,
     LUMINA_SERVER_PORT,
     LUMINA_POSTGRES_PORT,
+    LUMINA_SMTP_PORT,
+    LUMINA_SMTP_FROM,
+    LUMINA_ARGON2_MEMORY_KIB,
+    LUMINA_ARGON2_ITERATIONS,
+    LUMINA_ARGON2_LANES,
+    LUMINA_REGISTRATION_MODE,
+    LUMINA_DISCRIMINATORS,
+    LUMINA_WS_PING_INTERVAL_SECS,
+    LUMINA_WS_IDLE_TIMEOUT_SECS,
+    LUMINA_WS_MAX_FRAME_KIB,
+    LUMINA_RATE_LIMITER,
 }
 impl std::fmt::Display for EnvVar {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -73,6 +98,17 @@ impl std::fmt::Display for EnvVar {
                 EnvVar::LUMINA_SERVER_ADDR => "LUMINA_SERVER_ADDR",
                 EnvVar::LUMINA_SERVER_PORT => "LUMINA_SERVER_PORT",
                 EnvVar::LUMINA_POSTGRES_PORT => "LUMINA_POSTGRES_PORT",
+                EnvVar::LUMINA_SMTP_PORT => "LUMINA_SMTP_PORT",
+                EnvVar::LUMINA_SMTP_FROM => "LUMINA_SMTP_FROM",
+                EnvVar::LUMINA_ARGON2_MEMORY_KIB => "LUMINA_ARGON2_MEMORY_KIB",
+                EnvVar::LUMINA_ARGON2_ITERATIONS => "LUMINA_ARGON2_ITERATIONS",
+                EnvVar::LUMINA_ARGON2_LANES => "LUMINA_ARGON2_LANES",
+                EnvVar::LUMINA_REGISTRATION_MODE => "LUMINA_REGISTRATION_MODE",
+                EnvVar::LUMINA_DISCRIMINATORS => "LUMINA_DISCRIMINATORS",
+                EnvVar::LUMINA_WS_PING_INTERVAL_SECS => "LUMINA_WS_PING_INTERVAL_SECS",
+                EnvVar::LUMINA_WS_IDLE_TIMEOUT_SECS => "LUMINA_WS_IDLE_TIMEOUT_SECS",
+                EnvVar::LUMINA_WS_MAX_FRAME_KIB => "LUMINA_WS_MAX_FRAME_KIB",
+                EnvVar::LUMINA_RATE_LIMITER => "LUMINA_RATE_LIMITER",
             }
         )
     }
@@ -95,7 +131,27 @@ fn config_get() -> Result<ServerConfig, LuminaError> {
         s.parse::<u16>()
             .map_err(|_| LuminaError::ConfInvalid(LUMINA_SERVER_PORT))?
     };
-    Ok(ServerConfig { port, host: addr })
+    // Not kept in the config, but checked here so bad values stop startup instead of logins.
+    helpers::passwords::params()?;
+    username_policy::UsernamePolicy::from_env()?;
+    client_communication::SocketSettings::from_env()?;
+    rate_limiter::Backend::from_env()?;
+    Ok(ServerConfig {
+        port,
+        host: addr,
+        registration_mode: RegistrationMode::from_env()?,
+    })
+}
+
+/// Connect to the database for a one-off subcommand, exiting if that doesn't work.
+async fn cli_database(ev_log: &EventLogger) -> DbConn {
+    match database::setup().await {
+        Ok(db) => db.into(),
+        Err(e) => {
+            error_elog!(ev_log, "Could not connect to the database: {}", e);
+            process::exit(1);
+        }
+    }
 }
 
 #[rocket::main]
@@ -200,6 +256,38 @@ async fn main() {
                     let ev_log: EventLogger = EventLogger::new(&Some(pg));
                     success_elog!(ev_log, "Database connected.");
 
+                    match email::MailerConfig::from_env() {
+                        Ok(mailer_config) => {
+                            spawn(email::run_outbox_worker(
+                                db.recreate().await,
+                                mailer_config,
+                                ev_log.clone(),
+                            ));
+                        }
+                        Err(LuminaError::ConfInvalid(a)) => {
+                            error_elog!(
+                                ev_log,
+                                "Invalid environment variable: {}",
+                                a.to_string().color_bright_orange()
+                            );
+                            process::exit(1);
+                        }
+                        Err(e) => {
+                            error_elog!(ev_log, "Could not set up mail worker: {:?}", e);
+                            process::exit(1);
+                        }
+                    }
+
+                    // A rule that stopped compiling shouldn't keep the instance down, that would
+                    // leave no way to remove it.
+                    if let Err(e) = filters::refresh(&db).await {
+                        error_elog!(
+                            ev_log,
+                            "Could not load the filter rules, running without them: {:?}",
+                            e
+                        );
+                    }
+
                     if cfg!(debug_assertions) {
                         let redis_pool = db.get_redis_pool();
                         let mut redis_conn = redis_pool.get().await.unwrap();
@@ -214,6 +302,7 @@ async fn main() {
                             &db,
                             "00000000-0000-0000-0000-000000000000",
                             None,
+                            None,
                         )
                         .await
                         .unwrap_or_default();
@@ -234,6 +323,8 @@ async fn main() {
                                             String::from("test@lumina123.co"),
                                             String::from("testuser1"),
                                             String::from("MyTestPassw9292!"),
+                                            RegistrationMode::Open,
+                                            None,
                                             &db,
                                         )
                                         .await
@@ -255,6 +346,8 @@ async fn main() {
                                         String::from("test@lumina234.co"),
                                         String::from("testuser2"),
                                         String::from("MyTestPassw9292!"),
+                                        RegistrationMode::Open,
+                                        None,
                                         &db,
                                     )
                                     .await
@@ -274,6 +367,13 @@ async fn main() {
 
                                     match (user_1_, user_2_) {
                                         (Ok(user_1), Ok(_)) => {
+                                            // Nobody is going to click the verification links for these.
+                                            let _ = client
+                                                .execute(
+                                                    "UPDATE users SET email_verified = TRUE WHERE username IN ('testuser1', 'testuser2')",
+                                                    &[],
+                                                )
+                                                .await;
                                             println!(
                                                 "Created two users with password 'MyTestPassw9292!' and usernames 'testuser1' and 'testuser2'."
                                             );
@@ -307,17 +407,26 @@ async fn main() {
 
                     let appstate = AppState(Arc::from(InnerAppState {
                         config: config.clone(),
-                        db: Mutex::from(db),
+                        db,
                         event_logger: ev_log.clone(),
                     }));
 
-                    // Create a simple in-memory IP-based rate limiter.
-                    // Default: allow 5 events per 10 seconds (0.5 tokens/sec) with capacity 10.
-                    let rate_limiter = GeneralRateLimiter::new(0.5, 10.0);
-
+                    // IP-based rate limiters, in memory or shared by all nodes through Redis.
+                    // General: allow 5 events per 10 seconds (0.5 tokens/sec) with capacity 10.
                     // Dedicated, stricter limiter for authentication attempts (helps stop brute-force):
                     // e.g. allow 2 attempts per 10 seconds (0.2 tokens/sec) with capacity 4.
-                    let auth_rate_limiter = AuthRateLimiter::new(0.2, 4.0);
+                    let (rate_limiter, auth_rate_limiter) = match rate_limiter::Backend::from_env()
+                        .unwrap_or(rate_limiter::Backend::Memory)
+                    {
+                        rate_limiter::Backend::Memory => (
+                            GeneralRateLimiter::new(0.5, 10.0),
+                            AuthRateLimiter::new(0.2, 4.0),
+                        ),
+                        rate_limiter::Backend::Redis => (
+                            GeneralRateLimiter::shared(0.5, 10.0, appstate.0.db.get_redis_pool()),
+                            AuthRateLimiter::shared(0.2, 4.0, appstate.0.db.get_redis_pool()),
+                        ),
+                    };
 
                     let def = rocket::Config {
                         port: config.port,
@@ -343,6 +452,20 @@ async fn main() {
                                 staticroutes::logo_svg,
                                 staticroutes::logo_png,
                                 staticroutes::favicon,
+                                account_data::download_export,
+                                migration::profile,
+                            ],
+                        )
+                        .mount(
+                            "/api/v1",
+                            routes![
+                                api::register,
+                                api::login,
+                                api::second_factor,
+                                api::me,
+                                api::timeline,
+                                api::post,
+                                api::create_post,
                             ],
                         )
                         .mount("/assets", rocket::fs::FileServer::from("./assets"))
@@ -464,6 +587,155 @@ async fn main() {
                 }
             };
         }
+        (false, "invite") => {
+            dotenv().ok();
+            let max_uses = match args.get(1).map(|s| s.parse::<i32>()) {
+                None => 1,
+                Some(Ok(n)) if n > 0 => n,
+                Some(_) => {
+                    soft_error_elog!(ev_log, "The number of uses should be a positive number.");
+                    process::exit(1);
+                }
+            };
+            let valid_for_days = match args.get(2).map(|s| s.parse::<i32>()) {
+                None => None,
+                Some(Ok(n)) if n > 0 => Some(n),
+                Some(_) => {
+                    soft_error_elog!(ev_log, "The number of days should be a positive number.");
+                    process::exit(1);
+                }
+            };
+            let db = cli_database(&ev_log).await;
+            match registration::create_invite(max_uses, valid_for_days, &db).await {
+                Ok(code) => {
+                    success_elog!(
+                        ev_log,
+                        "Invite code for {} registration(s){}: {}",
+                        max_uses,
+                        valid_for_days
+                            .map(|days| format!(", valid for {} days", days))
+                            .unwrap_or_default(),
+                        code.color_bright_cyan()
+                    );
+                }
+                Err(e) => {
+                    error_elog!(ev_log, "Could not create invite code: {:?}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        (false, "approve") => {
+            dotenv().ok();
+            let db = cli_database(&ev_log).await;
+            match args.get(1) {
+                None => match registration::pending(&db).await {
+                    Ok(usernames) if usernames.is_empty() => {
+                        info_elog!(ev_log, "No accounts are waiting for approval.");
+                    }
+                    Ok(usernames) => {
+                        info_elog!(
+                            ev_log,
+                            "Waiting for approval: {}",
+                            usernames.join(", ").color_bright_cyan()
+                        );
+                    }
+                    Err(e) => {
+                        error_elog!(ev_log, "Could not list pending accounts: {:?}", e);
+                        process::exit(1);
+                    }
+                },
+                Some(username) => match registration::approve(username, &db).await {
+                    Ok(true) => {
+                        success_elog!(ev_log, "Approved {}.", username.clone().color_bright_cyan());
+                    }
+                    Ok(false) => {
+                        soft_error_elog!(
+                            ev_log,
+                            "No account named {} is waiting for approval.",
+                            username.clone().color_bright_cyan()
+                        );
+                        process::exit(1);
+                    }
+                    Err(e) => {
+                        error_elog!(ev_log, "Could not approve {}: {:?}", username, e);
+                        process::exit(1);
+                    }
+                },
+            }
+        }
+        (false, "grant") => {
+            dotenv().ok();
+            let Some(username) = args.get(1) else {
+                soft_error_elog!(ev_log, "Which user should get the role?");
+                process::exit(1);
+            };
+            let role = match args.get(2).map(|s| s.parse::<permissions::Role>()) {
+                None => permissions::Role::Admin,
+                Some(Ok(role)) => role,
+                Some(Err(())) => {
+                    soft_error_elog!(ev_log, "The role should be user, moderator or admin.");
+                    process::exit(1);
+                }
+            };
+            let db = cli_database(&ev_log).await;
+            match permissions::grant(username, role, &db).await {
+                Ok(true) => {
+                    success_elog!(
+                        ev_log,
+                        "{} now has the role {}.",
+                        username.clone().color_bright_cyan(),
+                        role.as_str()
+                    );
+                }
+                Ok(false) => {
+                    soft_error_elog!(
+                        ev_log,
+                        "No account named {}.",
+                        username.clone().color_bright_cyan()
+                    );
+                    process::exit(1);
+                }
+                Err(e) => {
+                    error_elog!(ev_log, "Could not change the role of {}: {:?}", username, e);
+                    process::exit(1);
+                }
+            }
+        }
+        (false, subcommand @ ("deactivate" | "reactivate")) => {
+            dotenv().ok();
+            let deactivate = subcommand == "deactivate";
+            let Some(username) = args.get(1) else {
+                soft_error_elog!(ev_log, "Which account should be {}d?", subcommand);
+                process::exit(1);
+            };
+            let db = cli_database(&ev_log).await;
+            match user::User::set_deactivated(username, deactivate, &db).await {
+                Ok(true) => {
+                    success_elog!(
+                        ev_log,
+                        "{} {}.",
+                        if deactivate {
+                            "Deactivated"
+                        } else {
+                            "Reactivated"
+                        },
+                        username.clone().color_bright_cyan()
+                    );
+                }
+                Ok(false) => {
+                    soft_error_elog!(
+                        ev_log,
+                        "No account named {}.",
+                        username.clone().color_bright_cyan()
+                    );
+                    process::exit(1);
+                }
+                Err(e) => {
+                    error_elog!(ev_log, "Could not {} {}: {:?}", subcommand, username, e);
+                    process::exit(1);
+                }
+            }
+        }
         (false, "licence") | (false, "license") => {
             println!(
                 "Licence for {} and its {}.",
@@ -504,6 +776,23 @@ async fn main() {
                     "\t\t{}\t\tStart Lumina server",
                     "start".color_lightblue().style_italic()
                 );
+                println!(
+                    "\t\t{} [uses] [days]\tCreate an invite code, for one use unless told otherwise",
+                    "invite".color_lightblue().style_italic()
+                );
+                println!(
+                    "\t\t{} [username]\tApprove a pending account, or list them",
+                    "approve".color_lightblue().style_italic()
+                );
+                println!(
+                    "\t\t{} <username> [role]\tGive a user a role, admin unless told otherwise",
+                    "grant".color_lightblue().style_italic()
+                );
+                println!(
+                    "\t\t{}|{} <username>\tLock an account out, or let it back in",
+                    "deactivate".color_lightblue().style_italic(),
+                    "reactivate".color_lightblue().style_italic()
+                );
             }
             println!();
             {
@@ -519,7 +808,52 @@ async fn main() {
                 builder.push_record([
                     "LUMINA_DB_SALT",
                     r#"sal"#,
-                    r#"The salting to use for some data on the database."#,
+                    r#"The salting to use for some data on the database, like the key session tokens are hashed with."#,
+                ]);
+                builder.push_record([
+                    "LUMINA_ARGON2_MEMORY_KIB",
+                    r#"19456"#,
+                    r#"Memory cost of password hashing (Argon2id), in KiB."#,
+                ]);
+                builder.push_record([
+                    "LUMINA_ARGON2_ITERATIONS",
+                    r#"2"#,
+                    r#"Time cost of password hashing (Argon2id), in passes over the memory."#,
+                ]);
+                builder.push_record([
+                    "LUMINA_ARGON2_LANES",
+                    r#"1"#,
+                    r#"Parallelism of password hashing (Argon2id)."#,
+                ]);
+                builder.push_record([
+                    "LUMINA_REGISTRATION_MODE",
+                    r#"open"#,
+                    r#"Who may register: 'open', 'invite' (with a code), 'approval' (by an admin) or 'closed'."#,
+                ]);
+                builder.push_record([
+                    "LUMINA_DISCRIMINATORS",
+                    r#"4,6"#,
+                    r#"Digit counts allowed for '#' discriminators in usernames (like 'name#1234'), or 'none'."#,
+                ]);
+                builder.push_record([
+                    "LUMINA_WS_PING_INTERVAL_SECS",
+                    r#"30"#,
+                    r#"Seconds between the pings sent to every WebSocket connection."#,
+                ]);
+                builder.push_record([
+                    "LUMINA_WS_IDLE_TIMEOUT_SECS",
+                    r#"90"#,
+                    r#"Seconds a WebSocket connection may go without a message or pong before it is closed."#,
+                ]);
+                builder.push_record([
+                    "LUMINA_WS_MAX_FRAME_KIB",
+                    r#"8256"#,
+                    r#"Largest WebSocket frame or message a client may send, in KiB."#,
+                ]);
+                builder.push_record([
+                    "LUMINA_RATE_LIMITER",
+                    r#"memory"#,
+                    r#"Where rate limits are kept: 'memory' (per node, reset on restart) or 'redis' (shared by all nodes)."#,
                 ]);
                 builder.push_record([
                     "LUMINA_SERVER_PORT",
@@ -533,11 +867,46 @@ async fn main() {
                     r#"localhost"#,
                     "Broadcasted domain name, should be equal to public domain name.",
                 ]);
+                builder.push_record([
+                    "LUMINA_SYNC_PEERS",
+                    r#"-"#,
+                    "Comma-separated instances to sync with, which accounts can also move in from.",
+                ]);
                 builder.push_record([
                     "LUMINA_SYNC_INTERVAL",
                     r#"30"#,
                     "Specifies the interval between syncs. Minimum is 30.",
                 ]);
+                builder.push_record([
+                    "LUMINA_SMTP_HOST",
+                    r#"localhost"#,
+                    r#"The SMTP relay to send email through."#,
+                ]);
+                builder.push_record([
+                    "LUMINA_SMTP_PORT",
+                    r#"1025"#,
+                    r#"The port of the SMTP relay. The default is MailHog's."#,
+                ]);
+                builder.push_record([
+                    "LUMINA_SMTP_USERNAME",
+                    r#""#,
+                    r#"The username to log in to the SMTP relay with, if any."#,
+                ]);
+                builder.push_record([
+                    "LUMINA_SMTP_PASSWORD",
+                    r#""#,
+                    r#"The password to log in to the SMTP relay with, if any."#,
+                ]);
+                builder.push_record([
+                    "LUMINA_SMTP_STARTTLS",
+                    r#"false"#,
+                    r#"Whether to upgrade the SMTP connection with STARTTLS."#,
+                ]);
+                builder.push_record([
+                    "LUMINA_SMTP_FROM",
+                    r#"Lumina <noreply@LUMINA_SYNC_IID>"#,
+                    r#"The sender of email from Lumina."#,
+                ]);
                 builder.push_record([
                     "LUMINA_POSTGRES_PORT",
                     r#"5432"#,
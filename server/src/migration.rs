@@ -0,0 +1,395 @@
+//! Lumina > Server > Migration
+//!
+//! Moving accounts between instances. The old instance records where an account moved to, and
+//! redirects lookups of its profile there. The new instance imports the export archive (see
+//! [`crate::account_data`]) into an account registered there, once the old instance confirms
+//! the account moved to it.
+//!
+//! Imported posts keep where they came from in `foreign_instance_id` and `foreign_post_id`.
+//! Follows are re-established for accounts that live on this instance now, and remembered in
+//! `remote_follows` for accounts on the peers this instance syncs with (`LUMINA_SYNC_PEERS`).
+
+/*
+ *     Lumina/Peonies
+ *     Copyright (C) 2018-2026 MLC 'Strawmelonjuice'  Bloeiman and contributors.
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::account_data::{AccountExport, ExportedPost, MEDIA_DIR};
+use crate::database::DbConn;
+use crate::errors::LuminaError;
+use crate::filters::{self, FilterAction};
+use crate::helpers::events::EventLogger;
+use crate::moderation;
+use crate::user::User;
+use crate::{AppState, email, http_code_elog, info_elog};
+use rocket::State;
+use rocket::futures::future::join_all;
+use rocket::response::Redirect;
+use rocket::response::content::RawJson;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use uuid::Uuid;
+
+/// How long to wait for a peer to answer a lookup.
+const PEER_TIMEOUT_SECS: u64 = 10;
+
+/// The name of this instance, as other instances know it.
+pub(crate) fn own_instance() -> String {
+    std::env::var("LUMINA_SYNC_IID").unwrap_or(String::from("localhost"))
+}
+
+/// The instances this one syncs with, from `LUMINA_SYNC_PEERS`.
+pub(crate) fn allowed_peers() -> Vec<String> {
+    std::env::var("LUMINA_SYNC_PEERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|peer| peer.trim().to_lowercase())
+        .filter(|peer| !peer.is_empty())
+        .collect()
+}
+
+/// Split a `username@instance` address.
+pub(crate) fn parse_address(address: &str) -> Option<(&str, &str)> {
+    let (username, instance) = address.trim().rsplit_once('@')?;
+    if username.is_empty()
+        || instance.is_empty()
+        || username.contains('/')
+        || instance.contains('/')
+    {
+        return None;
+    }
+    Some((username, instance))
+}
+
+/// What an instance says about one of its accounts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum PeerAccount {
+    Present,
+    /// Moved to this `username@instance`.
+    Moved(String),
+    Missing,
+}
+
+/// The profile served to other instances.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) struct PeerProfile {
+    pub(crate) id: Uuid,
+    pub(crate) username: String,
+    pub(crate) instance: String,
+}
+
+#[derive(rocket::Responder)]
+pub(crate) enum ProfileLookup {
+    Found(RawJson<String>),
+    Moved(Box<Redirect>),
+}
+
+/// Looks up an account of this instance, redirecting to its new home if it moved.
+#[get("/users/<username>")]
+pub(crate) async fn profile(username: &str, state: &State<AppState>) -> Option<ProfileLookup> {
+    let appstate = state.0.clone();
+    let ev_log = appstate.event_logger.clone();
+    let row = {
+        match &appstate.db {
+            DbConn::PgsqlConnection(pg_pool, _) => {
+                let client = pg_pool.get().await.ok()?;
+                client
+                    .query_opt(
+                        "SELECT id, username, moved_to FROM users WHERE username = $1",
+                        &[&username],
+                    )
+                    .await
+                    .ok()??
+            }
+        }
+    };
+    let moved_to: Option<String> = row.get(2);
+    match moved_to.as_deref().and_then(parse_address) {
+        Some((new_username, new_instance)) => {
+            http_code_elog!(ev_log, 301, "/users");
+            Some(ProfileLookup::Moved(Box::new(Redirect::permanent(
+                format!(
+                    "{}://{}/users/{}",
+                    email::url_scheme(),
+                    new_instance,
+                    new_username
+                ),
+            ))))
+        }
+        None => {
+            http_code_elog!(ev_log, 200, "/users");
+            let profile = PeerProfile {
+                id: row.get(0),
+                username: row.get(1),
+                instance: own_instance(),
+            };
+            Some(ProfileLookup::Found(RawJson(
+                serde_json::to_string(&profile).ok()?,
+            )))
+        }
+    }
+}
+
+/// Ask a peer about one of its accounts. Redirects aren't followed, as they are the answer.
+pub(crate) async fn lookup_peer_account(
+    instance: &str,
+    username: &str,
+) -> Result<PeerAccount, LuminaError> {
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(std::time::Duration::from_secs(PEER_TIMEOUT_SECS))
+        .build()?;
+    let response = client
+        .get(format!(
+            "{}://{}/users/{}",
+            email::url_scheme(),
+            instance,
+            username
+        ))
+        .send()
+        .await?;
+    if response.status().is_success() {
+        return Ok(PeerAccount::Present);
+    }
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(PeerAccount::Missing);
+    }
+    // The redirect of `profile` above: scheme://instance/users/username
+    response
+        .headers()
+        .get(reqwest::header::LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .and_then(|location| location.split_once("://"))
+        .and_then(|(_, rest)| rest.split_once("/users/"))
+        .map(|(new_instance, new_username)| {
+            PeerAccount::Moved(format!("{}@{}", new_username, new_instance))
+        })
+        .ok_or(LuminaError::MigrationPeerUnexpected)
+}
+
+/// Read an export archive back into its contents and the media files, by object id.
+pub(crate) fn read_archive(
+    archive: &[u8],
+) -> Result<(AccountExport, HashMap<String, Vec<u8>>), LuminaError> {
+    let invalid = |_| LuminaError::MigrationArchiveInvalid;
+    let mut export = None;
+    let mut media = HashMap::new();
+    let mut archive = tar::Archive::new(archive);
+    for entry in archive.entries().map_err(invalid)? {
+        let mut entry = entry.map_err(invalid)?;
+        let path = entry.path().map_err(invalid)?.into_owned();
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents).map_err(invalid)?;
+        if path == Path::new("account.json") {
+            export = Some(
+                serde_json::from_slice(&contents)
+                    .map_err(|_| LuminaError::MigrationArchiveInvalid)?,
+            );
+        } else if let Ok(object_id) = path.strip_prefix("media") {
+            media.insert(object_id.display().to_string(), contents);
+        }
+    }
+    Ok((export.ok_or(LuminaError::MigrationArchiveInvalid)?, media))
+}
+
+/// What an import brought over.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ImportSummary {
+    pub(crate) posts: usize,
+    /// Posts left out because a filter rule rejects them.
+    pub(crate) rejected: usize,
+    pub(crate) follows: usize,
+}
+
+/// Import the export archive of an account on another instance into `user`.
+///
+/// The other instance has to be one this instance syncs with, and has to redirect the exported
+/// account to `user`, which it does once its owner moved it there. Importing the same archive
+/// twice doesn't duplicate posts, or their media files.
+pub(crate) async fn import_archive(
+    user: &User,
+    archive: Vec<u8>,
+    db: &DbConn,
+    ev_log: &EventLogger,
+) -> Result<ImportSummary, LuminaError> {
+    let (export, media) = tokio::task::spawn_blocking(move || read_archive(&archive))
+        .await
+        .map_err(|_| LuminaError::JoinFaillure)??;
+    let peers = allowed_peers();
+    let old_instance = export.instance.to_lowercase();
+    if !peers.contains(&old_instance) {
+        return Err(LuminaError::MigrationPeerNotAllowed);
+    }
+    let own_address = format!("{}@{}", user.username, own_instance());
+    if lookup_peer_account(&old_instance, &export.profile.username).await?
+        != PeerAccount::Moved(own_address)
+    {
+        return Err(LuminaError::MigrationNotMoved);
+    }
+
+    // Followees may have moved as well, maybe even here. They are looked up before the
+    // transaction starts, so it isn't held open while waiting on the old instance.
+    let lookups = join_all(
+        export
+            .following
+            .iter()
+            .map(|followee| lookup_peer_account(&old_instance, followee)),
+    )
+    .await;
+    let followees: Vec<(String, String)> = export
+        .following
+        .iter()
+        .zip(lookups)
+        .filter_map(|(followee, lookup)| match lookup {
+            Ok(PeerAccount::Present) => Some((followee.clone(), old_instance.clone())),
+            Ok(PeerAccount::Moved(address)) => parse_address(&address)
+                .map(|(username, instance)| (username.to_string(), instance.to_lowercase())),
+            Ok(PeerAccount::Missing) | Err(_) => None,
+        })
+        .collect();
+
+    // Media files of new posts are written as the posts go in, under new object ids. Until the
+    // posts are committed, they are removed again if anything fails.
+    tokio::fs::create_dir_all(MEDIA_DIR).await?;
+    let mut written = Vec::new();
+    let outcome: Result<ImportSummary, LuminaError> = async {
+        let mut summary = ImportSummary::default();
+        match db {
+            DbConn::PgsqlConnection(pg_pool, _) => {
+                let mut client = pg_pool.get().await?;
+                let transaction = client.transaction().await?;
+                for post in &export.posts {
+                    // Posts coming in are held to the same filter rules as posts written here.
+                    let verdict = filters::check(&match post {
+                        ExportedPost::Text { content, .. } => content.clone(),
+                        ExportedPost::Media { caption, .. } => caption.clone().unwrap_or_default(),
+                        ExportedPost::Article { title, content, .. } => {
+                            format!("{}\n{}", title, content)
+                        }
+                    });
+                    if let Some((FilterAction::Reject, _)) = verdict {
+                        summary.rejected += 1;
+                        continue;
+                    }
+                    let sensitive = matches!(verdict, Some((FilterAction::MarkSensitive, _)));
+                    let imported = match post {
+                        ExportedPost::Text {
+                            id,
+                            created_at,
+                            content,
+                        } => {
+                            transaction
+                                .query_opt(
+                                    "INSERT INTO post_text (author_id, content, created_at, foreign_instance_id, foreign_post_id, sensitive) SELECT $1, $2, to_timestamp($3), $4::VARCHAR, $5::VARCHAR, $6 WHERE NOT EXISTS (SELECT 1 FROM post_text WHERE author_id = $1 AND foreign_instance_id = $4 AND foreign_post_id = $5) RETURNING id",
+                                    &[&user.id, content, &(*created_at as f64), &old_instance, &id.to_string(), &sensitive],
+                                )
+                                .await?
+                        }
+                        ExportedPost::Media {
+                            id,
+                            created_at,
+                            caption,
+                            object_id,
+                            ..
+                        } => {
+                            // Without the file, the post can only refer to it on the old instance.
+                            let file = media
+                                .get(object_id)
+                                .map(|contents| (Uuid::new_v4().to_string(), contents));
+                            let imported = transaction
+                                .query_opt(
+                                    "INSERT INTO post_media (author_id, minio_object_id, caption, created_at, foreign_instance_id, foreign_post_id, sensitive) SELECT $1, $2, $3, to_timestamp($4), $5::VARCHAR, $6::VARCHAR, $7 WHERE NOT EXISTS (SELECT 1 FROM post_media WHERE author_id = $1 AND foreign_instance_id = $5 AND foreign_post_id = $6) RETURNING id",
+                                    &[&user.id, file.as_ref().map_or(object_id, |(object_id, _)| object_id), caption, &(*created_at as f64), &old_instance, &id.to_string(), &sensitive],
+                                )
+                                .await?;
+                            // A post imported before already has its file.
+                            if imported.is_some()
+                                && let Some((object_id, contents)) = file
+                            {
+                                let path = Path::new(MEDIA_DIR).join(object_id);
+                                tokio::fs::write(&path, contents).await?;
+                                written.push(path);
+                            }
+                            imported
+                        }
+                        ExportedPost::Article {
+                            id,
+                            created_at,
+                            title,
+                            content,
+                        } => {
+                            transaction
+                                .query_opt(
+                                    "INSERT INTO post_article (author_id, title, content, created_at, foreign_instance_id, foreign_post_id, sensitive) SELECT $1, $2, $3, to_timestamp($4), $5::VARCHAR, $6::VARCHAR, $7 WHERE NOT EXISTS (SELECT 1 FROM post_article WHERE author_id = $1 AND foreign_instance_id = $5 AND foreign_post_id = $6) RETURNING id",
+                                    &[&user.id, title, content, &(*created_at as f64), &old_instance, &id.to_string(), &sensitive],
+                                )
+                                .await?
+                        }
+                    };
+                    if let Some(row) = imported {
+                        if let Some((FilterAction::Hold, rule_id)) = verdict {
+                            moderation::hold_for_review(&transaction, row.get(0), user.id, rule_id)
+                                .await?;
+                        }
+                        summary.posts += 1;
+                    }
+                }
+
+                for (username, instance) in &followees {
+                    let followed = if *instance == own_instance().to_lowercase() {
+                        transaction
+                            .execute(
+                                "INSERT INTO follows (follower_id, followee_id) SELECT $1, id FROM users WHERE username = $2 AND id != $1 AND NOT EXISTS (SELECT 1 FROM user_blocks WHERE (blocker_id = $1 AND blocked_id = users.id) OR (blocker_id = users.id AND blocked_id = $1)) ON CONFLICT DO NOTHING",
+                                &[&user.id, username],
+                            )
+                            .await?
+                    } else if peers.contains(instance) {
+                        transaction
+                            .execute(
+                                "INSERT INTO remote_follows (follower_id, instance, username) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+                                &[&user.id, instance, username],
+                            )
+                            .await?
+                    } else {
+                        0
+                    };
+                    summary.follows += followed as usize;
+                }
+                transaction.commit().await?;
+            }
+        }
+        Ok(summary)
+    }
+    .await;
+    if outcome.is_err() {
+        for path in written {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+    }
+    let summary = outcome?;
+    info_elog!(
+        ev_log,
+        "Imported {} posts ({} rejected by filter rules) and {} follows of {}@{} into {}.",
+        summary.posts,
+        summary.rejected,
+        summary.follows,
+        export.profile.username,
+        old_instance,
+        user.username
+    );
+    Ok(summary)
+}
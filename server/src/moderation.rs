@@ -0,0 +1,347 @@
+//! Lumina > Server > Moderation
+//!
+//! Reports by users and the moderation queue they make up.
+//!
+//! Any logged-in user can report a user or a post. Users with [`Permission::ModerateContent`] list
+//! the reports and resolve each by taking one of the [`ModerationAction`]s. Every action is written to the audit
+//! trail, the `MODERATION` entries in the logs table.
+
+/*
+ *     Lumina/Peonies
+ *     Copyright (C) 2018-2026 MLC 'Strawmelonjuice'  Bloeiman and contributors.
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::connections;
+use crate::database::DbConn;
+use crate::errors::LuminaError;
+use crate::helpers::events::EventLogger;
+use crate::moderation_elog;
+use crate::permissions::{self, Permission, Role};
+use crate::postgres::GenericClient;
+use crate::timeline;
+use crate::user::User;
+use cynthia_con::CynthiaColors;
+use uuid::Uuid;
+
+/// Why something was reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportReason {
+    Spam,
+    Harassment,
+    Hate,
+    Illegal,
+    Other,
+}
+
+impl ReportReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReportReason::Spam => "spam",
+            ReportReason::Harassment => "harassment",
+            ReportReason::Hate => "hate",
+            ReportReason::Illegal => "illegal",
+            ReportReason::Other => "other",
+        }
+    }
+}
+
+/// What is being reported.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ReportTarget {
+    User { username: String },
+    Post { post_id: Uuid },
+}
+
+/// The ways a moderator can resolve a report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ModerationAction {
+    /// Hide the reported post from everyone. It stays in place, so it can be looked at later.
+    HidePost,
+    /// Take the reported post out of every timeline it is on.
+    RemoveFromTimeline,
+    /// Show the reported post again after it was hidden, or held for review by a filter rule.
+    ReleasePost,
+    /// Suspend the reported user, or the author of the reported post, and end their sessions.
+    /// Without `days` the suspension doesn't run out.
+    SuspendUser {
+        #[serde(default)]
+        days: Option<i32>,
+    },
+    /// Close the report without doing anything.
+    Dismiss,
+}
+
+impl ModerationAction {
+    /// What ends up in `reports.resolution`.
+    fn as_str(&self) -> &'static str {
+        match self {
+            ModerationAction::HidePost => "hide_post",
+            ModerationAction::RemoveFromTimeline => "remove_from_timeline",
+            ModerationAction::ReleasePost => "release_post",
+            ModerationAction::SuspendUser { .. } => "suspend_user",
+            ModerationAction::Dismiss => "dismiss",
+        }
+    }
+}
+
+/// A report as moderators see it in the queue.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ReportInfo {
+    pub id: Uuid,
+    /// `None` once the reporter deleted their account, and for posts held by a filter rule.
+    pub reporter: Option<String>,
+    pub target_username: Option<String>,
+    pub target_item_id: Option<Uuid>,
+    /// One of the [`ReportReason`]s, or `filter` for posts held by a filter rule.
+    pub reason: String,
+    pub comment: String,
+    pub created_at: i64,
+    pub resolved_at: Option<i64>,
+    pub resolved_by: Option<String>,
+    pub resolution: Option<String>,
+}
+
+/// File a report, adding it to the moderation queue.
+pub(crate) async fn create_report(
+    reporter: &User,
+    target: ReportTarget,
+    reason: ReportReason,
+    comment: String,
+    db: &DbConn,
+) -> Result<(), LuminaError> {
+    let (target_user_id, target_item_id) = match target {
+        ReportTarget::User { username } => match User::get_user_by_identifier(username, db).await {
+            Ok(user) => (user.id, None),
+            Err(_) => return Err(LuminaError::ReportTargetNotFound),
+        },
+        ReportTarget::Post { post_id } => (post_author(post_id, db).await?, Some(post_id)),
+    };
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            client
+                .execute(
+                    "INSERT INTO reports (reporter_id, target_user_id, target_item_id, reason, comment) VALUES ($1, $2, $3, $4, $5)",
+                    &[
+                        &reporter.id,
+                        &target_user_id,
+                        &target_item_id,
+                        &reason.as_str(),
+                        &comment,
+                    ],
+                )
+                .await?;
+            Ok(())
+        }
+    }
+}
+
+/// Hide a post that a filter rule held for review, and put it in the moderation queue, where
+/// releasing it shows it. Takes a client so it can be part of the transaction adding the post.
+pub(crate) async fn hold_for_review(
+    client: &impl GenericClient,
+    post_id: Uuid,
+    author_id: Uuid,
+    rule_id: Uuid,
+) -> Result<(), LuminaError> {
+    client
+        .execute(
+            "INSERT INTO hidden_items (item_id) VALUES ($1) ON CONFLICT DO NOTHING",
+            &[&post_id],
+        )
+        .await?;
+    client
+        .execute(
+            "INSERT INTO reports (target_user_id, target_item_id, reason, comment) VALUES ($1, $2, 'filter', $3)",
+            &[&author_id, &post_id, &format!("Held for review by filter rule {}", rule_id)],
+        )
+        .await?;
+    Ok(())
+}
+
+/// The author of a post, of any kind.
+async fn post_author(post_id: Uuid, db: &DbConn) -> Result<Uuid, LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            client
+                .query_opt(
+                    "SELECT author_id FROM post_text WHERE id = $1 UNION ALL SELECT author_id FROM post_media WHERE id = $1 UNION ALL SELECT author_id FROM post_article WHERE id = $1",
+                    &[&post_id],
+                )
+                .await?
+                .map(|row| row.get(0))
+                .ok_or(LuminaError::ReportTargetNotFound)
+        }
+    }
+}
+
+/// The moderation queue, oldest first. Resolved reports are only included when asked for.
+pub(crate) async fn list_reports(
+    include_resolved: bool,
+    db: &DbConn,
+) -> Result<Vec<ReportInfo>, LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            let rows = client
+                .query(
+                    "SELECT reports.id, reporter.username, target.username, reports.target_item_id, reports.reason, reports.comment, EXTRACT(EPOCH FROM reports.created_at)::BIGINT, EXTRACT(EPOCH FROM reports.resolved_at)::BIGINT, resolver.username, reports.resolution FROM reports LEFT JOIN users AS reporter ON reporter.id = reports.reporter_id LEFT JOIN users AS target ON target.id = reports.target_user_id LEFT JOIN users AS resolver ON resolver.id = reports.resolved_by WHERE $1 OR reports.resolved_at IS NULL ORDER BY reports.created_at",
+                    &[&include_resolved],
+                )
+                .await?;
+            Ok(rows
+                .into_iter()
+                .map(|row| ReportInfo {
+                    id: row.get(0),
+                    reporter: row.get(1),
+                    target_username: row.get(2),
+                    target_item_id: row.get(3),
+                    reason: row.get(4),
+                    comment: row.get(5),
+                    created_at: row.get(6),
+                    resolved_at: row.get(7),
+                    resolved_by: row.get(8),
+                    resolution: row.get(9),
+                })
+                .collect())
+        }
+    }
+}
+
+/// Resolve a report by taking `action`, and write it to the audit trail.
+///
+/// Takes [`Permission::ModerateContent`], and [`Permission::SuspendUsers`] to suspend, which still
+/// doesn't allow suspending anyone whose role is at least the moderator's own.
+/// Suspending ends the sessions of the user and closes their open connections.
+pub(crate) async fn act(
+    moderator: &User,
+    report_id: Uuid,
+    action: ModerationAction,
+    db: &DbConn,
+    ev_log: EventLogger,
+) -> Result<(), LuminaError> {
+    if let ModerationAction::SuspendUser { days: Some(days) } = action
+        && days <= 0
+    {
+        return Err(LuminaError::SuspensionLengthInvalid);
+    }
+    let moderator_role =
+        permissions::ensure_permitted(moderator, Permission::ModerateContent, db).await?;
+    match db {
+        DbConn::PgsqlConnection(pg_pool, redis_pool) => {
+            let client = pg_pool.get().await?;
+            let report = client
+                .query_opt(
+                    "SELECT reports.target_user_id, reports.target_item_id, users.username, users.role FROM reports LEFT JOIN users ON users.id = reports.target_user_id WHERE reports.id = $1",
+                    &[&report_id],
+                )
+                .await?
+                .ok_or(LuminaError::ReportTargetNotFound)?;
+            let target_user_id: Option<Uuid> = report.get(0);
+            let target_item_id: Option<Uuid> = report.get(1);
+            let target_username: Option<String> = report.get(2);
+            let target_role = report.get::<_, Option<&str>>(3).map(Role::from_db);
+
+            match action {
+                ModerationAction::HidePost => {
+                    let item_id = target_item_id.ok_or(LuminaError::ReportTargetNotFound)?;
+                    client
+                        .execute(
+                            "INSERT INTO hidden_items (item_id, hidden_by) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                            &[&item_id, &moderator.id],
+                        )
+                        .await?;
+                }
+                ModerationAction::RemoveFromTimeline => {
+                    let item_id = target_item_id.ok_or(LuminaError::ReportTargetNotFound)?;
+                    let timeline_ids: Vec<Uuid> = client
+                        .query(
+                            "SELECT DISTINCT tlid FROM timelines WHERE item_id = $1",
+                            &[&item_id],
+                        )
+                        .await?
+                        .into_iter()
+                        .map(|row| row.get(0))
+                        .collect();
+                    for timeline_id in timeline_ids {
+                        timeline::remove_from_timeline(
+                            ev_log.clone(),
+                            db,
+                            &timeline_id.to_string(),
+                            &item_id.to_string(),
+                        )
+                        .await?;
+                    }
+                }
+                ModerationAction::ReleasePost => {
+                    let item_id = target_item_id.ok_or(LuminaError::ReportTargetNotFound)?;
+                    client
+                        .execute("DELETE FROM hidden_items WHERE item_id = $1", &[&item_id])
+                        .await?;
+                }
+                ModerationAction::SuspendUser { days } => {
+                    let (Some(user_id), Some(role)) = (target_user_id, target_role) else {
+                        return Err(LuminaError::ReportTargetNotFound);
+                    };
+                    permissions::ensure_permitted(moderator, Permission::SuspendUsers, db).await?;
+                    if role >= moderator_role {
+                        return Err(LuminaError::NotPermitted);
+                    }
+                    client
+                        .execute(
+                            "UPDATE users SET suspended_until = CASE WHEN $2::INT IS NULL THEN 'infinity' ELSE NOW() + make_interval(days => $2) END WHERE id = $1",
+                            &[&user_id, &days],
+                        )
+                        .await?;
+                    client
+                        .execute("DELETE FROM sessions WHERE user_id = $1", &[&user_id])
+                        .await?;
+                    connections::disconnect(redis_pool, user_id, None, "Account suspended").await;
+                }
+                ModerationAction::Dismiss => {}
+            }
+
+            client
+                .execute(
+                    "UPDATE reports SET resolved_at = NOW(), resolved_by = $2, resolution = $3 WHERE id = $1",
+                    &[&report_id, &moderator.id, &action.as_str()],
+                )
+                .await?;
+            moderation_elog!(
+                ev_log,
+                "{} resolved report {} about {}{} with {}{}",
+                moderator.username.clone().color_bright_cyan(),
+                report_id,
+                target_username.unwrap_or_else(|| "a deleted user".to_string()),
+                target_item_id
+                    .map(|id| format!(" (item {})", id))
+                    .unwrap_or_default(),
+                action.as_str(),
+                match action {
+                    ModerationAction::SuspendUser { days: Some(days) } =>
+                        format!(" for {} days", days),
+                    ModerationAction::SuspendUser { days: None } => " indefinitely".to_string(),
+                    _ => String::new(),
+                }
+            );
+            Ok(())
+        }
+    }
+}
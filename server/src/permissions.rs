@@ -0,0 +1,197 @@
+//! Lumina > Server > Permissions
+//!
+//! Roles and what they allow. Every user has one [`Role`], stored in `users.role`, and each role
+//! grants a set of [`Permission`]s. Handlers check for a permission, never for a role, with
+//! [`ensure_permitted`].
+//!
+//! There is no one to hand out the first admin role, so that is done with the `grant`
+//! subcommand. After that, admins can change roles from the client.
+
+/*
+ *     Lumina/Peonies
+ *     Copyright (C) 2018-2026 MLC 'Strawmelonjuice'  Bloeiman and contributors.
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::database::DbConn;
+use crate::errors::LuminaError;
+use crate::user::User;
+
+/// What a user may do beyond their own account. Each role may do everything the ones before it
+/// may.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    User,
+    Moderator,
+    Admin,
+}
+
+/// Privileged things a user can be allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// List reports, hide posts and take them out of timelines.
+    ModerateContent,
+    /// Suspend users with a lower role.
+    SuspendUsers,
+    /// Change the role of users.
+    ManageRoles,
+    /// Add and remove filter rules.
+    ManageFilters,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+
+    /// Unknown roles are treated as no role at all.
+    pub fn from_db(role: &str) -> Self {
+        match role {
+            "moderator" => Role::Moderator,
+            "admin" => Role::Admin,
+            _ => Role::User,
+        }
+    }
+
+    pub fn permits(&self, permission: Permission) -> bool {
+        match permission {
+            Permission::ModerateContent | Permission::SuspendUsers => *self >= Role::Moderator,
+            Permission::ManageRoles | Permission::ManageFilters => *self == Role::Admin,
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user" => Ok(Role::User),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Fails with [`LuminaError::NotPermitted`] unless `user` has `permission`. Returns the role of
+/// the user.
+///
+/// The role is read from the database, not taken from `user`, so a role taken away counts
+/// straight away, also on connections that were logged in before.
+pub(crate) async fn ensure_permitted(
+    user: &User,
+    permission: Permission,
+    db: &DbConn,
+) -> Result<Role, LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            let role = client
+                .query_opt("SELECT role FROM users WHERE id = $1", &[&user.id])
+                .await?
+                .map(|row| Role::from_db(row.get(0)))
+                .unwrap_or_default();
+            if role.permits(permission) {
+                Ok(role)
+            } else {
+                Err(LuminaError::NotPermitted)
+            }
+        }
+    }
+}
+
+/// Give the user named `username` a role, as `actor`. Like suspensions, only the roles of users
+/// below the role of the actor can be changed, and not to a role above it. Returns whether there
+/// is such a user.
+pub(crate) async fn change_role(
+    actor: &User,
+    username: &str,
+    role: Role,
+    db: &DbConn,
+) -> Result<bool, LuminaError> {
+    let actor_role = ensure_permitted(actor, Permission::ManageRoles, db).await?;
+    set_role(username, role, Some(actor_role), db).await
+}
+
+/// Give the user named `username` a role, from the command line. Returns whether there is such a
+/// user.
+pub(crate) async fn grant(username: &str, role: Role, db: &DbConn) -> Result<bool, LuminaError> {
+    set_role(username, role, None, db).await
+}
+
+/// Set the role of a user, if it is below `limit` and stays at or below it. Fails with
+/// [`LuminaError::LastAdmin`] rather than leave the instance without an admin.
+async fn set_role(
+    username: &str,
+    role: Role,
+    limit: Option<Role>,
+    db: &DbConn,
+) -> Result<bool, LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let mut client = pg_pool.get().await?;
+            let transaction = client.transaction().await?;
+            // Locked, so two admins can't take each other's role at the same time.
+            let admins = transaction
+                .query("SELECT id FROM users WHERE role = 'admin' FOR UPDATE", &[])
+                .await?
+                .len();
+            let Some(current) = transaction
+                .query_opt(
+                    "SELECT role FROM users WHERE username = $1 FOR UPDATE",
+                    &[&username],
+                )
+                .await?
+                .map(|row| Role::from_db(row.get(0)))
+            else {
+                return Ok(false);
+            };
+            if let Some(limit) = limit
+                && (current >= limit || role > limit)
+            {
+                return Err(LuminaError::NotPermitted);
+            }
+            if current == Role::Admin && role != Role::Admin && admins <= 1 {
+                return Err(LuminaError::LastAdmin);
+            }
+            transaction
+                .execute(
+                    "UPDATE users SET role = $2 WHERE username = $1",
+                    &[&username, &role.as_str()],
+                )
+                .await?;
+            transaction.commit().await?;
+            Ok(true)
+        }
+    }
+}
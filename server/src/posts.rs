@@ -0,0 +1,179 @@
+//! Lumina > Server > Posts
+//!
+//! Writing and viewing posts. Every new post is checked against the filter rules (see
+//! [`crate::filters`]) before it goes on the global timeline.
+
+/*
+ *     Lumina/Peonies
+ *     Copyright (C) 2018-2026 MLC 'Strawmelonjuice'  Bloeiman and contributors.
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::account_data;
+use crate::database::DbConn;
+use crate::errors::LuminaError;
+use crate::filters::{self, FilterAction};
+use crate::helpers::events::EventLogger;
+use crate::moderation;
+use crate::relationships;
+use crate::timeline::{self, GLOBAL_TIMELINE_ID};
+use crate::user::User;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use uuid::Uuid;
+
+/// How a new post came out of the filter rules.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PostCreated {
+    pub(crate) post_id: Uuid,
+    /// Hidden until a moderator releases it.
+    pub(crate) held: bool,
+    pub(crate) sensitive: bool,
+}
+
+/// Write a text post and put it on the global timeline. Fails with
+/// [`LuminaError::PostRejected`] if a filter rule rejects it.
+pub(crate) async fn create_text_post(
+    author: &User,
+    content: String,
+    db: &DbConn,
+    ev_log: EventLogger,
+) -> Result<PostCreated, LuminaError> {
+    author.ensure_may_post(db).await?;
+    let verdict = filters::check(&content);
+    if let Some((FilterAction::Reject, _)) = verdict {
+        return Err(LuminaError::PostRejected);
+    }
+    let sensitive = matches!(verdict, Some((FilterAction::MarkSensitive, _)));
+    let post_id = match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let mut client = pg_pool.get().await?;
+            let transaction = client.transaction().await?;
+            let post_id: Uuid = transaction
+                .query_one(
+                    "INSERT INTO post_text (author_id, content, sensitive) VALUES ($1, $2, $3) RETURNING id",
+                    &[&author.id, &content, &sensitive],
+                )
+                .await?
+                .get(0);
+            if let Some((FilterAction::Hold, rule_id)) = verdict {
+                moderation::hold_for_review(&transaction, post_id, author.id, rule_id).await?;
+            }
+            transaction.commit().await?;
+            post_id
+        }
+    };
+    timeline::add_to_timeline(ev_log, db, GLOBAL_TIMELINE_ID, &post_id.to_string()).await?;
+    Ok(PostCreated {
+        post_id,
+        held: matches!(verdict, Some((FilterAction::Hold, _))),
+        sensitive,
+    })
+}
+
+/// A post as it is shown, of any kind.
+#[derive(Debug, Clone)]
+pub(crate) enum PostView {
+    Text {
+        post_id: Uuid,
+        source_instance: String,
+        content: String,
+        sensitive: bool,
+    },
+    Media {
+        post_id: Uuid,
+        source_instance: String,
+        description: String,
+        /// Base64 encoded media files, empty when the file isn't stored here.
+        medias: Vec<String>,
+        sensitive: bool,
+    },
+    Article {
+        post_id: Uuid,
+        source_instance: String,
+        title: String,
+        content: String,
+        timestamp: u64,
+        author_id: Uuid,
+        sensitive: bool,
+    },
+}
+
+/// Look up a post for `viewer`. Posts hidden from the viewer, by their blocks and mutes or by a
+/// moderator, are treated as if they don't exist.
+pub(crate) async fn view_post(
+    post_id: Uuid,
+    viewer: Option<Uuid>,
+    db: &DbConn,
+) -> Result<Option<PostView>, LuminaError> {
+    let visible = relationships::filter_hidden_posts(viewer, vec![post_id.to_string()], db).await?;
+    if visible.is_empty() {
+        return Ok(None);
+    }
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            if let Some(row) = client
+                .query_opt(
+                    "SELECT content, COALESCE(foreign_instance_id, 'local'), sensitive FROM post_text WHERE id = $1",
+                    &[&post_id],
+                )
+                .await?
+            {
+                return Ok(Some(PostView::Text {
+                    post_id,
+                    source_instance: row.get(1),
+                    content: row.get(0),
+                    sensitive: row.get(2),
+                }));
+            }
+            if let Some(row) = client
+                .query_opt(
+                    "SELECT minio_object_id, COALESCE(caption, ''), COALESCE(foreign_instance_id, 'local'), sensitive FROM post_media WHERE id = $1",
+                    &[&post_id],
+                )
+                .await?
+            {
+                let object_id: String = row.get(0);
+                let medias = match account_data::media_file(&object_id) {
+                    Some(path) => vec![STANDARD.encode(tokio::fs::read(path).await?)],
+                    None => vec![],
+                };
+                return Ok(Some(PostView::Media {
+                    post_id,
+                    source_instance: row.get(2),
+                    description: row.get(1),
+                    medias,
+                    sensitive: row.get(3),
+                }));
+            }
+            Ok(client
+                .query_opt(
+                    "SELECT title, content, EXTRACT(EPOCH FROM created_at)::BIGINT, author_id, COALESCE(foreign_instance_id, 'local'), sensitive FROM post_article WHERE id = $1",
+                    &[&post_id],
+                )
+                .await?
+                .map(|row| PostView::Article {
+                    post_id,
+                    source_instance: row.get(4),
+                    title: row.get(0),
+                    content: row.get(1),
+                    timestamp: row.get::<_, i64>(2) as u64,
+                    author_id: row.get(3),
+                    sensitive: row.get(5),
+                }))
+        }
+    }
+}
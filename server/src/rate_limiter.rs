@@ -1,6 +1,7 @@
 //! Lumina > Server > Rate Limiter
 //!
-//! This module implements a simple rate limiter using a token bucket algorithm.
+//! This module implements a simple rate limiter using a token bucket algorithm, with the
+//! buckets in memory or in Redis.
 
 /*
  *     Lumina/Peonies
@@ -20,12 +21,17 @@
  *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use crate::EnvVar::LUMINA_RATE_LIMITER;
+use crate::errors::LuminaError;
+use bb8::Pool;
+use bb8_redis::RedisConnectionManager;
 use rocket::State;
 use rocket::http::Status;
 use rocket::request::{FromRequest, Outcome, Request};
 use std::collections::HashMap;
 use std::net::IpAddr;
-use std::time::Instant;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 
 /// A request guard that enforces the rate limit. Add as a parameter to handlers
@@ -56,9 +62,14 @@ impl<'r> FromRequest<'r> for RateLimit {
     }
 }
 
-/// Simple token-bucket rate limiter keyed by string (IP address).
+/// Token-bucket rate limiter keyed by string (IP address).
+///
+/// Buckets are kept in memory, or with [`RateLimiter::shared`], in Redis, so that limits hold
+/// across restarts and are shared by every node. When Redis can't be reached, the in-memory
+/// buckets of this node take over until it can.
 pub struct RateLimiter {
-    inner: Mutex<HashMap<String, TokenBucket>>,
+    buckets: Mutex<Buckets>,
+    redis: Option<(Pool<RedisConnectionManager>, &'static str)>,
     refill_per_second: f64,
     capacity: f64,
 }
@@ -68,24 +79,131 @@ struct TokenBucket {
     last: Instant,
 }
 
+struct Buckets {
+    map: HashMap<String, TokenBucket>,
+    last_sweep: Instant,
+}
+
+/// How often the in-memory buckets are swept for ones that are full again.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most keys kept in memory. Past this, the buckets used longest ago are dropped even if they
+/// aren't full yet, which hands those keys a full bucket when they come back.
+pub(crate) const MAX_BUCKETS: usize = 100_000;
+
+/// Which limiter to use, set through `LUMINA_RATE_LIMITER`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Memory,
+    Redis,
+}
+
+impl Backend {
+    pub fn from_env() -> Result<Self, LuminaError> {
+        match std::env::var("LUMINA_RATE_LIMITER").as_deref() {
+            Err(_) | Ok("memory") => Ok(Backend::Memory),
+            Ok("redis") => Ok(Backend::Redis),
+            Ok(_) => Err(LuminaError::ConfInvalid(LUMINA_RATE_LIMITER)),
+        }
+    }
+}
+
+/// A token bucket in a Redis hash, taken from and refilled in one go. Time comes from Redis, so
+/// nodes with clocks that disagree still agree on the buckets. A bucket expires once it would be
+/// full again, since a missing bucket counts as full.
+///
+/// KEYS[1] is the bucket, ARGV[1] the refill per second and ARGV[2] the capacity. Returns 1 if
+/// a token was taken.
+static TOKEN_BUCKET: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r"
+local refill = tonumber(ARGV[1])
+local capacity = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'last')
+local tokens = tonumber(bucket[1]) or capacity
+local last = tonumber(bucket[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - last) / 1000 * refill)
+local allowed = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+    allowed = 1
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'last', tostring(now))
+redis.call('PEXPIRE', KEYS[1], math.ceil((capacity - tokens) / refill * 1000) + 1)
+return allowed
+",
+    )
+});
+
 impl RateLimiter {
     /// Create a new RateLimiter.
     /// refill_per_second: how many tokens are added per second
     /// capacity: maximum number of tokens stored
     pub fn new(refill_per_second: f64, capacity: f64) -> Self {
         Self {
-            inner: Mutex::new(HashMap::new()),
+            buckets: Mutex::new(Buckets {
+                map: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+            redis: None,
             refill_per_second,
             capacity,
         }
     }
 
+    /// Like [`RateLimiter::new`], with the buckets in Redis under `rate_limit:<name>:<key>`.
+    pub fn shared(
+        name: &'static str,
+        refill_per_second: f64,
+        capacity: f64,
+        redis_pool: Pool<RedisConnectionManager>,
+    ) -> Self {
+        Self {
+            redis: Some((redis_pool, name)),
+            ..Self::new(refill_per_second, capacity)
+        }
+    }
+
     /// Allow or deny a single event for the given key (usually an IP string).
     /// Returns true if allowed (consumes one token), false if rate limited.
     pub async fn allow(&self, key: &str) -> bool {
-        let mut map = self.inner.lock().await;
+        if let Some((redis_pool, name)) = &self.redis {
+            match self.allow_redis(redis_pool, name, key).await {
+                Ok(allowed) => return allowed,
+                // Limiting per node beats not limiting at all.
+                Err(_) => return self.allow_memory(key).await,
+            }
+        }
+        self.allow_memory(key).await
+    }
+
+    async fn allow_redis(
+        &self,
+        redis_pool: &Pool<RedisConnectionManager>,
+        name: &str,
+        key: &str,
+    ) -> Result<bool, LuminaError> {
+        let mut redis_conn = redis_pool.get().await?;
+        let allowed: i64 = TOKEN_BUCKET
+            .key(format!("rate_limit:{}:{}", name, key))
+            .arg(self.refill_per_second)
+            .arg(self.capacity)
+            .invoke_async(&mut *redis_conn)
+            .await?;
+        Ok(allowed == 1)
+    }
+
+    async fn allow_memory(&self, key: &str) -> bool {
+        let mut buckets = self.buckets.lock().await;
         let now = Instant::now();
-        let bucket = map.entry(key.to_string()).or_insert(TokenBucket {
+        if now.duration_since(buckets.last_sweep) >= SWEEP_INTERVAL
+            || buckets.map.len() >= MAX_BUCKETS
+        {
+            self.sweep(&mut buckets, now);
+        }
+        let bucket = buckets.map.entry(key.to_string()).or_insert(TokenBucket {
             tokens: self.capacity,
             last: now,
         });
@@ -104,6 +222,30 @@ impl RateLimiter {
         }
     }
 
+    /// Drop the buckets that are full again, which are no different from missing ones. If that
+    /// isn't enough, drop the ones used longest ago down to three quarters of [`MAX_BUCKETS`].
+    fn sweep(&self, buckets: &mut Buckets, now: Instant) {
+        buckets.last_sweep = now;
+        let refill_per_second = self.refill_per_second;
+        let capacity = self.capacity;
+        buckets.map.retain(|_, bucket| {
+            bucket.tokens + now.duration_since(bucket.last).as_secs_f64() * refill_per_second
+                < capacity
+        });
+        if buckets.map.len() >= MAX_BUCKETS {
+            let mut lasts: Vec<Instant> = buckets.map.values().map(|bucket| bucket.last).collect();
+            let keep = MAX_BUCKETS * 3 / 4;
+            let cutoff = *lasts.select_nth_unstable(buckets.map.len() - keep).1;
+            buckets.map.retain(|_, bucket| bucket.last > cutoff);
+        }
+    }
+
+    /// How many keys have a bucket in memory.
+    #[cfg(test)]
+    pub async fn tracked_keys(&self) -> usize {
+        self.buckets.lock().await.map.len()
+    }
+
     /// Convenience: accept an Option<IpAddr> and use a string key.
     pub async fn allow_ip(&self, ip: Option<IpAddr>) -> bool {
         let key = match ip {
@@ -129,6 +271,20 @@ impl AuthRateLimiter {
         AuthRateLimiter(RateLimiter::new(refill_per_second, capacity))
     }
 
+    /// An AuthRateLimiter with its buckets in Redis, see [`RateLimiter::shared`].
+    pub fn shared(
+        refill_per_second: f64,
+        capacity: f64,
+        redis_pool: Pool<RedisConnectionManager>,
+    ) -> Self {
+        AuthRateLimiter(RateLimiter::shared(
+            "auth",
+            refill_per_second,
+            capacity,
+            redis_pool,
+        ))
+    }
+
     /// Delegate allow_ip to the inner limiter.
     pub async fn allow_ip(&self, ip: Option<IpAddr>) -> bool {
         self.0.allow_ip(ip).await
@@ -143,6 +299,19 @@ impl GeneralRateLimiter {
         GeneralRateLimiter(RateLimiter::new(refill_per_second, capacity))
     }
 
+    pub fn shared(
+        refill_per_second: f64,
+        capacity: f64,
+        redis_pool: Pool<RedisConnectionManager>,
+    ) -> Self {
+        GeneralRateLimiter(RateLimiter::shared(
+            "general",
+            refill_per_second,
+            capacity,
+            redis_pool,
+        ))
+    }
+
     pub async fn allow_ip(&self, ip: Option<IpAddr>) -> bool {
         self.0.allow_ip(ip).await
     }
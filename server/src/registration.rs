@@ -0,0 +1,131 @@
+//! Lumina > Server > Registration
+//!
+//! Who may create an account on this instance. The mode is set with `LUMINA_REGISTRATION_MODE`
+//! and enforced by [`crate::user::User::create_user`]; invite codes and approvals are handed
+//! out by an admin through the `invite` and `approve` subcommands.
+
+/*
+ *     Lumina/Peonies
+ *     Copyright (C) 2018-2026 MLC 'Strawmelonjuice'  Bloeiman and contributors.
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::EnvVar::*;
+use crate::database::DbConn;
+use crate::errors::LuminaError;
+use crate::helpers::tokens;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationMode {
+    /// Anyone can register.
+    #[default]
+    Open,
+    /// Registering takes an invite code.
+    Invite,
+    /// Anyone can register, but can't log in until an admin approves the account.
+    Approval,
+    /// Nobody can register.
+    Closed,
+}
+
+impl RegistrationMode {
+    pub(crate) fn from_env() -> Result<Self, LuminaError> {
+        match std::env::var("LUMINA_REGISTRATION_MODE")
+            .unwrap_or(String::from("open"))
+            .to_lowercase()
+            .as_str()
+        {
+            "open" => Ok(RegistrationMode::Open),
+            "invite" => Ok(RegistrationMode::Invite),
+            "approval" => Ok(RegistrationMode::Approval),
+            "closed" => Ok(RegistrationMode::Closed),
+            _ => Err(LuminaError::ConfInvalid(LUMINA_REGISTRATION_MODE)),
+        }
+    }
+}
+
+/// Create an invite code that can be redeemed `max_uses` times, optionally only for a number of
+/// days. The code is only returned here; like session tokens, just its hash is stored.
+pub(crate) async fn create_invite(
+    max_uses: i32,
+    valid_for_days: Option<i32>,
+    db: &DbConn,
+) -> Result<String, LuminaError> {
+    let code = tokens::generate_token();
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            client
+                .execute(
+                    "INSERT INTO invite_codes (code_hash, max_uses, expires_at) VALUES ($1, $2, NOW() + make_interval(days => $3))",
+                    &[&tokens::hash_token(&code), &max_uses, &valid_for_days],
+                )
+                .await?;
+            Ok(code)
+        }
+    }
+}
+
+/// Use up one redemption of an invite code, as part of the transaction creating the account,
+/// so a registration that fails afterwards doesn't cost a use.
+pub(crate) async fn redeem_invite(
+    code: &str,
+    transaction: &crate::postgres::Transaction<'_>,
+) -> Result<(), LuminaError> {
+    let redeemed = transaction
+        .execute(
+            "UPDATE invite_codes SET uses = uses + 1 WHERE code_hash = $1 AND uses < max_uses AND (expires_at IS NULL OR expires_at > NOW())",
+            &[&tokens::hash_token(code)],
+        )
+        .await?;
+    if redeemed == 1 {
+        Ok(())
+    } else {
+        Err(LuminaError::RegisterInviteInvalid)
+    }
+}
+
+/// Let a pending account log in. Returns whether there was such a pending account.
+pub(crate) async fn approve(username: &str, db: &DbConn) -> Result<bool, LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            let approved = client
+                .execute(
+                    "UPDATE users SET pending_approval = FALSE WHERE username = $1 AND pending_approval",
+                    &[&username],
+                )
+                .await?;
+            Ok(approved == 1)
+        }
+    }
+}
+
+/// Usernames of the accounts waiting for approval.
+pub(crate) async fn pending(db: &DbConn) -> Result<Vec<String>, LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            let rows = client
+                .query(
+                    "SELECT username FROM users WHERE pending_approval ORDER BY username",
+                    &[],
+                )
+                .await?;
+            Ok(rows.into_iter().map(|row| row.get(0)).collect())
+        }
+    }
+}
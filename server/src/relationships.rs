@@ -0,0 +1,229 @@
+//! Lumina > Server > Relationships
+//!
+//! Blocks and mutes between users.
+//!
+//! A block works both ways: neither user can follow, message, reply to or react to the other,
+//! and neither sees the other's posts. A mute only hides the muted user's posts from the muter.
+//!
+//! Hiding happens when a timeline page is handed out, after it is read from the cache, so the
+//! cached pages stay shared between all viewers.
+
+/*
+ *     Lumina/Peonies
+ *     Copyright (C) 2018-2026 MLC 'Strawmelonjuice'  Bloeiman and contributors.
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::database::DbConn;
+use crate::errors::LuminaError;
+use crate::user::User;
+use uuid::Uuid;
+
+/// The two kinds of relationship a user can put on another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Relationship {
+    Block,
+    Mute,
+}
+
+/// Block or mute `target` for `user`. Blocking also ends follows in both directions.
+pub(crate) async fn add(
+    user: &User,
+    target: &User,
+    relationship: Relationship,
+    db: &DbConn,
+) -> Result<(), LuminaError> {
+    if user.id == target.id {
+        return Err(LuminaError::RelationshipWithSelf);
+    }
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let mut client = pg_pool.get().await?;
+            let transaction = client.transaction().await?;
+            match relationship {
+                Relationship::Block => {
+                    transaction
+                        .execute(
+                            "INSERT INTO user_blocks (blocker_id, blocked_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                            &[&user.id, &target.id],
+                        )
+                        .await?;
+                    transaction
+                        .execute(
+                            "DELETE FROM follows WHERE (follower_id = $1 AND followee_id = $2) OR (follower_id = $2 AND followee_id = $1)",
+                            &[&user.id, &target.id],
+                        )
+                        .await?;
+                }
+                Relationship::Mute => {
+                    transaction
+                        .execute(
+                            "INSERT INTO user_mutes (muter_id, muted_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                            &[&user.id, &target.id],
+                        )
+                        .await?;
+                }
+            }
+            transaction.commit().await?;
+            Ok(())
+        }
+    }
+}
+
+/// Lift a block or mute. Returns whether there was one.
+pub(crate) async fn remove(
+    user: &User,
+    target: &User,
+    relationship: Relationship,
+    db: &DbConn,
+) -> Result<bool, LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            let removed = client
+                .execute(
+                    match relationship {
+                        Relationship::Block => {
+                            "DELETE FROM user_blocks WHERE blocker_id = $1 AND blocked_id = $2"
+                        }
+                        Relationship::Mute => {
+                            "DELETE FROM user_mutes WHERE muter_id = $1 AND muted_id = $2"
+                        }
+                    },
+                    &[&user.id, &target.id],
+                )
+                .await?;
+            Ok(removed == 1)
+        }
+    }
+}
+
+/// Usernames of the users this user blocked or muted.
+pub(crate) async fn list(
+    user: &User,
+    relationship: Relationship,
+    db: &DbConn,
+) -> Result<Vec<String>, LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            let rows = client
+                .query(
+                    match relationship {
+                        Relationship::Block => {
+                            "SELECT users.username FROM user_blocks JOIN users ON users.id = user_blocks.blocked_id WHERE user_blocks.blocker_id = $1 ORDER BY users.username"
+                        }
+                        Relationship::Mute => {
+                            "SELECT users.username FROM user_mutes JOIN users ON users.id = user_mutes.muted_id WHERE user_mutes.muter_id = $1 ORDER BY users.username"
+                        }
+                    },
+                    &[&user.id],
+                )
+                .await?;
+            Ok(rows.into_iter().map(|row| row.get(0)).collect())
+        }
+    }
+}
+
+/// Fails with [`LuminaError::Blocked`] if either user blocked the other, for anything one user
+/// does to another: following, messaging, replying and reacting, and seeing whether the other
+/// is online.
+pub(crate) async fn ensure_may_interact(
+    user_id: Uuid,
+    other_id: Uuid,
+    db: &DbConn,
+) -> Result<(), LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            let blocked = client
+                .query_opt(
+                    "SELECT 1 FROM user_blocks WHERE (blocker_id = $1 AND blocked_id = $2) OR (blocker_id = $2 AND blocked_id = $1)",
+                    &[&user_id, &other_id],
+                )
+                .await?
+                .is_some();
+            if blocked {
+                Err(LuminaError::Blocked)
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Whether `recipient` should hear about something `actor` did. Not for anyone blocked either
+/// way or muted by the recipient, the same users whose posts are hidden.
+#[expect(dead_code, reason = "Will be used once there are notifications")]
+pub(crate) async fn may_notify(
+    recipient_id: Uuid,
+    actor_id: Uuid,
+    db: &DbConn,
+) -> Result<bool, LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            let hidden = client
+                .query_opt(
+                    &format!("SELECT 1 WHERE $2 IN ({})", HIDDEN_AUTHORS),
+                    &[&recipient_id, &actor_id],
+                )
+                .await?
+                .is_some();
+            Ok(!hidden)
+        }
+    }
+}
+
+/// The users whose posts are hidden from user `$1`.
+const HIDDEN_AUTHORS: &str = "SELECT muted_id FROM user_mutes WHERE muter_id = $1 UNION SELECT blocked_id FROM user_blocks WHERE blocker_id = $1 UNION SELECT blocker_id FROM user_blocks WHERE blocked_id = $1";
+
+/// Leave out the posts `viewer` shouldn't see from a page of post ids, keeping the order. Posts
+/// hidden by a moderator are left out for everyone, including viewers that aren't logged in.
+pub(crate) async fn filter_hidden_posts(
+    viewer: Option<Uuid>,
+    post_ids: Vec<String>,
+    db: &DbConn,
+) -> Result<Vec<String>, LuminaError> {
+    if post_ids.is_empty() {
+        return Ok(post_ids);
+    }
+    let ids: Vec<Uuid> = post_ids
+        .iter()
+        .filter_map(|id| Uuid::parse_str(id).ok())
+        .collect();
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            let hidden: Vec<String> = client
+                .query(
+                    &format!(
+                        "SELECT posts.id FROM (SELECT id, author_id FROM post_text UNION ALL SELECT id, author_id FROM post_media UNION ALL SELECT id, author_id FROM post_article) AS posts WHERE posts.id = ANY($2) AND (posts.author_id IN ({}) OR posts.id IN (SELECT item_id FROM hidden_items))",
+                        HIDDEN_AUTHORS
+                    ),
+                    &[&viewer, &ids],
+                )
+                .await?
+                .into_iter()
+                .map(|row| row.get::<_, Uuid>(0).to_string())
+                .collect();
+            Ok(post_ids
+                .into_iter()
+                .filter(|id| !hidden.contains(id))
+                .collect())
+        }
+    }
+}
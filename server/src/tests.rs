@@ -16,11 +16,44 @@
  *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use crate::database::{self, DatabaseConnections};
+use crate::account_data::{self, AccountExport, ExportedPost, ExportedProfile};
+use crate::api;
+use crate::client_communication::{self, ClientMessage, ClientType, ErrorCode, ServerMessage};
+use crate::database::{self, DatabaseConnections, DbConn};
+use crate::email;
 use crate::errors::LuminaError;
+use crate::filters::{CompiledFilters, FilterAction, FilterRule};
+use crate::helpers::events::EventLogger;
+use crate::helpers::{passwords, tokens};
+use crate::migration;
+use crate::moderation::{self, ModerationAction, ReportReason, ReportTarget};
+use crate::permissions::{self, Permission, Role};
+use crate::posts;
+use crate::rate_limiter;
+use crate::registration::{self, RegistrationMode};
+use crate::relationships::{self, Relationship};
 use crate::timeline;
+use crate::two_factor;
+use crate::user::{
+    self, AccountState, AuthenticationOutcome, OnRegisterPasswordNotValid, RegisterError,
+    RegisterErrorCode, RegisterField, SessionOrigin, User,
+};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
 use std::mem;
 
+mod connections;
+mod load;
+mod protocol;
+mod username_policy;
+
+/// A Redis pool that is never connected to, for what should keep working without Redis.
+fn offline_redis() -> bb8::Pool<bb8_redis::RedisConnectionManager> {
+    bb8::Pool::builder()
+        .connection_timeout(std::time::Duration::from_millis(10))
+        .build_unchecked(bb8_redis::RedisConnectionManager::new("redis://127.0.0.1:1").unwrap())
+}
+
 #[tokio::test]
 async fn test_database_setup() {
     let result = database::setup()
@@ -70,6 +103,21 @@ async fn test_redis_bloom_filter() {
         .unwrap_or(());
 }
 
+#[tokio::test]
+async fn test_redis_rate_limiter() {
+    let db = database::setup().await.expect("DB setup");
+    let key = format!("test-{}", uuid::Uuid::new_v4());
+    let limiter = rate_limiter::RateLimiter::shared("test", 0.01, 2.0, db.get_redis_pool());
+    assert!(limiter.allow(&key).await);
+    assert!(limiter.allow(&key).await);
+    assert!(!limiter.allow(&key).await);
+    // Another node sees the same bucket.
+    let other_node = rate_limiter::RateLimiter::shared("test", 0.01, 2.0, db.get_redis_pool());
+    assert!(!other_node.allow(&key).await);
+    // Nothing was kept in memory, Redis was there.
+    assert_eq!(limiter.tracked_keys().await, 0);
+}
+
 #[tokio::test]
 async fn test_timeline_invalidation() {
     let db = database::setup().await.expect("DB setup");
@@ -101,6 +149,1061 @@ async fn test_timeline_invalidation() {
     assert!(result.is_none(), "Cache should be invalidated");
 }
 
+#[tokio::test]
+async fn test_email_outbox_delivery() {
+    // Needs an SMTP catcher such as MailHog on the configured relay, like `local-devel-prep` starts.
+    let db = database::setup().await.expect("DB setup");
+    let config = email::MailerConfig::from_env().expect("Mailer config");
+    let transport = config.transport().expect("SMTP transport");
+    let from = config.sender().expect("Sender");
+    let recipient = format!("outbox-test-{}@example.com", uuid::Uuid::new_v4());
+    email::enqueue(&db.clone().into(), &recipient, "Outbox test", "Hello!")
+        .await
+        .expect("Enqueue");
+
+    email::drain_outbox_once(&db, &transport, &from, &EventLogger::new(&None))
+        .await
+        .expect("Drain outbox");
+
+    let pg_pool = db.get_postgres_pool();
+    let client = pg_pool.get().await.expect("Postgres conn");
+    let row = client
+        .query_one(
+            "SELECT sent_at IS NOT NULL, last_error FROM email_outbox WHERE recipient = $1",
+            &[&recipient],
+        )
+        .await
+        .expect("Outbox row");
+    let last_error: Option<String> = row.get(1);
+    assert!(
+        row.get::<_, bool>(0),
+        "Email should be sent: {:?}",
+        last_error
+    );
+}
+
+/// Register a throwaway account, for tests that need one in the database. Delete it with
+/// [`account_data::delete_account`] when done.
+async fn register_test_user(db: &DbConn, prefix: &str) -> User {
+    let username = format!(
+        "{prefix}{}",
+        &uuid::Uuid::new_v4().simple().to_string()[..8]
+    );
+    User::create_user(
+        format!("{username}@example.com"),
+        username,
+        "Test-password1".to_string(),
+        RegistrationMode::Open,
+        None,
+        db,
+    )
+    .await
+    .expect("Registering a test user")
+}
+
+#[tokio::test]
+async fn test_registration_modes() {
+    let db: DbConn = database::setup().await.expect("DB setup").into();
+    let ev_log = EventLogger::new(&None);
+    let origin = SessionOrigin {
+        user_agent: Some("registration test".to_string()),
+        ip: None,
+    };
+    let register = |prefix: &str, mode: RegistrationMode, invite_code: Option<String>| {
+        let username = format!(
+            "{prefix}{}",
+            &uuid::Uuid::new_v4().simple().to_string()[..8]
+        );
+        User::create_user(
+            format!("{username}@example.com"),
+            username,
+            "Test-password1".to_string(),
+            mode,
+            invite_code,
+            &db,
+        )
+    };
+    let mut accounts = vec![];
+
+    assert!(matches!(
+        register("closed", RegistrationMode::Closed, None).await,
+        Err(LuminaError::RegistrationClosed)
+    ));
+
+    // An invite works as often as it may be used, and not without one.
+    let code = registration::create_invite(2, None, &db).await.unwrap();
+    for _ in 0..2 {
+        accounts.push(
+            register("invited", RegistrationMode::Invite, Some(code.clone()))
+                .await
+                .expect("Registering with an invite"),
+        );
+    }
+    assert!(matches!(
+        register("invited", RegistrationMode::Invite, Some(code)).await,
+        Err(LuminaError::RegisterInviteInvalid)
+    ));
+    assert!(matches!(
+        register("invited", RegistrationMode::Invite, None).await,
+        Err(LuminaError::RegisterInviteInvalid)
+    ));
+
+    // Nor once it expired.
+    let code = registration::create_invite(5, Some(1), &db).await.unwrap();
+    let pg_pool = db.get_postgres_pool();
+    let client = pg_pool.get().await.expect("Postgres conn");
+    client
+        .execute(
+            "UPDATE invite_codes SET expires_at = NOW() - INTERVAL '1 minute' WHERE code_hash = $1",
+            &[&tokens::hash_token(&code)],
+        )
+        .await
+        .expect("Expiring an invite");
+    assert!(matches!(
+        register("invited", RegistrationMode::Invite, Some(code)).await,
+        Err(LuminaError::RegisterInviteInvalid)
+    ));
+
+    // Accounts that wait for approval can't log in until they get it.
+    let pending = register("approval", RegistrationMode::Approval, None)
+        .await
+        .expect("Registering for approval");
+    accounts.push(pending.clone());
+    assert_eq!(
+        pending.account_state(&db).await.unwrap(),
+        AccountState::PendingApproval
+    );
+    assert!(
+        registration::pending(&db)
+            .await
+            .unwrap()
+            .contains(&pending.username)
+    );
+    let log_in = || {
+        User::authenticate(
+            pending.username.clone(),
+            "Test-password1".to_string(),
+            &db,
+            ev_log.clone(),
+            &origin,
+        )
+    };
+    assert!(matches!(
+        log_in().await,
+        Err(LuminaError::AccountLocked(state)) if *state == AccountState::PendingApproval
+    ));
+    assert!(registration::approve(&pending.username, &db).await.unwrap());
+    assert!(!registration::approve(&pending.username, &db).await.unwrap());
+    assert!(matches!(
+        log_in().await,
+        Ok(AuthenticationOutcome::Authenticated(..))
+    ));
+
+    for account in accounts {
+        account_data::delete_account(account.id, &db, &ev_log)
+            .await
+            .expect("Deleting a test account");
+    }
+}
+
+/// The token in the last mail with this subject queued for `recipient`, from the link after
+/// `parameter=`.
+async fn mailed_token(db: &DbConn, recipient: &str, subject: &str, parameter: &str) -> String {
+    let pg_pool = db.get_postgres_pool();
+    let client = pg_pool.get().await.expect("Postgres conn");
+    let row = client
+        .query_one(
+            "SELECT body FROM email_outbox WHERE recipient = $1 AND subject = $2 ORDER BY created_at DESC LIMIT 1",
+            &[&recipient, &subject],
+        )
+        .await
+        .expect("Queued mail");
+    let body: String = row.get(0);
+    let (_, rest) = body
+        .split_once(&format!("{parameter}="))
+        .expect("A link with a token");
+    rest.split_whitespace().next().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn test_email_verification_and_reset() {
+    let db: DbConn = database::setup().await.expect("DB setup").into();
+    let ev_log = EventLogger::new(&None);
+    let user = register_test_user(&db, "verify").await;
+
+    // New accounts can't post until the link they were mailed is followed, once.
+    assert!(matches!(
+        user.ensure_may_post(&db).await,
+        Err(LuminaError::EmailNotVerified)
+    ));
+    let token = mailed_token(
+        &db,
+        &user.email,
+        "Confirm your Lumina account",
+        "verify_email",
+    )
+    .await;
+    let verified = User::verify_email(token.clone(), &db)
+        .await
+        .expect("Verifying with the mailed token");
+    assert_eq!(verified.id, user.id);
+    assert!(user.ensure_may_post(&db).await.is_ok());
+    assert!(matches!(
+        User::verify_email(token, &db).await,
+        Err(LuminaError::TokenInvalid)
+    ));
+
+    // Resetting the password ends every session.
+    let origin = SessionOrigin {
+        user_agent: Some("reset test".to_string()),
+        ip: None,
+    };
+    user.clone()
+        .create_session(&db, ev_log.clone(), &origin)
+        .await
+        .expect("Session");
+    assert_eq!(user.list_sessions(&db).await.unwrap().len(), 1);
+    User::request_password_reset(user.email.clone(), &db)
+        .await
+        .expect("Requesting a reset");
+    let token = mailed_token(
+        &db,
+        &user.email,
+        "Reset your Lumina password",
+        "reset_password",
+    )
+    .await;
+    User::reset_password(token.clone(), "New-password2".to_string(), &db)
+        .await
+        .expect("Resetting with the mailed token");
+    assert!(user.list_sessions(&db).await.unwrap().is_empty());
+    assert!(
+        User::reset_password(token, "Other-password3".to_string(), &db)
+            .await
+            .is_err()
+    );
+
+    account_data::delete_account(user.id, &db, &ev_log)
+        .await
+        .expect("Deleting a test account");
+}
+
+#[tokio::test]
+async fn test_session_expiry_and_revocation() {
+    let db: DbConn = database::setup().await.expect("DB setup").into();
+    let ev_log = EventLogger::new(&None);
+    let origin = SessionOrigin {
+        user_agent: Some("session test".to_string()),
+        ip: None,
+    };
+    let user = register_test_user(&db, "sessions").await;
+    let (stale, _) = user
+        .clone()
+        .create_session(&db, ev_log.clone(), &origin)
+        .await
+        .expect("Session");
+    let (fresh, _) = user
+        .clone()
+        .create_session(&db, ev_log.clone(), &origin)
+        .await
+        .expect("Session");
+
+    // A session left unused for too long is not revived, one in use slides along.
+    let pg_pool = db.get_postgres_pool();
+    let client = pg_pool.get().await.expect("Postgres conn");
+    client
+        .execute(
+            "UPDATE sessions SET last_used_at = NOW() - make_interval(days => $2 + 1) WHERE id = $1",
+            &[&stale.session_id, &user::SESSION_IDLE_EXPIRY_DAYS],
+        )
+        .await
+        .expect("Backdating a session");
+    assert!(
+        User::revive_session_from_token(stale.token.clone(), &db)
+            .await
+            .is_err()
+    );
+    let (revived, _) = User::revive_session_from_token(fresh.token.clone(), &db)
+        .await
+        .expect("A session in use should revive");
+    assert_eq!(revived.session_id, fresh.session_id);
+
+    // Revoking ends that session only, and only for its owner.
+    let other = register_test_user(&db, "sessions").await;
+    assert!(!other.revoke_session(fresh.session_id, &db).await.unwrap());
+    assert!(user.revoke_session(fresh.session_id, &db).await.unwrap());
+    assert!(!user.revoke_session(fresh.session_id, &db).await.unwrap());
+    assert!(
+        User::revive_session_from_token(fresh.token, &db)
+            .await
+            .is_err()
+    );
+    let sessions = user.list_sessions(&db).await.unwrap();
+    assert_eq!(
+        sessions.iter().map(|s| s.session_id).collect::<Vec<_>>(),
+        vec![stale.session_id]
+    );
+
+    // Logging out everywhere takes the rest.
+    assert_eq!(user.revoke_all_sessions(&db).await.unwrap(), 1);
+    assert!(user.list_sessions(&db).await.unwrap().is_empty());
+
+    for account in [user, other] {
+        account_data::delete_account(account.id, &db, &ev_log)
+            .await
+            .expect("Deleting a test account");
+    }
+}
+
+#[tokio::test]
+async fn test_blocks_and_mutes() {
+    let db: DbConn = database::setup().await.expect("DB setup").into();
+    let ev_log = EventLogger::new(&None);
+    let viewer = register_test_user(&db, "viewer").await;
+    let muted = register_test_user(&db, "muted").await;
+    let blocked = register_test_user(&db, "blocked").await;
+    let blocker = register_test_user(&db, "blocker").await;
+    let plain = register_test_user(&db, "plain").await;
+    let pg_pool = db.get_postgres_pool();
+    let client = pg_pool.get().await.expect("Postgres conn");
+    let ids = [viewer.id, muted.id, blocked.id, blocker.id, plain.id];
+    client
+        .execute(
+            "UPDATE users SET email_verified = TRUE WHERE id = ANY($1)",
+            &[&ids.to_vec()],
+        )
+        .await
+        .expect("Verifying the test accounts");
+
+    // Blocking ends follows both ways.
+    client
+        .execute(
+            "INSERT INTO follows (follower_id, followee_id) VALUES ($1, $2), ($2, $1)",
+            &[&viewer.id, &blocked.id],
+        )
+        .await
+        .expect("Following");
+    relationships::add(&viewer, &blocked, Relationship::Block, &db)
+        .await
+        .unwrap();
+    let follows: i64 = client
+        .query_one(
+            "SELECT COUNT(*) FROM follows WHERE (follower_id = $1 AND followee_id = $2) OR (follower_id = $2 AND followee_id = $1)",
+            &[&viewer.id, &blocked.id],
+        )
+        .await
+        .unwrap()
+        .get(0);
+    assert_eq!(follows, 0);
+    relationships::add(&viewer, &muted, Relationship::Mute, &db)
+        .await
+        .unwrap();
+    relationships::add(&blocker, &viewer, Relationship::Block, &db)
+        .await
+        .unwrap();
+
+    // A timeline busy enough to be cached, with a post of each.
+    let timeline_id = uuid::Uuid::new_v4().to_string();
+    let redis_pool = db.get_redis_pool();
+    let mut redis_conn = redis_pool.get().await.expect("Redis conn");
+    let _: () = redis::cmd("SET")
+        .arg(format!("timeline_lookup:{}", timeline_id))
+        .arg(timeline::HIGH_TRAFFIC_THRESHOLD)
+        .query_async(&mut *redis_conn)
+        .await
+        .expect("SET");
+    let mut post_ids = vec![];
+    for author in [&muted, &blocked, &blocker, &plain] {
+        let post = posts::create_text_post(author, "Hello".to_string(), &db, ev_log.clone())
+            .await
+            .expect("Posting");
+        timeline::add_to_timeline(ev_log.clone(), &db, &timeline_id, &post.post_id.to_string())
+            .await
+            .expect("Adding to the timeline");
+        post_ids.push(post.post_id.to_string());
+    }
+
+    // Filled into the cache by someone who sees everything...
+    let (seen, _, _) =
+        timeline::fetch_timeline_post_ids(ev_log.clone(), &db, &timeline_id, None, None)
+            .await
+            .unwrap();
+    assert_eq!(seen.len(), 4);
+    let cached: Option<String> = redis::cmd("GET")
+        .arg(format!("timeline_cache:{}:page:0", timeline_id))
+        .query_async(&mut *redis_conn)
+        .await
+        .unwrap();
+    assert!(cached.is_some(), "The page should be cached");
+    // ...and read from it by the viewer, who only sees the post of the user they left alone.
+    let (seen, total_count, _) =
+        timeline::fetch_timeline_post_ids(ev_log.clone(), &db, &timeline_id, None, Some(viewer.id))
+            .await
+            .unwrap();
+    assert_eq!(seen, vec![post_ids[3].clone()]);
+    assert_eq!(total_count, 4);
+
+    timeline::invalidate_timeline_cache(&mut redis_conn, &timeline_id)
+        .await
+        .expect("Invalidate cache");
+    let _: () = redis::cmd("DEL")
+        .arg(format!("timeline_lookup:{}", timeline_id))
+        .query_async(&mut *redis_conn)
+        .await
+        .unwrap_or(());
+    client
+        .execute(
+            "DELETE FROM timelines WHERE tlid = $1",
+            &[&uuid::Uuid::parse_str(&timeline_id).unwrap()],
+        )
+        .await
+        .expect("Removing the test timeline");
+    for account in [viewer, muted, blocked, blocker, plain] {
+        account_data::delete_account(account.id, &db, &ev_log)
+            .await
+            .expect("Deleting a test account");
+    }
+}
+
+#[tokio::test]
+async fn test_rate_limiter() {
+    let limiter = rate_limiter::RateLimiter::new(0.01, 2.0);
+    assert!(limiter.allow("a").await);
+    assert!(limiter.allow("a").await);
+    assert!(!limiter.allow("a").await);
+    assert!(limiter.allow("b").await);
+
+    // Without Redis to reach, a shared limiter still limits, per node.
+    let limiter = rate_limiter::RateLimiter::shared("test", 0.01, 1.0, offline_redis());
+    assert!(limiter.allow("a").await);
+    assert!(!limiter.allow("a").await);
+}
+
+#[tokio::test]
+async fn test_rate_limiter_eviction() {
+    // Buckets that are full again are dropped when the map fills up.
+    let limiter = rate_limiter::RateLimiter::new(1e9, 1.0);
+    for key in 0..rate_limiter::MAX_BUCKETS {
+        limiter.allow(&key.to_string()).await;
+    }
+    limiter.allow("one more").await;
+    assert_eq!(limiter.tracked_keys().await, 1);
+
+    // Buckets that never refill go by how long ago they were used.
+    let limiter = rate_limiter::RateLimiter::new(0.0, 1.0);
+    for key in 0..rate_limiter::MAX_BUCKETS {
+        limiter.allow(&key.to_string()).await;
+    }
+    assert_eq!(limiter.tracked_keys().await, rate_limiter::MAX_BUCKETS);
+    limiter.allow("one more").await;
+    assert!(limiter.tracked_keys().await <= rate_limiter::MAX_BUCKETS * 3 / 4 + 1);
+    // The oldest bucket is gone, so its key starts over with a full one. The newest is kept.
+    assert!(limiter.allow("0").await);
+    assert!(
+        !limiter
+            .allow(&(rate_limiter::MAX_BUCKETS - 1).to_string())
+            .await
+    );
+}
+
+#[test]
+fn test_token_hashing() {
+    let token = tokens::generate_token();
+    assert_ne!(token, tokens::generate_token(), "Tokens should be unique");
+    assert!(token.len() >= 43, "Tokens should carry at least 256 bits");
+    assert_eq!(
+        tokens::hash_token(&token),
+        tokens::hash_token(&token),
+        "Hashing should be deterministic, or sessions can't be looked up"
+    );
+    assert_ne!(
+        tokens::hash_token(&token),
+        token,
+        "The stored hash should not be the token"
+    );
+}
+
+#[tokio::test]
+async fn test_password_hashing() {
+    let password = String::from("MyTestPassw9292!");
+    let hash = passwords::hash_password(password.clone()).await.unwrap();
+    assert!(
+        hash.starts_with("$argon2id$"),
+        "New hashes should be Argon2id"
+    );
+    assert!(
+        passwords::verify_password(password.clone(), hash.clone())
+            .await
+            .unwrap()
+    );
+    assert!(
+        !passwords::verify_password(String::from("MyTestPassw9293!"), hash.clone())
+            .await
+            .unwrap()
+    );
+    assert!(!passwords::needs_rehash(&hash));
+
+    let legacy = bcrypt::hash(&password, 4).unwrap();
+    assert!(
+        passwords::verify_password(password, legacy.clone())
+            .await
+            .unwrap(),
+        "Existing bcrypt hashes should keep working"
+    );
+    assert!(
+        passwords::needs_rehash(&legacy),
+        "bcrypt hashes should be upgraded on login"
+    );
+}
+
+#[test]
+fn test_register_error_codes() {
+    let error = RegisterError::from(&LuminaError::RegisterPasswordNotValid(
+        OnRegisterPasswordNotValid::MissingNumber,
+    ));
+    assert_eq!(error.field, Some(RegisterField::Password));
+    let json = serde_json::to_value(&error).unwrap();
+    assert_eq!(json["field"], "password");
+    assert_eq!(json["code"], "password_missing_number");
+
+    let error = RegisterError::from(&LuminaError::Unknown);
+    assert_eq!(error.code, RegisterErrorCode::Internal);
+    assert_eq!(error.field, None);
+
+    // Clients from before the structured errors are told the way they understand.
+    let failure = || ServerMessage::RegisterFailure {
+        error: RegisterError::from(&LuminaError::RegisterEmailInUse),
+    };
+    assert!(matches!(
+        client_communication::for_protocol_version(failure(), 1),
+        ServerMessage::AuthFailure
+    ));
+    assert!(matches!(
+        client_communication::for_protocol_version(failure(), 2),
+        ServerMessage::RegisterFailure { .. }
+    ));
+    assert!(matches!(
+        client_communication::for_protocol_version(ServerMessage::Unauthenticated, 1),
+        ServerMessage::AuthFailure
+    ));
+    assert!(matches!(
+        client_communication::for_protocol_version(ServerMessage::Unauthenticated, 2),
+        ServerMessage::Unauthenticated
+    ));
+}
+
+#[test]
+fn test_recovery_codes() {
+    let code = two_factor::generate_recovery_code();
+    assert_eq!(code.len(), 11, "Recovery codes look like xxxxx-xxxxx");
+    assert_eq!(
+        two_factor::normalise_recovery_code(&code),
+        two_factor::normalise_recovery_code(&format!(" {} ", code.to_uppercase().replace('-', ""))),
+        "Case, dashes and surrounding space should not matter when typing a code back in"
+    );
+    assert_ne!(code, two_factor::generate_recovery_code());
+}
+
+/// The TOTP code for `secret`, `steps` steps away from now.
+fn totp_code(secret: &str, steps: i64) -> String {
+    let totp = totp_rs::TOTP::new_unchecked(
+        totp_rs::Algorithm::SHA1,
+        6,
+        0,
+        30,
+        totp_rs::Secret::Encoded(secret.to_string())
+            .to_bytes()
+            .unwrap(),
+        None,
+        String::new(),
+    );
+    let time = time::OffsetDateTime::now_utc().unix_timestamp() + steps * 30;
+    totp.generate(u64::try_from(time).unwrap())
+}
+
+#[tokio::test]
+async fn test_two_factor_verification() {
+    let db: DbConn = database::setup().await.expect("DB setup").into();
+    let ev_log = EventLogger::new(&None);
+    let origin = SessionOrigin {
+        user_agent: Some("two-factor test".to_string()),
+        ip: None,
+    };
+    let user = register_test_user(&db, "twofactor").await;
+    let (_, secret) = two_factor::begin_enrolment(&user, &db).await.unwrap();
+    let recovery_codes = two_factor::confirm_enrolment(&user, &totp_code(&secret, -1), &db)
+        .await
+        .expect("A code from the step before should confirm");
+
+    // One step either way is allowed for clock drift, but no step is accepted twice, nor one
+    // before the last that was.
+    assert!(
+        two_factor::verify(&user, &totp_code(&secret, 0), &db)
+            .await
+            .is_ok()
+    );
+    assert!(
+        two_factor::verify(&user, &totp_code(&secret, 0), &db)
+            .await
+            .is_err()
+    );
+    assert!(
+        two_factor::verify(&user, &totp_code(&secret, -1), &db)
+            .await
+            .is_err()
+    );
+    assert!(
+        two_factor::verify(&user, &totp_code(&secret, 1), &db)
+            .await
+            .is_ok()
+    );
+    assert!(
+        two_factor::verify(&user, &totp_code(&secret, 2), &db)
+            .await
+            .is_err()
+    );
+
+    // Recovery codes work once each, however they are typed.
+    assert!(
+        two_factor::verify(&user, &recovery_codes[0], &db)
+            .await
+            .is_ok()
+    );
+    assert!(
+        two_factor::verify(&user, &recovery_codes[0], &db)
+            .await
+            .is_err()
+    );
+    assert!(
+        two_factor::verify(&user, &recovery_codes[1].to_uppercase(), &db)
+            .await
+            .is_ok()
+    );
+
+    // A challenge is gone after a few wrong answers.
+    let challenge = two_factor::create_challenge(user.id, &db).await.unwrap();
+    for _ in 0..two_factor::CHALLENGE_MAX_FAILURES {
+        let result = User::complete_second_factor(
+            challenge.clone(),
+            "wrong".to_string(),
+            &db,
+            ev_log.clone(),
+            &origin,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(LuminaError::AuthenticationWrongSecondFactor)
+        ));
+    }
+    assert!(two_factor::challenge_user(&challenge, &db).await.is_err());
+
+    // Fresh challenges don't give more guesses than the account allows, not even right ones.
+    for _ in two_factor::CHALLENGE_MAX_FAILURES..two_factor::ACCOUNT_MAX_FAILURES {
+        let challenge = two_factor::create_challenge(user.id, &db).await.unwrap();
+        let _ = User::complete_second_factor(
+            challenge,
+            "wrong".to_string(),
+            &db,
+            ev_log.clone(),
+            &origin,
+        )
+        .await;
+    }
+    let challenge = two_factor::create_challenge(user.id, &db).await.unwrap();
+    let result = User::complete_second_factor(
+        challenge,
+        recovery_codes[2].clone(),
+        &db,
+        ev_log.clone(),
+        &origin,
+    )
+    .await;
+    assert!(matches!(
+        result,
+        Err(LuminaError::AuthenticationTooManyAttempts)
+    ));
+
+    account_data::delete_account(user.id, &db, &ev_log)
+        .await
+        .expect("Deleting a test account");
+}
+
+/// An export of testuser1, with a media post whose file is `media/object1`, and one whose file
+/// was missing.
+fn sample_export() -> AccountExport {
+    AccountExport {
+        exported_at: 1_700_000_000,
+        instance: "old.example".to_string(),
+        profile: ExportedProfile {
+            id: uuid::Uuid::new_v4(),
+            username: "testuser1".to_string(),
+            email: "test@lumina123.co".to_string(),
+            email_verified: true,
+            two_factor_enabled: false,
+        },
+        posts: vec![
+            ExportedPost::Text {
+                id: uuid::Uuid::new_v4(),
+                created_at: 1_600_000_000,
+                content: "Hello".to_string(),
+            },
+            ExportedPost::Media {
+                id: uuid::Uuid::new_v4(),
+                created_at: 1_600_000_001,
+                caption: Some("A strawmelon".to_string()),
+                object_id: "object1".to_string(),
+                file: Some("media/object1".to_string()),
+            },
+            ExportedPost::Media {
+                id: uuid::Uuid::new_v4(),
+                created_at: 1_600_000_002,
+                caption: None,
+                object_id: "object2".to_string(),
+                file: None,
+            },
+        ],
+        following: vec!["testuser2".to_string()],
+        followers: vec![],
+        sessions: vec![],
+    }
+}
+
+#[test]
+fn test_export_archive() {
+    let dir = std::env::temp_dir().join(format!("lumina-export-test-{}", uuid::Uuid::new_v4()));
+    let media_dir = dir.join("media");
+    std::fs::create_dir_all(&media_dir).unwrap();
+    std::fs::write(media_dir.join("object1"), b"not really a webp").unwrap();
+    let export = sample_export();
+    let path = dir.join("export.tar");
+    account_data::write_archive(&path, &export, &media_dir).unwrap();
+
+    let mut archive = tar::Archive::new(std::fs::File::open(&path).unwrap());
+    let mut entries = std::collections::BTreeMap::new();
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let mut contents = Vec::new();
+        std::io::Read::read_to_end(&mut entry, &mut contents).unwrap();
+        entries.insert(entry.path().unwrap().display().to_string(), contents);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(
+        entries.keys().collect::<Vec<_>>(),
+        vec!["account.json", "media/object1"],
+        "Media posts without a file should only be in the JSON"
+    );
+    assert_eq!(entries["media/object1"], b"not really a webp");
+    let json: serde_json::Value = serde_json::from_slice(&entries["account.json"]).unwrap();
+    assert_eq!(json["profile"]["username"], "testuser1");
+    assert_eq!(json["posts"][1]["kind"], "media");
+    assert_eq!(json["posts"][2]["object_id"], "object2");
+}
+
+#[test]
+fn test_migration_archive() {
+    let dir = std::env::temp_dir().join(format!("lumina-import-test-{}", uuid::Uuid::new_v4()));
+    let media_dir = dir.join("media");
+    std::fs::create_dir_all(&media_dir).unwrap();
+    std::fs::write(media_dir.join("object1"), b"not really a webp").unwrap();
+    let export = sample_export();
+    let path = dir.join("export.tar");
+    account_data::write_archive(&path, &export, &media_dir).unwrap();
+    let archive = std::fs::read(&path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let (read_back, media) = migration::read_archive(&archive).unwrap();
+    assert_eq!(read_back.instance, "old.example");
+    assert_eq!(read_back.profile.username, "testuser1");
+    assert_eq!(media["object1"], b"not really a webp");
+    assert!(matches!(
+        migration::read_archive(b"not an archive"),
+        Err(LuminaError::MigrationArchiveInvalid)
+    ));
+
+    assert_eq!(
+        migration::parse_address("testuser1@new.example"),
+        Some(("testuser1", "new.example"))
+    );
+    assert_eq!(migration::parse_address("testuser1"), None);
+    assert_eq!(migration::parse_address("@new.example"), None);
+    assert_eq!(
+        migration::parse_address("testuser1@new.example/users"),
+        None
+    );
+}
+
+#[test]
+fn test_roles_and_permissions() {
+    assert_eq!(Role::from_db("moderator"), Role::Moderator);
+    assert_eq!(Role::from_db("admin"), Role::Admin);
+    assert_eq!(Role::from_db("superuser"), Role::User);
+    assert_eq!("admin".parse::<Role>(), Ok(Role::Admin));
+    assert!("superuser".parse::<Role>().is_err());
+    assert!(!Role::User.permits(Permission::ModerateContent));
+    assert!(Role::Moderator.permits(Permission::ModerateContent));
+    assert!(Role::Moderator.permits(Permission::SuspendUsers));
+    assert!(!Role::Moderator.permits(Permission::ManageRoles));
+    assert!(Role::Admin.permits(Permission::ManageRoles));
+    assert!(Role::Admin > Role::Moderator);
+
+    let action: ModerationAction =
+        serde_json::from_str(r#"{"action": "suspend_user", "days": 7}"#).unwrap();
+    assert_eq!(action, ModerationAction::SuspendUser { days: Some(7) });
+    let action: ModerationAction = serde_json::from_str(r#"{"action": "suspend_user"}"#).unwrap();
+    assert_eq!(action, ModerationAction::SuspendUser { days: None });
+    let action: ModerationAction = serde_json::from_str(r#"{"action": "dismiss"}"#).unwrap();
+    assert_eq!(action, ModerationAction::Dismiss);
+}
+
+#[tokio::test]
+async fn test_role_changes() {
+    let db: DbConn = database::setup().await.expect("DB setup").into();
+    let ev_log = EventLogger::new(&None);
+    let admin = register_test_user(&db, "admin").await;
+    let other_admin = register_test_user(&db, "admin").await;
+    let moderator = register_test_user(&db, "moderator").await;
+    for (user, role) in [
+        (&admin, Role::Admin),
+        (&other_admin, Role::Admin),
+        (&moderator, Role::Moderator),
+    ] {
+        assert!(permissions::grant(&user.username, role, &db).await.unwrap());
+    }
+
+    // Admins change the roles of users below them...
+    assert!(
+        permissions::change_role(&admin, &moderator.username, Role::User, &db)
+            .await
+            .unwrap()
+    );
+    // ...but not of other admins, or their own.
+    for target in [&other_admin, &admin] {
+        assert!(matches!(
+            permissions::change_role(&admin, &target.username, Role::User, &db).await,
+            Err(LuminaError::NotPermitted)
+        ));
+    }
+    // Nor can anyone else change roles.
+    assert!(matches!(
+        permissions::change_role(&moderator, &admin.username, Role::User, &db).await,
+        Err(LuminaError::NotPermitted)
+    ));
+    assert!(
+        !permissions::change_role(&admin, "nobody at all", Role::User, &db)
+            .await
+            .unwrap()
+    );
+
+    for account in [admin, other_admin, moderator] {
+        account_data::delete_account(account.id, &db, &ev_log)
+            .await
+            .expect("Deleting a test account");
+    }
+}
+
+#[tokio::test]
+async fn test_reports_and_moderation() {
+    let db: DbConn = database::setup().await.expect("DB setup").into();
+    let ev_log = EventLogger::new(&None);
+    let moderator = register_test_user(&db, "moderator").await;
+    let other_moderator = register_test_user(&db, "moderator").await;
+    let author = register_test_user(&db, "author").await;
+    let reporter = register_test_user(&db, "reporter").await;
+    for user in [&moderator, &other_moderator] {
+        assert!(
+            permissions::grant(&user.username, Role::Moderator, &db)
+                .await
+                .unwrap()
+        );
+    }
+    let pg_pool = db.get_postgres_pool();
+    let client = pg_pool.get().await.expect("Postgres conn");
+    client
+        .execute(
+            "UPDATE users SET email_verified = TRUE WHERE id = $1",
+            &[&author.id],
+        )
+        .await
+        .expect("Verifying the author");
+    let post_id = posts::create_text_post(&author, "Hello".to_string(), &db, ev_log.clone())
+        .await
+        .expect("Posting")
+        .post_id;
+    let visible =
+        || relationships::filter_hidden_posts(Some(reporter.id), vec![post_id.to_string()], &db);
+    assert_eq!(visible().await.unwrap().len(), 1);
+
+    // The report of the post lands in the queue, where hiding and releasing it work.
+    moderation::create_report(
+        &reporter,
+        ReportTarget::Post { post_id },
+        ReportReason::Spam,
+        "Buy now".to_string(),
+        &db,
+    )
+    .await
+    .unwrap();
+    let report_id = moderation::list_reports(false, &db)
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|report| report.target_item_id == Some(post_id))
+        .expect("The report in the queue")
+        .id;
+    moderation::act(
+        &moderator,
+        report_id,
+        ModerationAction::HidePost,
+        &db,
+        ev_log.clone(),
+    )
+    .await
+    .unwrap();
+    assert!(visible().await.unwrap().is_empty());
+    moderation::act(
+        &moderator,
+        report_id,
+        ModerationAction::ReleasePost,
+        &db,
+        ev_log.clone(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(visible().await.unwrap().len(), 1);
+
+    // Suspensions are for at least a day, and only of users below the moderator.
+    let origin = SessionOrigin {
+        user_agent: Some("moderation test".to_string()),
+        ip: None,
+    };
+    author
+        .clone()
+        .create_session(&db, ev_log.clone(), &origin)
+        .await
+        .expect("Session");
+    for days in [0, -3] {
+        assert!(matches!(
+            moderation::act(
+                &moderator,
+                report_id,
+                ModerationAction::SuspendUser { days: Some(days) },
+                &db,
+                ev_log.clone(),
+            )
+            .await,
+            Err(LuminaError::SuspensionLengthInvalid)
+        ));
+    }
+    assert_eq!(author.list_sessions(&db).await.unwrap().len(), 1);
+    moderation::create_report(
+        &reporter,
+        ReportTarget::User {
+            username: other_moderator.username.clone(),
+        },
+        ReportReason::Harassment,
+        String::new(),
+        &db,
+    )
+    .await
+    .unwrap();
+    let peer_report_id = moderation::list_reports(false, &db)
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|report| report.target_username.as_deref() == Some(&other_moderator.username))
+        .expect("The report in the queue")
+        .id;
+    assert!(matches!(
+        moderation::act(
+            &moderator,
+            peer_report_id,
+            ModerationAction::SuspendUser { days: None },
+            &db,
+            ev_log.clone(),
+        )
+        .await,
+        Err(LuminaError::NotPermitted)
+    ));
+
+    // Suspending ends the sessions of the author.
+    moderation::act(
+        &moderator,
+        report_id,
+        ModerationAction::SuspendUser { days: Some(3) },
+        &db,
+        ev_log.clone(),
+    )
+    .await
+    .unwrap();
+    assert!(author.list_sessions(&db).await.unwrap().is_empty());
+    assert!(matches!(
+        author.account_state(&db).await.unwrap(),
+        AccountState::Suspended { until: Some(_) }
+    ));
+
+    for account in [moderator, other_moderator, author, reporter] {
+        account_data::delete_account(account.id, &db, &ev_log)
+            .await
+            .expect("Deleting a test account");
+    }
+}
+
+#[test]
+fn test_account_states() {
+    assert!(AccountState::Active.allows_login());
+    assert!(AccountState::PendingVerification.allows_login());
+    assert!(!AccountState::PendingApproval.allows_login());
+    assert!(!AccountState::Deactivated.allows_login());
+    let suspended = AccountState::Suspended {
+        until: Some(1_800_000_000),
+    };
+    assert!(!suspended.allows_login());
+    assert_eq!(
+        serde_json::to_value(suspended).unwrap(),
+        serde_json::json!({"state": "suspended", "until": 1_800_000_000})
+    );
+    assert_eq!(
+        serde_json::to_value(AccountState::Suspended { until: None }).unwrap(),
+        serde_json::json!({"state": "suspended", "until": null})
+    );
+}
+
+#[test]
+fn test_filter_rules() {
+    let rule = |pattern: &str, is_regex, action| FilterRule {
+        id: uuid::Uuid::new_v4(),
+        pattern: pattern.to_string(),
+        is_regex,
+        action,
+    };
+    let rules = vec![
+        rule("strawmelon", false, FilterAction::MarkSensitive),
+        rule("c.o", false, FilterAction::Reject),
+        rule(r"buy\s+now", true, FilterAction::Hold),
+    ];
+    let filters = CompiledFilters::compile(&rules).unwrap();
+    assert_eq!(filters.check("Hello world"), None);
+    // Keywords match whole words, ignoring case, and aren't regular expressions.
+    assert_eq!(
+        filters.check("I love StrawMelon juice"),
+        Some((FilterAction::MarkSensitive, rules[0].id))
+    );
+    assert_eq!(filters.check("strawmelons"), None);
+    assert_eq!(filters.check("cdo"), None);
+    assert_eq!(
+        filters.check("visit c.o"),
+        Some((FilterAction::Reject, rules[1].id))
+    );
+    // The strictest action wins.
+    assert_eq!(
+        filters.check("strawmelon, buy   now"),
+        Some((FilterAction::Hold, rules[2].id))
+    );
+    assert!(CompiledFilters::compile(&[rule("(", true, FilterAction::Reject)]).is_err());
+}
+
 #[test]
 fn print_sizes() {
     println!(
@@ -118,6 +1221,146 @@ fn print_sizes() {
     );
 }
 
+#[test]
+fn test_api_status_codes() {
+    use rocket::http::Status;
+    assert_eq!(
+        api::status_of(&ServerMessage::AuthFailure),
+        Status::Unauthorized
+    );
+    assert_eq!(
+        api::status_of(&ServerMessage::PermissionDenied),
+        Status::Forbidden
+    );
+    assert_eq!(
+        api::status_of(&ServerMessage::PostNotFound {
+            post_id: uuid::Uuid::nil()
+        }),
+        Status::NotFound
+    );
+    assert_eq!(
+        api::status_of(&ServerMessage::RegisterPendingApproval),
+        Status::Ok
+    );
+
+    // Post data sent by older servers has no `sensitive` field.
+    let msg: ServerMessage = serde_json::from_str(
+        r#"{"type": "data_textual_post", "post_id": "00000000-0000-0000-0000-000000000000", "source_instance": "local", "content": "Hi"}"#,
+    )
+    .unwrap();
+    assert!(matches!(
+        msg,
+        ServerMessage::TextPostDataSent {
+            sensitive: false,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn test_socket_settings() {
+    let defaults = client_communication::SocketSettings::default();
+    // The defaults must pass the same checks as configured values.
+    assert!(!defaults.ping_interval.is_zero());
+    assert!(defaults.ping_interval < defaults.idle_timeout);
+    // The largest archive the default is meant for has to fit, request and all.
+    let archive = STANDARD.encode(vec![0u8; client_communication::DEFAULT_IMPORT_ARCHIVE_SIZE]);
+    let request = serde_json::json!({
+        "type": "account_import_request",
+        "request_id": "1",
+        "archive": archive,
+    });
+    assert!(request.to_string().len() <= defaults.max_frame_size);
+    if [
+        "LUMINA_WS_PING_INTERVAL_SECS",
+        "LUMINA_WS_IDLE_TIMEOUT_SECS",
+        "LUMINA_WS_MAX_FRAME_KIB",
+    ]
+    .iter()
+    .all(|var| std::env::var(var).is_err())
+    {
+        assert_eq!(
+            client_communication::SocketSettings::from_env().unwrap(),
+            defaults
+        );
+    }
+}
+
+#[test]
+fn test_protocol_negotiation() {
+    // Clients from before versioning send no version or features.
+    let negotiated = client_communication::negotiate("web", None, &[], None).unwrap();
+    assert_eq!(negotiated.client_type, ClientType::Web);
+    assert_eq!(negotiated.protocol_version, 1);
+    assert!(negotiated.features.is_empty());
+
+    let features = vec!["two_factor".to_string(), "time_travel".to_string()];
+    let negotiated = client_communication::negotiate("web", Some(1), &features, None).unwrap();
+    assert_eq!(negotiated.features, vec!["two_factor".to_string()]);
+
+    let too_new = client_communication::MAX_PROTOCOL_VERSION + 1;
+    let why = client_communication::negotiate("web", Some(too_new), &[], None).unwrap_err();
+    assert!(why.contains("not supported"));
+    // Close frame reasons can't be longer than 123 bytes.
+    assert!(why.len() <= 123);
+    assert!(client_communication::negotiate("mobile", None, &[], None).is_err());
+    assert!(client_communication::negotiate("toaster", None, &[], None).is_err());
+    let long = "x".repeat(500);
+    let why = client_communication::negotiate(&long, None, &[], None).unwrap_err();
+    assert!(why.len() <= 123);
+    let why = client_communication::negotiate("web", None, &[], Some(&long)).unwrap_err();
+    assert!(why.len() <= 123);
+
+    let msg: ClientMessage = serde_json::from_str(
+        r#"{"type": "introduction", "client_kind": "web", "try_revive": null}"#,
+    )
+    .unwrap();
+    assert!(matches!(
+        msg,
+        ClientMessage::Introduction {
+            protocol_version: None,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn test_request_ids() {
+    let (request_id, msg) = client_communication::decode(
+        r#"{"type": "own_user_information_request", "request_id": "7"}"#,
+    );
+    assert_eq!(request_id.as_deref(), Some("7"));
+    assert!(matches!(msg, Ok(ClientMessage::OwnUserInformationRequest)));
+
+    // The id survives a message that can't be read.
+    let (request_id, msg) =
+        client_communication::decode(r#"{"type": "no_such_message", "request_id": "8"}"#);
+    assert_eq!(request_id.as_deref(), Some("8"));
+    assert!(msg.is_err());
+    let (request_id, msg) = client_communication::decode("not json");
+    assert_eq!(request_id, None);
+    assert!(msg.is_err());
+
+    let reply = client_communication::replytojson(
+        &Some("8".to_string()),
+        ServerMessage::ErrorResponse {
+            code: ErrorCode::InvalidMessage,
+            message: "What?".to_string(),
+        },
+    );
+    let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["request_id"], "8");
+    assert_eq!(reply["code"], "invalid_message");
+
+    // Without an id, replies look like they always have.
+    let reply = client_communication::replytojson(&None, ServerMessage::AuthFailure);
+    assert_eq!(
+        reply,
+        client_communication::msgtojson(ServerMessage::AuthFailure)
+    );
+}
+
 #[test]
 fn test_error_sizes() {
     // We want to keep our error types small to minimize overhead when passing them around.
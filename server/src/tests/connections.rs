@@ -0,0 +1,95 @@
+//! The registry has to reach every connection of a user, and only the connections a disconnect
+//! is meant for. Redis is never reached here, which pushes and disconnects shrug off.
+
+/*
+ *     Lumina/Peonies
+ *     Copyright (C) 2018-2026 MLC 'Strawmelonjuice'  Bloeiman and contributors.
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::offline_redis;
+use crate::client_communication::ServerMessage;
+use crate::connections::{self, Push};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use uuid::Uuid;
+
+/// Register a connection of the user, returning its id and what is pushed to it.
+fn connect(user_id: Uuid, session_id: Uuid) -> (Uuid, UnboundedReceiver<Push>) {
+    let (pusher, pushed) = mpsc::unbounded_channel();
+    let connection_id = Uuid::new_v4();
+    connections::registry().register(user_id, session_id, connection_id, pusher);
+    (connection_id, pushed)
+}
+
+#[test]
+fn test_registry() {
+    let user_id = Uuid::new_v4();
+    let (phone, _) = connect(user_id, Uuid::new_v4());
+    let (laptop, _) = connect(user_id, Uuid::new_v4());
+    assert_eq!(connections::registry().connection_count(user_id), 2);
+
+    connections::registry().unregister(user_id, phone);
+    assert_eq!(connections::registry().connection_count(user_id), 1);
+    // Unregistering twice does nothing.
+    connections::registry().unregister(user_id, phone);
+    connections::registry().unregister(user_id, laptop);
+    assert_eq!(connections::registry().connection_count(user_id), 0);
+}
+
+#[tokio::test]
+async fn test_push_to_other_devices() {
+    let redis_pool = offline_redis();
+    let user_id = Uuid::new_v4();
+    let (phone, mut phone_pushed) = connect(user_id, Uuid::new_v4());
+    let (_, mut laptop_pushed) = connect(user_id, Uuid::new_v4());
+    let (_, mut stranger_pushed) = connect(Uuid::new_v4(), Uuid::new_v4());
+
+    connections::push(&redis_pool, user_id, ServerMessage::LoggedOut, Some(phone)).await;
+    assert!(matches!(
+        laptop_pushed.try_recv(),
+        Ok(Push::Message {
+            message: ServerMessage::LoggedOut,
+            ..
+        })
+    ));
+    assert!(phone_pushed.try_recv().is_err());
+    assert!(stranger_pushed.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_disconnect_sessions() {
+    let redis_pool = offline_redis();
+    let user_id = Uuid::new_v4();
+    let revoked = Uuid::new_v4();
+    let (_, mut revoked_pushed) = connect(user_id, revoked);
+    let (_, mut kept_pushed) = connect(user_id, Uuid::new_v4());
+
+    connections::disconnect(&redis_pool, user_id, Some(revoked), "Session ended").await;
+    assert!(matches!(
+        revoked_pushed.try_recv(),
+        Ok(Push::Disconnect {
+            session_id: Some(session_id),
+            ..
+        }) if session_id == revoked
+    ));
+    assert!(kept_pushed.try_recv().is_err());
+
+    // Without a session, every connection of the user goes.
+    connections::disconnect(&redis_pool, user_id, None, "Account suspended").await;
+    assert!(matches!(
+        kept_pushed.try_recv(),
+        Ok(Push::Disconnect { reason, .. }) if reason == "Account suspended"
+    ));
+}
@@ -0,0 +1,178 @@
+//! Logins and timeline fetches from many connections at once have to run side by side, not one
+//! after another. Like the other tests that call `database::setup`, this needs Postgres and
+//! Redis.
+
+/*
+ *     Lumina/Peonies
+ *     Copyright (C) 2018-2026 MLC 'Strawmelonjuice'  Bloeiman and contributors.
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::account_data;
+use crate::client_communication::{
+    self, ClientMessage, HandlerContext, ServerMessage, SessionData,
+};
+use crate::database::{self, DatabaseConnections};
+use crate::helpers::events::EventLogger;
+use crate::rate_limiter::{AuthRateLimiter, GeneralRateLimiter};
+use crate::registration::RegistrationMode;
+use crate::user::{SessionOrigin, User};
+use crate::{InnerAppState, ServerConfig};
+use rocket::futures::future::join_all;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use uuid::Uuid;
+
+/// How many connections log in and fetch a timeline.
+const CONNECTIONS: usize = 16;
+
+const PASSWORD: &str = "Load-test-password1";
+
+/// What one connection does: log in, then fetch the global timeline.
+async fn log_in_and_fetch(username: &str, ctx: &HandlerContext<'_>) {
+    let mut conn = SessionData::new();
+    let replies = client_communication::dispatch(
+        ClientMessage::LoginAuthenticationRequest {
+            email_username: username.to_string(),
+            password: PASSWORD.to_string(),
+        },
+        &mut conn,
+        ctx,
+    )
+    .await
+    .unwrap();
+    assert!(
+        matches!(replies[..], [ServerMessage::AuthSuccess { .. }]),
+        "{replies:?}"
+    );
+    let replies = client_communication::dispatch(
+        ClientMessage::TimelineRequest {
+            by_name: "global".to_string(),
+            page: None,
+        },
+        &mut conn,
+        ctx,
+    )
+    .await
+    .unwrap();
+    assert!(
+        matches!(replies[..], [ServerMessage::TimelineResponse { .. }]),
+        "{replies:?}"
+    );
+}
+
+/// Register an account to log in to, returning its username.
+async fn register(ctx: &HandlerContext<'_>) -> (String, User) {
+    let username = format!("loadtest{}", &Uuid::new_v4().simple().to_string()[..8]);
+    let (reply, session) = client_communication::handle_register(
+        format!("{username}@example.com"),
+        username.clone(),
+        PASSWORD.to_string(),
+        None,
+        RegistrationMode::Open,
+        &ctx.appstate.db,
+        &ctx.ev_log,
+        &ctx.origin,
+    )
+    .await;
+    let (_, user) = session.unwrap_or_else(|| panic!("Registering failed: {reply:?}"));
+    (username, user)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_logins_and_timelines() {
+    let db = database::setup().await.expect("DB setup");
+    let appstate = Arc::new(InnerAppState {
+        config: ServerConfig {
+            port: 0,
+            host: [127, 0, 0, 1].into(),
+            registration_mode: RegistrationMode::Open,
+        },
+        db: db.into(),
+        event_logger: EventLogger::new(&None),
+    });
+    // Plenty of room, the rate limits aren't what is tested here.
+    let limiter = GeneralRateLimiter::new(1000.0, 1000.0);
+    let auth_limiter = AuthRateLimiter::new(1000.0, 1000.0);
+    let ctx = &HandlerContext {
+        appstate: appstate.clone(),
+        limiter: &limiter,
+        auth_limiter: &auth_limiter,
+        origin: SessionOrigin {
+            user_agent: Some("load test".to_string()),
+            ip: None,
+        },
+        ev_log: EventLogger::new(&None),
+    };
+    let (stuck_username, stuck_user) = register(ctx).await;
+    let (username, user) = register(ctx).await;
+
+    // Many connections at once all get their answers.
+    join_all((0..CONNECTIONS).map(|_| log_in_and_fetch(&username, ctx))).await;
+
+    // One connection waiting on the database doesn't hold up the others: hold a lock that the
+    // session a login inserts has to wait for, and log in on another account meanwhile.
+    let pg_pool = appstate.db.get_postgres_pool();
+    let mut held = pg_pool.get().await.expect("Postgres conn");
+    let transaction = held.transaction().await.unwrap();
+    transaction
+        .execute(
+            "SELECT 1 FROM users WHERE id = $1 FOR UPDATE",
+            &[&stuck_user.id],
+        )
+        .await
+        .unwrap();
+    let held_pid: i32 = transaction
+        .query_one("SELECT pg_backend_pid()", &[])
+        .await
+        .unwrap()
+        .get(0);
+    let stuck_done = &AtomicBool::new(false);
+    let stuck = async {
+        log_in_and_fetch(&stuck_username, ctx).await;
+        stuck_done.store(true, Ordering::SeqCst);
+    };
+    let watcher = pg_pool.get().await.expect("Postgres conn");
+    let meanwhile = async move {
+        loop {
+            let waiting: i64 = watcher
+                .query_one(
+                    "SELECT COUNT(*) FROM pg_stat_activity WHERE $1 = ANY(pg_blocking_pids(pid))",
+                    &[&held_pid],
+                )
+                .await
+                .unwrap()
+                .get(0);
+            if waiting > 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        log_in_and_fetch(&username, ctx).await;
+        assert!(
+            !stuck_done.load(Ordering::SeqCst),
+            "The login waiting on the lock should still be in flight"
+        );
+        transaction.rollback().await.unwrap();
+    };
+    tokio::join!(stuck, meanwhile);
+    assert!(stuck_done.load(Ordering::SeqCst));
+
+    for user in [stuck_user, user] {
+        account_data::delete_account(user.id, &appstate.db, &ctx.ev_log)
+            .await
+            .expect("Deleting the test account");
+    }
+}
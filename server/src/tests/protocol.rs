@@ -0,0 +1,422 @@
+//! Whatever a client sends, decoding it must not panic, and must either give a message to
+//! handle or a typed error to reply with. Dispatching it must refuse it when the connection
+//! doesn't have the access the message needs.
+
+/*
+ *     Lumina/Peonies
+ *     Copyright (C) 2018-2026 MLC 'Strawmelonjuice'  Bloeiman and contributors.
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use super::offline_redis;
+use crate::client_communication::{
+    self, Access, ClientMessage, Encoding, ErrorCode, HandlerContext, ServerMessage, SessionData,
+};
+use crate::database::DbConn;
+use crate::helpers::events::EventLogger;
+use crate::permissions::Role;
+use crate::rate_limiter::{AuthRateLimiter, GeneralRateLimiter};
+use crate::registration::RegistrationMode;
+use crate::user::{SessionOrigin, User};
+use crate::{InnerAppState, ServerConfig};
+use bb8::Pool;
+use bb8_postgres::PostgresConnectionManager;
+use proptest::prelude::*;
+use serde_json::{Map, Value};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Message types of both directions, and some that don't exist, so that generated messages
+/// often get past the `type` check.
+const TYPES: &[&str] = &[
+    "introduction",
+    "login_authentication_request",
+    "register_request",
+    "timeline_request",
+    "post_view_request",
+    "text_post_create_request",
+    "own_user_information_request",
+    "moderation_action_request",
+    "filter_rule_add_request",
+    "auth_success",
+    "auth_failure",
+    "greeting",
+    "timeline_response",
+    "error",
+    "unknown",
+    "client-init",
+    "",
+];
+
+/// Field names used by the messages, so generated messages sometimes fill them in.
+const FIELDS: &[&str] = &[
+    "request_id",
+    "client_kind",
+    "try_revive",
+    "protocol_version",
+    "features",
+    "encoding",
+    "email_username",
+    "password",
+    "by_name",
+    "page",
+    "post_id",
+    "content",
+    "action",
+    "report_id",
+    "token",
+];
+
+fn any_json() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::Bool),
+        any::<i64>().prop_map(Value::from),
+        any::<f64>().prop_map(Value::from),
+        ".*".prop_map(Value::String),
+        prop::sample::select(TYPES).prop_map(Value::from),
+    ];
+    leaf.prop_recursive(4, 32, 6, |inner| {
+        prop_oneof![
+            prop::collection::vec(inner.clone(), 0..6).prop_map(Value::Array),
+            prop::collection::vec(
+                (
+                    prop_oneof![prop::sample::select(FIELDS).prop_map(str::to_string), ".*"],
+                    inner
+                ),
+                0..6
+            )
+            .prop_map(|fields| Value::Object(fields.into_iter().collect::<Map<_, _>>())),
+        ]
+    })
+}
+
+/// An object with a message type, and any fields.
+fn any_message() -> impl Strategy<Value = Value> {
+    (
+        prop::sample::select(TYPES),
+        prop::collection::vec((prop::sample::select(FIELDS), any_json()), 0..6),
+    )
+        .prop_map(|(kind, fields)| {
+            let mut object: Map<_, _> = fields
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect();
+            object.insert("type".to_string(), Value::from(kind));
+            Value::Object(object)
+        })
+}
+
+/// What decoding `text` must give: a message to handle, or an error reply with the request id
+/// the text was tagged with.
+fn check_decode(text: &str) {
+    let (request_id, decoded) = client_communication::decode(text);
+    if let Ok(Value::Object(object)) = serde_json::from_str::<Value>(text) {
+        assert_eq!(
+            request_id.as_deref(),
+            object.get("request_id").and_then(Value::as_str)
+        );
+    }
+    if let Err(reply) = decoded {
+        assert!(matches!(reply, ServerMessage::ErrorResponse { .. }));
+        // The reply must be sendable.
+        serde_json::to_string(&reply).unwrap();
+    }
+}
+
+/// Decoding a message as MessagePack must give what decoding it as JSON gives.
+fn check_decode_msgpack(value: &Value) {
+    let bytes = rmp_serde::to_vec_named(value).unwrap();
+    let (request_id, decoded) = client_communication::decode_msgpack(&bytes);
+    let (json_request_id, json_decoded) = client_communication::decode(&value.to_string());
+    assert_eq!(request_id, json_request_id);
+    match (decoded, json_decoded) {
+        (Ok(msg), Ok(json_msg)) => assert_eq!(format!("{msg:?}"), format!("{json_msg:?}")),
+        (Err(reply), Err(_)) => assert!(matches!(reply, ServerMessage::ErrorResponse { .. })),
+        (decoded, json_decoded) => {
+            panic!("{decoded:?} from MessagePack, {json_decoded:?} from JSON")
+        }
+    }
+}
+
+proptest! {
+    #[test]
+    fn decode_any_text(text in ".*") {
+        check_decode(&text);
+    }
+
+    #[test]
+    fn decode_any_bytes(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+        let (_, decoded) = client_communication::decode_msgpack(&bytes);
+        if let Err(reply) = decoded {
+            prop_assert!(
+                matches!(reply, ServerMessage::ErrorResponse { .. }),
+                "{:?}",
+                reply
+            );
+        }
+    }
+
+    #[test]
+    fn decode_any_message_msgpack(value in any_message()) {
+        check_decode_msgpack(&value);
+    }
+
+    #[test]
+    fn decode_any_json(value in any_json()) {
+        check_decode(&value.to_string());
+    }
+
+    #[test]
+    fn decode_any_message(value in any_message()) {
+        check_decode(&value.to_string());
+    }
+}
+
+#[test]
+fn test_server_messages_are_refused() {
+    for msg in [
+        ServerMessage::AuthFailure,
+        ServerMessage::Greeting {
+            greeting: "Hi".to_string(),
+        },
+        ServerMessage::PermissionDenied,
+        ServerMessage::PostNotFound {
+            post_id: uuid::Uuid::nil(),
+        },
+    ] {
+        let (_, decoded) = client_communication::decode(&client_communication::msgtojson(msg));
+        assert!(matches!(
+            decoded,
+            Err(ServerMessage::ErrorResponse {
+                code: ErrorCode::UnexpectedMessage,
+                ..
+            })
+        ));
+    }
+    let (_, decoded) = client_communication::decode(r#"{"type": "unknown"}"#);
+    assert!(matches!(
+        decoded,
+        Err(ServerMessage::ErrorResponse {
+            code: ErrorCode::InvalidMessage,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_msgpack_replies() {
+    let msg = ServerMessage::PostNotFound {
+        post_id: uuid::Uuid::nil(),
+    };
+    let request_id = Some("7".to_string());
+    let ws::Message::Binary(bytes) = Encoding::Msgpack.encode(&request_id, msg.clone()) else {
+        panic!("MessagePack replies go in binary frames");
+    };
+    // The same keys as the JSON reply.
+    let map: std::collections::BTreeMap<String, serde::de::IgnoredAny> =
+        rmp_serde::from_slice(&bytes).unwrap();
+    assert_eq!(
+        map.keys().collect::<Vec<_>>(),
+        vec!["post_id", "request_id", "type"]
+    );
+    // Sent back, it is recognised as a message only the server sends.
+    let (echoed_id, decoded) = client_communication::decode_msgpack(&bytes);
+    assert_eq!(echoed_id, request_id);
+    assert!(matches!(
+        decoded,
+        Err(ServerMessage::ErrorResponse {
+            code: ErrorCode::UnexpectedMessage,
+            ..
+        })
+    ));
+
+    let ws::Message::Text(text) = Encoding::Json.encode(&request_id, msg) else {
+        panic!("JSON replies go in text frames");
+    };
+    assert!(text.contains(r#""request_id":"7""#));
+}
+
+/// Handler state with databases that are never connected to, for messages that are answered
+/// before they would be.
+fn offline_state() -> Arc<InnerAppState> {
+    let pg_manager = PostgresConnectionManager::new_from_stringlike(
+        "host=/nonexistent user=lumina",
+        tokio_postgres::NoTls,
+    )
+    .unwrap();
+    Arc::new(InnerAppState {
+        config: ServerConfig {
+            port: 0,
+            host: [127, 0, 0, 1].into(),
+            registration_mode: RegistrationMode::Open,
+        },
+        db: DbConn::PgsqlConnection(
+            Pool::builder()
+                .connection_timeout(Duration::from_millis(10))
+                .build_unchecked(pg_manager),
+            offline_redis(),
+        ),
+        event_logger: EventLogger::new(&None),
+    })
+}
+
+async fn dispatch_offline(
+    msg: ClientMessage,
+    conn: &mut SessionData,
+) -> Result<Vec<ServerMessage>, String> {
+    let limiter = GeneralRateLimiter::new(1.0, 10.0);
+    let auth_limiter = AuthRateLimiter::new(1.0, 10.0);
+    let ctx = HandlerContext {
+        appstate: offline_state(),
+        limiter: &limiter,
+        auth_limiter: &auth_limiter,
+        origin: SessionOrigin {
+            user_agent: None,
+            ip: None,
+        },
+        ev_log: EventLogger::new(&None),
+    };
+    client_communication::dispatch(msg, conn, &ctx).await
+}
+
+#[tokio::test]
+async fn test_dispatch_without_session() {
+    for text in [
+        r#"{"type": "own_user_information_request"}"#,
+        r#"{"type": "timeline_request", "by_name": "global", "page": 0}"#,
+        r#"{"type": "text_post_create_request", "content": "Hi"}"#,
+        r#"{"type": "filter_rule_list_request"}"#,
+        r#"{"type": "role_change_request", "username": "testuser1", "role": "admin"}"#,
+    ] {
+        let (_, msg) = client_communication::decode(text);
+        let msg = msg.unwrap();
+        assert_ne!(msg.access(), Access::Public);
+        let replies = dispatch_offline(msg, &mut SessionData::new())
+            .await
+            .unwrap();
+        assert!(matches!(replies[..], [ServerMessage::Unauthenticated]));
+    }
+
+    // Logging out without being logged in needs no database either.
+    let mut conn = SessionData::new();
+    let replies = dispatch_offline(ClientMessage::LogoutRequest, &mut conn)
+        .await
+        .unwrap();
+    assert!(matches!(replies[..], [ServerMessage::LoggedOut]));
+}
+
+#[tokio::test]
+async fn test_dispatch_always_replies() {
+    // Even when the database can't be reached, a logged-in request gets an answer to carry its
+    // request id.
+    let mut conn = SessionData::new();
+    conn.user = Some(User {
+        id: uuid::Uuid::new_v4(),
+        email: "offline@example.com".to_string(),
+        username: "offline".to_string(),
+        foreign_instance_id: String::new(),
+        role: Role::User,
+    });
+    let replies = dispatch_offline(ClientMessage::EmailVerificationResendRequest, &mut conn)
+        .await
+        .unwrap();
+    assert!(matches!(
+        replies[..],
+        [ServerMessage::ErrorResponse {
+            code: ErrorCode::Internal,
+            ..
+        }]
+    ));
+}
+
+#[tokio::test]
+async fn test_dispatch_introduction() {
+    let mut conn = SessionData::new();
+    let (_, msg) = client_communication::decode(
+        r#"{"type": "introduction", "client_kind": "web", "try_revive": null, "protocol_version": 2, "features": ["sessions"]}"#,
+    );
+    let replies = dispatch_offline(msg.unwrap(), &mut conn).await.unwrap();
+    assert!(matches!(
+        replies[..],
+        [
+            ServerMessage::IntroductionResponse { .. },
+            ServerMessage::Greeting { .. }
+        ]
+    ));
+    assert_eq!(conn.features, vec!["sessions".to_string()]);
+    assert_eq!(conn.encoding, Encoding::Json);
+
+    // Clients from before versioning only get the greeting they know.
+    let mut conn = SessionData::new();
+    let (_, msg) = client_communication::decode(
+        r#"{"type": "introduction", "client_kind": "web", "try_revive": null}"#,
+    );
+    let replies = dispatch_offline(msg.unwrap(), &mut conn).await.unwrap();
+    assert!(matches!(replies[..], [ServerMessage::Greeting { .. }]));
+    assert_eq!(conn.protocol_version, 1);
+
+    let mut conn = SessionData::new();
+    let (_, msg) = client_communication::decode(
+        r#"{"type": "introduction", "client_kind": "web", "try_revive": null, "protocol_version": 2, "encoding": "msgpack"}"#,
+    );
+    let replies = dispatch_offline(msg.unwrap(), &mut conn).await.unwrap();
+    assert!(matches!(
+        replies[0],
+        ServerMessage::IntroductionResponse {
+            encoding: Encoding::Msgpack,
+            ..
+        }
+    ));
+    assert_eq!(conn.encoding, Encoding::Msgpack);
+
+    let (_, msg) = client_communication::decode(
+        r#"{"type": "introduction", "client_kind": "web", "try_revive": null, "encoding": "cbor"}"#,
+    );
+    assert!(
+        dispatch_offline(msg.unwrap(), &mut SessionData::new())
+            .await
+            .is_err()
+    );
+
+    let (_, msg) = client_communication::decode(
+        r#"{"type": "introduction", "client_kind": "web", "try_revive": null, "protocol_version": 999}"#,
+    );
+    assert!(
+        dispatch_offline(msg.unwrap(), &mut SessionData::new())
+            .await
+            .is_err()
+    );
+}
+
+proptest! {
+    /// Whatever a client without a session sends, messages that need one are refused before
+    /// anything else happens.
+    #[test]
+    fn dispatch_any_message_without_session(value in any_message()) {
+        if let (_, Ok(msg)) = client_communication::decode(&value.to_string())
+            && msg.access() != Access::Public
+        {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            let replies = runtime
+                .block_on(dispatch_offline(msg, &mut SessionData::new()))
+                .unwrap();
+            prop_assert!(matches!(replies[..], [ServerMessage::Unauthenticated]));
+        }
+    }
+}
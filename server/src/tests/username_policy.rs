@@ -0,0 +1,126 @@
+/*
+ *     Lumina/Peonies
+ *     Copyright (C) 2018-2026 MLC 'Strawmelonjuice'  Bloeiman and contributors.
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::user::OnRegisterUsernameInvalid;
+use crate::username_policy::{self, UsernamePolicy};
+
+/// What the default policy should say about a username: `Ok` with the stored form, or the
+/// reason it's refused.
+const CASES: &[(&str, Result<&str, OnRegisterUsernameInvalid>)] = {
+    use OnRegisterUsernameInvalid::*;
+    &[
+        // Plain names, and the separators allowed in them.
+        ("testuser1", Ok("testuser1")),
+        ("straw_melon", Ok("straw_melon")),
+        ("straw-melon.juice", Ok("straw-melon.juice")),
+        // Non-Latin scripts are fine on their own, and in the usual combinations.
+        ("ピオニーズ", Ok("ピオニーズ")),
+        ("Łucja", Ok("Łucja")),
+        ("lumiタロウ", Ok("lumiタロウ")),
+        // Decomposed input is stored composed.
+        ("Lu\u{0063}\u{0301}ja", Ok("Lućja")),
+        // Surrounding whitespace is not part of the name.
+        ("  spaced  ", Ok("spaced")),
+        // Discriminators of the configured lengths.
+        ("melon#1234", Ok("melon#1234")),
+        ("melon#123456", Ok("melon#123456")),
+        // Length is counted in characters, not bytes.
+        ("ピオニ", Err(TooShort)),
+        (
+            "ピオニーズピオニーズピオニーズピオニーズ",
+            Ok("ピオニーズピオニーズピオニーズピオニーズ"),
+        ),
+        ("ピオニーズピオニーズピオニーズピオニーズピ", Err(TooLong)),
+        ("abc", Err(TooShort)),
+        ("abcdefghijklmnopqrstu", Err(TooLong)),
+        // Discriminators of other lengths, or with other characters.
+        ("melon#123", Err(InvalidDiscriminator)),
+        ("melon#12345", Err(InvalidDiscriminator)),
+        ("melon#12a4", Err(InvalidDiscriminator)),
+        ("melon#12#34", Err(InvalidDiscriminator)),
+        ("melon#", Err(InvalidDiscriminator)),
+        ("#1234", Err(InvalidCharacters)),
+        // Characters that aren't letters, digits or separators.
+        ("straw melon", Err(InvalidCharacters)),
+        ("straw@melon", Err(InvalidCharacters)),
+        ("straw/melon", Err(InvalidCharacters)),
+        ("straw\u{200b}melon", Err(InvalidCharacters)),
+        // Latin mixed with Cyrillic look-alikes.
+        ("strаwmelon", Err(InvalidCharacters)),
+        // Reserved names, in any case or disguise.
+        ("admin", Err(Reserved)),
+        ("Admin", Err(Reserved)),
+        ("ADMlN", Err(Reserved)),
+        ("admin#1234", Err(Reserved)),
+        ("global", Err(Reserved)),
+        ("connection", Err(Reserved)),
+    ]
+};
+
+#[test]
+fn test_username_policy_table() {
+    let policy = UsernamePolicy::default();
+    for (username, expected) in CASES {
+        assert_eq!(
+            policy.check(username).as_deref(),
+            expected.as_deref(),
+            "{:?}",
+            username
+        );
+    }
+}
+
+#[test]
+fn test_username_discriminators_configurable() {
+    let none = UsernamePolicy::new(vec![]);
+    assert!(matches!(
+        none.check("melon#1234"),
+        Err(OnRegisterUsernameInvalid::InvalidDiscriminator)
+    ));
+    assert!(none.check("melon").is_ok());
+
+    let two = UsernamePolicy::new(vec![2]);
+    assert!(two.check("melon#12").is_ok());
+    assert!(two.check("melon#1234").is_err());
+}
+
+#[test]
+fn test_username_skeletons() {
+    // Pairs that must not be able to exist side by side.
+    for (a, b) in [
+        ("strawmelon", "StrawMelon"),
+        ("strawmelon", "strawmeIon"),
+        ("strawmelon", "strаwmelon"),
+        ("lumina_fan", "ｌｕｍｉｎａ_fan"),
+        ("Lućja", "Lu\u{0063}\u{0301}ja"),
+        ("rnelon", "melon"),
+        ("ADMIN", "admin"),
+    ] {
+        assert_eq!(
+            username_policy::skeleton(a),
+            username_policy::skeleton(b),
+            "{:?} and {:?} should count as the same username",
+            a,
+            b
+        );
+    }
+    // And ones that should.
+    for (a, b) in [("strawmelon", "strawmelon2"), ("melon#1234", "melon#4321")] {
+        assert_ne!(username_policy::skeleton(a), username_policy::skeleton(b));
+    }
+}
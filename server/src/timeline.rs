@@ -22,7 +22,7 @@
 
 use crate::errors::{LuminaDbError, LuminaError};
 use crate::helpers::events::EventLogger;
-use crate::{DbConn, error_elog, info_elog, user};
+use crate::{DbConn, error_elog, info_elog, relationships, user};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -218,12 +218,16 @@ async fn fetch_timeline_from_db(
 }
 
 /// Fetch a paginated list of post IDs for a given timeline.
+/// Posts the viewer has hidden by blocking or muting, and posts hidden by moderators, are left
+/// out after the (shared) cache, so pages can come out shorter than [`TIMELINE_PAGE_SIZE`]. The
+/// total count includes them.
 /// Returns (post_ids, total_count, has_more_pages)
 pub async fn fetch_timeline_post_ids(
     event_logger: EventLogger,
     db: &DbConn,
     timeline_id: &str,
     page: Option<usize>,
+    viewer: Option<Uuid>,
 ) -> Result<(Vec<String>, usize, bool), LuminaError> {
     let page = page.unwrap_or(0);
     let offset = page * TIMELINE_PAGE_SIZE;
@@ -248,7 +252,8 @@ pub async fn fetch_timeline_post_ids(
             get_cached_timeline_page(&mut redis_conn, timeline_id, page).await?
     {
         let has_more = (page + 1) * TIMELINE_PAGE_SIZE < cached_page.total_count;
-        return Ok((cached_page.post_ids, cached_page.total_count, has_more));
+        let post_ids = relationships::filter_hidden_posts(viewer, cached_page.post_ids, db).await?;
+        return Ok((post_ids, cached_page.total_count, has_more));
     }
 
     // Cache miss or low-traffic timeline - fetch from database
@@ -298,6 +303,7 @@ pub async fn fetch_timeline_post_ids(
         }
 
         let has_more = (page + 1) * TIMELINE_PAGE_SIZE < total_count;
+        let post_ids = relationships::filter_hidden_posts(viewer, post_ids, db).await?;
         Ok((post_ids, total_count, has_more))
     } else {
         // Non-global, low-traffic timeline - return empty for now
@@ -326,7 +332,8 @@ pub async fn fetch_timeline_post_ids_by_timeline_name(
         let timeline_uuid =
             Uuid::parse_str(GLOBAL_TIMELINE_ID).map_err(|_| LuminaError::UUidError)?;
         let (post_ids, total_count, has_more) =
-            fetch_timeline_post_ids(event_logger, db, GLOBAL_TIMELINE_ID, page).await?;
+            fetch_timeline_post_ids(event_logger, db, GLOBAL_TIMELINE_ID, page, Some(user.id))
+                .await?;
         Ok((timeline_uuid, post_ids, total_count, has_more))
     } else {
         // Handle other timelines in the future
@@ -375,7 +382,6 @@ pub async fn add_to_timeline(
     Ok(())
 }
 
-#[expect(dead_code, reason = "Not used yet")]
 /// Remove a post from a timeline and invalidate cache if necessary
 pub async fn remove_from_timeline(
     event_logger: EventLogger,
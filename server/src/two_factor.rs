@@ -0,0 +1,419 @@
+//! Lumina > Server > Two-factor authentication
+//!
+//! Optional TOTP-based second factor for accounts, with single-use recovery codes for when the
+//! authenticator app is lost.
+//!
+//! Logging in with two-factor enabled is a two-step affair: a correct password gets the client
+//! a short-lived challenge (kept in Redis), and only answering that challenge with a valid
+//! code creates a session.
+
+/*
+ *     Lumina/Peonies
+ *     Copyright (C) 2018-2026 MLC 'Strawmelonjuice'  Bloeiman and contributors.
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::database::DbConn;
+use crate::errors::LuminaError;
+use crate::helpers::tokens;
+use crate::user::User;
+use rand::{Rng, RngCore};
+use totp_rs::{Algorithm, Secret, TOTP};
+use uuid::Uuid;
+
+/// Length of a TOTP step in seconds, as every authenticator app expects.
+const TOTP_STEP: u64 = 30;
+
+/// Number of digits in a TOTP code.
+const TOTP_DIGITS: usize = 6;
+
+/// Size of a TOTP secret in bytes. RFC 4226 recommends 160 bits.
+const TOTP_SECRET_BYTES: usize = 20;
+
+/// How many recovery codes are handed out when two-factor authentication is enabled.
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// How long a client has to answer a second-factor challenge, in seconds.
+const CHALLENGE_TTL: u64 = 300;
+
+/// How many wrong codes a challenge takes before it is dropped.
+pub(crate) const CHALLENGE_MAX_FAILURES: i64 = 3;
+
+/// How many wrong codes an account takes, over all of its challenges, before second factors
+/// are refused for the rest of [`ACCOUNT_FAILURE_WINDOW`].
+pub(crate) const ACCOUNT_MAX_FAILURES: i64 = 10;
+
+/// How long wrong codes count against an account, in seconds, from the first one.
+const ACCOUNT_FAILURE_WINDOW: u64 = 3600;
+
+/// Alphabet recovery codes are drawn from, leaving out look-alike characters.
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+fn totp(secret: &str, username: &str) -> Result<TOTP, LuminaError> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|_| LuminaError::AuthenticationWrongSecondFactor)?;
+    // No skew: `accept_totp` walks the neighbouring steps itself, to know which one matched.
+    Ok(TOTP::new_unchecked(
+        Algorithm::SHA1,
+        TOTP_DIGITS,
+        0,
+        TOTP_STEP,
+        secret,
+        Some(String::from("Lumina")),
+        username.to_string(),
+    ))
+}
+
+fn now() -> u64 {
+    u64::try_from(time::OffsetDateTime::now_utc().unix_timestamp()).unwrap_or(0)
+}
+
+/// Recovery codes are compared case-insensitively and without the dash.
+pub(crate) fn normalise_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+pub(crate) fn generate_recovery_code() -> String {
+    let mut rng = rand::rng();
+    // Sampled by range rather than by byte, as the alphabet doesn't divide 256 evenly.
+    let chars: String = (0..10)
+        .map(|_| RECOVERY_CODE_ALPHABET[rng.random_range(0..RECOVERY_CODE_ALPHABET.len())] as char)
+        .collect();
+    format!("{}-{}", &chars[..5], &chars[5..])
+}
+
+fn challenge_key(challenge: &str) -> String {
+    format!("two_factor_challenge:{}", tokens::hash_token(challenge))
+}
+
+/// Whether this user has a confirmed second factor.
+pub(crate) async fn is_enabled(user_id: Uuid, db: &DbConn) -> Result<bool, LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            Ok(client
+                .query_opt(
+                    "SELECT 1 FROM user_totp WHERE user_id = $1 AND confirmed_at IS NOT NULL",
+                    &[&user_id],
+                )
+                .await?
+                .is_some())
+        }
+    }
+}
+
+/// Start enrolling this user, replacing any earlier unfinished enrolment.
+/// Returns the provisioning URI for authenticator apps and the base32 secret for manual entry.
+pub(crate) async fn begin_enrolment(
+    user: &User,
+    db: &DbConn,
+) -> Result<(String, String), LuminaError> {
+    if is_enabled(user.id, db).await? {
+        return Err(LuminaError::TwoFactorAlreadyEnabled);
+    }
+    let mut bytes = [0u8; TOTP_SECRET_BYTES];
+    rand::rng().fill_bytes(&mut bytes);
+    let secret = Secret::Raw(bytes.to_vec()).to_encoded().to_string();
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            client
+                .execute(
+                    "INSERT INTO user_totp (user_id, secret) VALUES ($1, $2) ON CONFLICT (user_id) DO UPDATE SET secret = $2, confirmed_at = NULL, last_used_step = NULL",
+                    &[&user.id, &secret],
+                )
+                .await?;
+        }
+    }
+    Ok((totp(&secret, &user.username)?.get_url(), secret))
+}
+
+/// Finish enrolment by proving the authenticator app works. Returns the recovery codes, which
+/// are only ever shown this once.
+pub(crate) async fn confirm_enrolment(
+    user: &User,
+    code: &str,
+    db: &DbConn,
+) -> Result<Vec<String>, LuminaError> {
+    if is_enabled(user.id, db).await? {
+        return Err(LuminaError::TwoFactorAlreadyEnabled);
+    }
+    if !accept_totp(user, code, db).await? {
+        return Err(LuminaError::AuthenticationWrongSecondFactor);
+    }
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect();
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let mut client = pg_pool.get().await?;
+            let transaction = client.transaction().await?;
+            transaction
+                .execute(
+                    "UPDATE user_totp SET confirmed_at = NOW() WHERE user_id = $1",
+                    &[&user.id],
+                )
+                .await?;
+            transaction
+                .execute(
+                    "DELETE FROM user_recovery_codes WHERE user_id = $1",
+                    &[&user.id],
+                )
+                .await?;
+            for code in &recovery_codes {
+                transaction
+                    .execute(
+                        "INSERT INTO user_recovery_codes (user_id, code_hash) VALUES ($1, $2)",
+                        &[
+                            &user.id,
+                            &tokens::hash_token(&normalise_recovery_code(code)),
+                        ],
+                    )
+                    .await?;
+            }
+            transaction.commit().await?;
+        }
+    }
+    Ok(recovery_codes)
+}
+
+/// Check a TOTP code against the user's (possibly unconfirmed) secret.
+///
+/// Codes from one step before or after the current one are accepted for clock drift, but each
+/// step only once, so an observed code can't be replayed.
+async fn accept_totp(user: &User, code: &str, db: &DbConn) -> Result<bool, LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            let Some(row) = client
+                .query_opt(
+                    "SELECT secret FROM user_totp WHERE user_id = $1",
+                    &[&user.id],
+                )
+                .await?
+            else {
+                return Ok(false);
+            };
+            let secret: String = row.get(0);
+            let totp = totp(&secret, &user.username)?;
+            let code = code.trim();
+            let now = now();
+            let matched_step = [now.saturating_sub(TOTP_STEP), now, now + TOTP_STEP]
+                .into_iter()
+                .find(|t| totp.check(code, *t))
+                .map(|t| i64::try_from(t / TOTP_STEP).unwrap_or(i64::MAX));
+            let Some(step) = matched_step else {
+                return Ok(false);
+            };
+            let updated = client
+                .execute(
+                    "UPDATE user_totp SET last_used_step = $2 WHERE user_id = $1 AND (last_used_step IS NULL OR last_used_step < $2)",
+                    &[&user.id, &step],
+                )
+                .await?;
+            Ok(updated == 1)
+        }
+    }
+}
+
+/// Check a second factor: either a current TOTP code or an unused recovery code, which is used
+/// up by this.
+pub(crate) async fn verify(user: &User, code: &str, db: &DbConn) -> Result<(), LuminaError> {
+    if accept_totp(user, code, db).await? {
+        return Ok(());
+    }
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            let used = client
+                .execute(
+                    "DELETE FROM user_recovery_codes WHERE user_id = $1 AND code_hash = $2",
+                    &[
+                        &user.id,
+                        &tokens::hash_token(&normalise_recovery_code(code)),
+                    ],
+                )
+                .await?;
+            if used == 1 {
+                Ok(())
+            } else {
+                Err(LuminaError::AuthenticationWrongSecondFactor)
+            }
+        }
+    }
+}
+
+/// Remove the second factor and recovery codes of this user.
+pub(crate) async fn disable(user_id: Uuid, db: &DbConn) -> Result<(), LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(pg_pool, _) => {
+            let client = pg_pool.get().await?;
+            client
+                .execute("DELETE FROM user_totp WHERE user_id = $1", &[&user_id])
+                .await?;
+            client
+                .execute(
+                    "DELETE FROM user_recovery_codes WHERE user_id = $1",
+                    &[&user_id],
+                )
+                .await?;
+            Ok(())
+        }
+    }
+}
+
+/// Hand out a challenge for a user who got their password right, to be answered with a second
+/// factor.
+pub(crate) async fn create_challenge(user_id: Uuid, db: &DbConn) -> Result<String, LuminaError> {
+    let challenge = tokens::generate_token();
+    match db {
+        DbConn::PgsqlConnection(_, redis_pool) => {
+            let mut redis_conn = redis_pool.get().await?;
+            let _: () = redis::cmd("SETEX")
+                .arg(challenge_key(&challenge))
+                .arg(CHALLENGE_TTL)
+                .arg(user_id.to_string())
+                .query_async(&mut *redis_conn)
+                .await?;
+        }
+    }
+    Ok(challenge)
+}
+
+/// The user a still-valid challenge was handed out to.
+pub(crate) async fn challenge_user(challenge: &str, db: &DbConn) -> Result<Uuid, LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(_, redis_pool) => {
+            let mut redis_conn = redis_pool.get().await?;
+            let user_id: Option<String> = redis::cmd("GET")
+                .arg(challenge_key(challenge))
+                .query_async(&mut *redis_conn)
+                .await?;
+            user_id
+                .and_then(|id| Uuid::parse_str(&id).ok())
+                .ok_or(LuminaError::TokenInvalid)
+        }
+    }
+}
+
+/// Invalidate a challenge once it has been answered.
+pub(crate) async fn drop_challenge(challenge: &str, db: &DbConn) -> Result<(), LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(_, redis_pool) => {
+            let mut redis_conn = redis_pool.get().await?;
+            let _: () = redis::cmd("DEL")
+                .arg(challenge_key(challenge))
+                .arg(challenge_failures_key(challenge))
+                .query_async(&mut *redis_conn)
+                .await?;
+            Ok(())
+        }
+    }
+}
+
+fn challenge_failures_key(challenge: &str) -> String {
+    format!(
+        "two_factor_failures:challenge:{}",
+        tokens::hash_token(challenge)
+    )
+}
+
+fn account_failures_key(user_id: Uuid) -> String {
+    format!("two_factor_failures:user:{}", user_id)
+}
+
+/// Count up a failure under `key`, which runs out `ttl` seconds after the first one.
+async fn count_failure(
+    redis_conn: &mut redis::aio::MultiplexedConnection,
+    key: &str,
+    ttl: u64,
+) -> Result<i64, LuminaError> {
+    let failures: i64 = redis::cmd("INCR").arg(key).query_async(redis_conn).await?;
+    if failures == 1 {
+        let _: () = redis::cmd("EXPIRE")
+            .arg(key)
+            .arg(ttl)
+            .query_async(redis_conn)
+            .await?;
+    }
+    Ok(failures)
+}
+
+/// Refuse to check second factors for an account that got too many of them wrong lately, so
+/// that fresh challenges from other addresses don't give a password holder more guesses.
+pub(crate) async fn ensure_attempts_left(user_id: Uuid, db: &DbConn) -> Result<(), LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(_, redis_pool) => {
+            let mut redis_conn = redis_pool.get().await?;
+            let failures: Option<i64> = redis::cmd("GET")
+                .arg(account_failures_key(user_id))
+                .query_async(&mut *redis_conn)
+                .await?;
+            if failures.unwrap_or(0) >= ACCOUNT_MAX_FAILURES {
+                Err(LuminaError::AuthenticationTooManyAttempts)
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Count a wrong answer to a challenge against both the challenge and the account. A challenge
+/// answered wrong [`CHALLENGE_MAX_FAILURES`] times is dropped.
+pub(crate) async fn record_failure(
+    challenge: &str,
+    user_id: Uuid,
+    db: &DbConn,
+) -> Result<(), LuminaError> {
+    let challenge_failures = match db {
+        DbConn::PgsqlConnection(_, redis_pool) => {
+            let mut redis_conn = redis_pool.get().await?;
+            count_failure(
+                &mut redis_conn,
+                &account_failures_key(user_id),
+                ACCOUNT_FAILURE_WINDOW,
+            )
+            .await?;
+            count_failure(
+                &mut redis_conn,
+                &challenge_failures_key(challenge),
+                CHALLENGE_TTL,
+            )
+            .await?
+        }
+    };
+    if challenge_failures >= CHALLENGE_MAX_FAILURES {
+        drop_challenge(challenge, db).await?;
+    }
+    Ok(())
+}
+
+/// Forget the wrong answers of an account, once it got one right.
+pub(crate) async fn clear_failures(user_id: Uuid, db: &DbConn) -> Result<(), LuminaError> {
+    match db {
+        DbConn::PgsqlConnection(_, redis_pool) => {
+            let mut redis_conn = redis_pool.get().await?;
+            let _: () = redis::cmd("DEL")
+                .arg(account_failures_key(user_id))
+                .query_async(&mut *redis_conn)
+                .await?;
+            Ok(())
+        }
+    }
+}
@@ -19,10 +19,33 @@
  *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use crate::{LuminaError, database::DbConn, helpers::events::EventLogger, info_elog};
+use crate::connections;
+use crate::helpers::{passwords, tokens};
+use crate::migration;
+use crate::permissions::Role;
+use crate::postgres::GenericClient;
+use crate::registration::{self, RegistrationMode};
+use crate::two_factor;
+use crate::username_policy::{self, UsernamePolicy};
+use crate::{
+    LuminaError, database::DbConn, email, helpers::events::EventLogger, info_elog, warn_elog,
+};
 use cynthia_con::CynthiaColors;
+use std::net::IpAddr;
 use uuid::Uuid;
 
+/// Sessions that haven't been used for this many days expire.
+pub const SESSION_IDLE_EXPIRY_DAYS: i32 = 20;
+
+/// How long an email verification link stays valid.
+const EMAIL_VERIFICATION_TOKEN_HOURS: i32 = 48;
+
+/// How long a password reset link stays valid.
+const PASSWORD_RESET_TOKEN_MINUTES: i32 = 60;
+
+/// How long after asking an account is actually deleted, during which it can be called off.
+const ACCOUNT_DELETION_GRACE_DAYS: i32 = 30;
+
 #[derive(Debug, Clone)]
 pub struct User {
     pub id: Uuid,
@@ -30,6 +53,33 @@ pub struct User {
     pub username: String,
     #[expect(dead_code, reason = "Will be used for federated posts in the future")]
     pub foreign_instance_id: String, // Added to handle foreign_instance_id
+    pub role: Role,
+}
+
+/// Whether an account is usable, and if not, why.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum AccountState {
+    Active,
+    /// The email address isn't confirmed yet. The account can log in, but not post.
+    PendingVerification,
+    /// Waits for an admin to approve it, see [`RegistrationMode::Approval`].
+    PendingApproval,
+    /// Suspended by a moderator until the Unix timestamp `until`, or for good without it.
+    Suspended {
+        until: Option<i64>,
+    },
+    /// Deactivated by an admin.
+    Deactivated,
+}
+
+impl AccountState {
+    pub fn allows_login(&self) -> bool {
+        matches!(
+            self,
+            AccountState::Active | AccountState::PendingVerification
+        )
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -38,13 +88,46 @@ pub struct SessionReference {
     pub token: String,
 }
 
+/// What a correct password leads to.
+#[derive(Debug, Clone)]
+pub enum AuthenticationOutcome {
+    Authenticated(SessionReference, User),
+    /// The account has two-factor authentication enabled: no session until the challenge is
+    /// answered through [`User::complete_second_factor`].
+    SecondFactorRequired {
+        challenge: String,
+        user: User,
+    },
+}
+
+/// Where a session was started from, recorded when it is created.
+#[derive(Debug, Clone, Default)]
+pub struct SessionOrigin {
+    pub user_agent: Option<String>,
+    pub ip: Option<IpAddr>,
+}
+
+/// A session as shown to its owner when listing their sessions.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SessionInfo {
+    pub session_id: Uuid,
+    /// Unix timestamp of the moment the session was created.
+    pub created_at: i64,
+    /// Unix timestamp of the last time the session was revived.
+    pub last_used_at: i64,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
 impl User {
+    /// Check a password and, unless the account has a second factor, start a session.
     pub async fn authenticate(
         email_username: String,
         password: String,
         db: &DbConn,
         ev_log: EventLogger,
-    ) -> Result<(SessionReference, User), LuminaError> {
+        origin: &SessionOrigin,
+    ) -> Result<AuthenticationOutcome, LuminaError> {
         let user = match User::get_user_by_identifier(email_username, db).await {
             // Replace some errors
 
@@ -52,13 +135,198 @@ impl User {
             Ok(user) => Ok(user),
             Err(e) => Err(e),
         }?;
-        let hashed_password = user.clone().get_hashed_password(db).await?;
-        if bcrypt::verify(password, &hashed_password).map_err(|_| LuminaError::BcryptError)? {
-            user.create_session(db, ev_log).await
+        let hashed_password = user.verify_password(password.clone(), db).await?;
+        if passwords::needs_rehash(&hashed_password) {
+            match user.set_password(password, db).await {
+                Ok(()) => info_elog!(
+                    ev_log,
+                    "Upgraded password hash of {}",
+                    user.username.clone().color_bright_cyan()
+                ),
+                Err(e) => warn_elog!(
+                    ev_log,
+                    "Could not upgrade password hash of {}: {:?}",
+                    user.username.clone().color_bright_cyan(),
+                    e
+                ),
+            }
+        }
+        user.ensure_may_log_in(db).await?;
+        if two_factor::is_enabled(user.id, db).await? {
+            let challenge = two_factor::create_challenge(user.id, db).await?;
+            return Ok(AuthenticationOutcome::SecondFactorRequired { challenge, user });
+        }
+        let (session_reference, user) = user.create_session(db, ev_log, origin).await?;
+        Ok(AuthenticationOutcome::Authenticated(
+            session_reference,
+            user,
+        ))
+    }
+
+    /// Answer the challenge handed out by [`User::authenticate`] with a TOTP or recovery code,
+    /// starting a session if it is right. Wrong codes are counted, see
+    /// [`two_factor::record_failure`].
+    pub async fn complete_second_factor(
+        challenge: String,
+        code: String,
+        db: &DbConn,
+        ev_log: EventLogger,
+        origin: &SessionOrigin,
+    ) -> Result<(SessionReference, User), LuminaError> {
+        let user_id = two_factor::challenge_user(&challenge, db).await?;
+        two_factor::ensure_attempts_left(user_id, db).await?;
+        let user = User::get_user_by_id(user_id, db).await?;
+        if let Err(e) = two_factor::verify(&user, &code, db).await {
+            if matches!(e, LuminaError::AuthenticationWrongSecondFactor) {
+                two_factor::record_failure(&challenge, user_id, db).await?;
+            }
+            return Err(e);
+        }
+        two_factor::drop_challenge(&challenge, db).await?;
+        two_factor::clear_failures(user_id, db).await?;
+        user.ensure_may_log_in(db).await?;
+        user.create_session(db, ev_log, origin).await
+    }
+
+    /// Turn two-factor authentication off. Requires both the password and a current second
+    /// factor, so a hijacked session alone can't do it.
+    pub async fn disable_two_factor(
+        &self,
+        password: String,
+        code: String,
+        db: &DbConn,
+    ) -> Result<(), LuminaError> {
+        self.verify_password(password, db).await?;
+        two_factor::verify(self, &code, db).await?;
+        two_factor::disable(self.id, db).await
+    }
+
+    /// Schedule this account for deletion once the grace period is over, which takes the
+    /// password. Returns the Unix timestamp of the moment it will be deleted.
+    ///
+    /// Asking again while a deletion is scheduled keeps the original moment.
+    pub async fn schedule_deletion(
+        &self,
+        password: String,
+        db: &DbConn,
+    ) -> Result<i64, LuminaError> {
+        self.verify_password(password, db).await?;
+        match db {
+            DbConn::PgsqlConnection(pg_pool, _) => {
+                let client = pg_pool.get().await?;
+                let row = client
+                    .query_one(
+                        "UPDATE users SET deletion_scheduled_for = COALESCE(deletion_scheduled_for, NOW() + make_interval(days => $2)) WHERE id = $1 RETURNING EXTRACT(EPOCH FROM deletion_scheduled_for)::BIGINT",
+                        &[&self.id, &ACCOUNT_DELETION_GRACE_DAYS],
+                    )
+                    .await?;
+                let body = format!(
+                    "Hi {},\n\nYour Lumina account will be deleted in {} days, together with everything you posted. If you change your mind, log in on {} and cancel the deletion before then.\n\nIf this wasn't you, log in, cancel the deletion and change your password.",
+                    self.username,
+                    ACCOUNT_DELETION_GRACE_DAYS,
+                    email::instance_base_url()
+                );
+                email::enqueue(
+                    db,
+                    &self.email,
+                    "Your Lumina account will be deleted",
+                    &body,
+                )
+                .await?;
+                Ok(row.get(0))
+            }
+        }
+    }
+
+    /// Record that this account moved to another instance, as `username@instance`, which takes
+    /// the password. `None` takes the record back.
+    pub async fn set_moved_to(
+        &self,
+        password: String,
+        moved_to: Option<String>,
+        db: &DbConn,
+    ) -> Result<(), LuminaError> {
+        self.verify_password(password, db).await?;
+        if let Some(address) = &moved_to
+            && migration::parse_address(address).is_none()
+        {
+            return Err(LuminaError::MigrationAddressInvalid);
+        }
+        match db {
+            DbConn::PgsqlConnection(pg_pool, _) => {
+                let client = pg_pool.get().await?;
+                client
+                    .execute(
+                        "UPDATE users SET moved_to = $2 WHERE id = $1",
+                        &[
+                            &self.id,
+                            &moved_to.map(|address| address.trim().to_string()),
+                        ],
+                    )
+                    .await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Call off a scheduled deletion. Returns whether one was scheduled.
+    pub async fn cancel_deletion(&self, db: &DbConn) -> Result<bool, LuminaError> {
+        match db {
+            DbConn::PgsqlConnection(pg_pool, _) => {
+                let client = pg_pool.get().await?;
+                let cancelled = client
+                    .execute(
+                        "UPDATE users SET deletion_scheduled_for = NULL WHERE id = $1 AND deletion_scheduled_for IS NOT NULL",
+                        &[&self.id],
+                    )
+                    .await?;
+                Ok(cancelled == 1)
+            }
+        }
+    }
+
+    /// The Unix timestamp this account is scheduled to be deleted at, if it is.
+    pub async fn deletion_scheduled_for(&self, db: &DbConn) -> Result<Option<i64>, LuminaError> {
+        match db {
+            DbConn::PgsqlConnection(pg_pool, _) => {
+                let client = pg_pool.get().await?;
+                let row = client
+                    .query_one(
+                        "SELECT EXTRACT(EPOCH FROM deletion_scheduled_for)::BIGINT FROM users WHERE id = $1",
+                        &[&self.id],
+                    )
+                    .await?;
+                Ok(row.get(0))
+            }
+        }
+    }
+
+    /// Check a password against the stored hash, returning that hash if it is right.
+    async fn verify_password(&self, password: String, db: &DbConn) -> Result<String, LuminaError> {
+        let hashed_password = self.clone().get_hashed_password(db).await?;
+        if passwords::verify_password(password, hashed_password.clone()).await? {
+            Ok(hashed_password)
         } else {
             Err(LuminaError::AuthenticationWrongPassword)
         }
     }
+
+    /// Replace the stored hash with a fresh one of this password.
+    async fn set_password(&self, password: String, db: &DbConn) -> Result<(), LuminaError> {
+        let password = passwords::hash_password(password).await?;
+        match db {
+            DbConn::PgsqlConnection(pg_pool, _) => {
+                let client = pg_pool.get().await?;
+                client
+                    .execute(
+                        "UPDATE users SET password = $2 WHERE id = $1",
+                        &[&self.id, &password],
+                    )
+                    .await?;
+                Ok(())
+            }
+        }
+    }
     async fn get_hashed_password(self, database: &DbConn) -> Result<String, LuminaError> {
         match database {
             DbConn::PgsqlConnection(pg_pool, _) => {
@@ -71,19 +339,29 @@ impl User {
             }
         }
     }
+    /// Register a new account, as far as the registration mode of the instance allows.
+    ///
+    /// In [`RegistrationMode::Approval`] the account is created pending, and can't log in until
+    /// approved.
     pub async fn create_user(
         email: String,
         username: String,
         password: String,
+        mode: RegistrationMode,
+        invite_code: Option<String>,
         db: &DbConn,
     ) -> Result<User, LuminaError> {
+        if mode == RegistrationMode::Closed {
+            return Err(LuminaError::RegistrationClosed);
+        }
+        let username = username_policy::normalise(&username);
         register_validitycheck(email.clone(), username.clone(), password.clone(), db).await?;
+        let username_skeleton = username_policy::skeleton(&username);
         // hash the password
-        let password =
-            bcrypt::hash(password, bcrypt::DEFAULT_COST).map_err(|_| LuminaError::BcryptError)?;
+        let password = passwords::hash_password(password).await?;
         match db {
             DbConn::PgsqlConnection(pg_pool, _) => {
-                let client = pg_pool.get().await?;
+                let mut client = pg_pool.get().await?;
                 // Some username and email validation should be done here
                 // Check if the email is already in use
                 let email_exists = client
@@ -92,23 +370,317 @@ impl User {
                 if !email_exists.is_empty() {
                     return Err(LuminaError::RegisterEmailInUse);
                 }
-                // Check if the username is already in use
+                // Check if the username, or one too much like it, is already in use
                 let username_exists = client
-                    .query("SELECT * FROM users WHERE username = $1", &[&username])
+                    .query(
+                        "SELECT * FROM users WHERE username_skeleton = $1",
+                        &[&username_skeleton],
+                    )
                     .await?;
                 if !username_exists.is_empty() {
                     return Err(LuminaError::RegisterUsernameInUse);
                 }
 
-                let id = client
-					.query_one("INSERT INTO users (email, username, password) VALUES ($1, $2, $3) RETURNING id", &[&email, &username, &password])
+                let transaction = client.transaction().await?;
+                if mode == RegistrationMode::Invite {
+                    let code = invite_code.ok_or(LuminaError::RegisterInviteInvalid)?;
+                    registration::redeem_invite(&code, &transaction).await?;
+                }
+                let pending_approval = mode == RegistrationMode::Approval;
+                let id = transaction
+					.query_one("INSERT INTO users (email, username, username_skeleton, password, email_verified, pending_approval) VALUES ($1, $2, $3, $4, FALSE, $5) RETURNING id", &[&email, &username, &username_skeleton, &password, &pending_approval])
 					.await
 					?;
-                Ok(User {
+                let user = User {
                     id: id.get(0),
                     email,
                     username,
                     foreign_instance_id: "".to_string(), // Default value for new users
+                    role: Role::User,
+                };
+                // In the same transaction, so there is never an account without a way to verify it.
+                user.queue_email_verification(&transaction).await?;
+                transaction.commit().await?;
+                Ok(user)
+            }
+        }
+    }
+
+    /// The state of this account, which decides whether it may log in.
+    pub async fn account_state(&self, db: &DbConn) -> Result<AccountState, LuminaError> {
+        match db {
+            DbConn::PgsqlConnection(pg_pool, _) => {
+                let client = pg_pool.get().await?;
+                let row = client
+                    .query_one(
+                        "SELECT deactivated_at IS NOT NULL, COALESCE(suspended_until > NOW(), FALSE), CASE WHEN suspended_until = 'infinity' THEN NULL ELSE EXTRACT(EPOCH FROM suspended_until)::BIGINT END, pending_approval, email_verified FROM users WHERE id = $1",
+                        &[&self.id],
+                    )
+                    .await?;
+                Ok(if row.get(0) {
+                    AccountState::Deactivated
+                } else if row.get(1) {
+                    AccountState::Suspended { until: row.get(2) }
+                } else if row.get(3) {
+                    AccountState::PendingApproval
+                } else if row.get(4) {
+                    AccountState::Active
+                } else {
+                    AccountState::PendingVerification
+                })
+            }
+        }
+    }
+
+    /// Fails with [`LuminaError::AccountLocked`] if the state of this account doesn't allow logging
+    /// in, after ending all of its sessions.
+    pub async fn ensure_may_log_in(&self, db: &DbConn) -> Result<(), LuminaError> {
+        let state = self.account_state(db).await?;
+        if state.allows_login() {
+            return Ok(());
+        }
+        match db {
+            DbConn::PgsqlConnection(pg_pool, redis_pool) => {
+                let client = pg_pool.get().await?;
+                client
+                    .execute("DELETE FROM sessions WHERE user_id = $1", &[&self.id])
+                    .await?;
+                connections::disconnect(redis_pool, self.id, None, "Account locked").await;
+            }
+        }
+        Err(LuminaError::AccountLocked(Box::new(state)))
+    }
+
+    /// Deactivate or reactivate the account named `username`. Deactivating ends all of its
+    /// sessions. Returns whether there is such an account.
+    pub async fn set_deactivated(
+        username: &str,
+        deactivated: bool,
+        db: &DbConn,
+    ) -> Result<bool, LuminaError> {
+        match db {
+            DbConn::PgsqlConnection(pg_pool, redis_pool) => {
+                let client = pg_pool.get().await?;
+                let user_id: Option<Uuid> = client
+                    .query_opt(
+                        "UPDATE users SET deactivated_at = CASE WHEN $2 THEN COALESCE(deactivated_at, NOW()) END WHERE username = $1 RETURNING id",
+                        &[&username, &deactivated],
+                    )
+                    .await?
+                    .map(|row| row.get(0));
+                if deactivated && let Some(user_id) = user_id {
+                    client
+                        .execute("DELETE FROM sessions WHERE user_id = $1", &[&user_id])
+                        .await?;
+                    connections::disconnect(redis_pool, user_id, None, "Account deactivated").await;
+                }
+                Ok(user_id.is_some())
+            }
+        }
+    }
+
+    /// Issue a fresh verification token for this user and queue the email carrying it.
+    pub async fn send_email_verification(&self, db: &DbConn) -> Result<(), LuminaError> {
+        match db {
+            DbConn::PgsqlConnection(pg_pool, _) => {
+                let client = pg_pool.get().await?;
+                self.queue_email_verification(&*client).await
+            }
+        }
+    }
+
+    /// Like [`User::send_email_verification`], but takes a client so it can be part of the
+    /// transaction creating the account.
+    async fn queue_email_verification(
+        &self,
+        client: &impl GenericClient,
+    ) -> Result<(), LuminaError> {
+        let token = tokens::generate_token();
+        client
+            .execute(
+                "INSERT INTO email_verification_tokens (token, user_id, expires_at) VALUES ($1, $2, NOW() + make_interval(hours => $3))",
+                &[&tokens::hash_token(&token), &self.id, &EMAIL_VERIFICATION_TOKEN_HOURS],
+            )
+            .await?;
+        let body = format!(
+            "Hi {},\n\nWelcome to Lumina! Please confirm your email address by opening the link below:\n\n{}/?verify_email={}\n\nThis link expires in {} hours. If you did not create this account, you can ignore this email.",
+            self.username,
+            email::instance_base_url(),
+            token,
+            EMAIL_VERIFICATION_TOKEN_HOURS
+        );
+        email::enqueue_with(client, &self.email, "Confirm your Lumina account", &body).await
+    }
+
+    /// Consume an email verification token, marking the owning account as verified.
+    pub async fn verify_email(token: String, db: &DbConn) -> Result<User, LuminaError> {
+        match db {
+            DbConn::PgsqlConnection(pg_pool, _) => {
+                let mut client = pg_pool.get().await?;
+                // The token is only used up if the account is verified with it.
+                let transaction = client.transaction().await?;
+                let row = transaction
+                    .query_opt(
+                        "DELETE FROM email_verification_tokens WHERE token = $1 AND expires_at > NOW() RETURNING user_id",
+                        &[&tokens::hash_token(&token)],
+                    )
+                    .await?
+                    .ok_or(LuminaError::TokenInvalid)?;
+                let user_id: Uuid = row.get(0);
+                let user = transaction
+                    .query_one(
+                        "UPDATE users SET email_verified = TRUE WHERE id = $1 RETURNING id, email, username, COALESCE(foreign_instance_id, ''), role",
+                        &[&user_id],
+                    )
+                    .await?;
+                // Any other outstanding links for this account are useless now.
+                transaction
+                    .execute(
+                        "DELETE FROM email_verification_tokens WHERE user_id = $1",
+                        &[&user_id],
+                    )
+                    .await?;
+                transaction.commit().await?;
+                Ok(User {
+                    id: user.get(0),
+                    email: user.get(1),
+                    username: user.get(2),
+                    foreign_instance_id: user.get(3),
+                    role: Role::from_db(user.get(4)),
+                })
+            }
+        }
+    }
+
+    /// Whether this user has confirmed their email address.
+    pub async fn is_email_verified(&self, db: &DbConn) -> Result<bool, LuminaError> {
+        match db {
+            DbConn::PgsqlConnection(pg_pool, _) => {
+                let client = pg_pool.get().await?;
+                let row = client
+                    .query_one(
+                        "SELECT email_verified FROM users WHERE id = $1",
+                        &[&self.id],
+                    )
+                    .await?;
+                Ok(row.get(0))
+            }
+        }
+    }
+
+    /// Fails with [`LuminaError::EmailNotVerified`] if this user may not post yet.
+    pub async fn ensure_may_post(&self, db: &DbConn) -> Result<(), LuminaError> {
+        if self.is_email_verified(db).await? {
+            Ok(())
+        } else {
+            Err(LuminaError::EmailNotVerified)
+        }
+    }
+
+    /// Start a password reset for the account with this email address.
+    ///
+    /// Succeeds silently when no such account exists, so callers can't be used to find out
+    /// which addresses are registered.
+    pub async fn request_password_reset(email: String, db: &DbConn) -> Result<(), LuminaError> {
+        match db {
+            DbConn::PgsqlConnection(pg_pool, _) => {
+                let client = pg_pool.get().await?;
+                let Some(row) = client
+                    .query_opt("SELECT id, username FROM users WHERE email = $1", &[&email])
+                    .await?
+                else {
+                    return Ok(());
+                };
+                let user_id: Uuid = row.get(0);
+                let username: String = row.get(1);
+                let token = tokens::generate_token();
+                client
+                    .execute(
+                        "INSERT INTO password_reset_tokens (token, user_id, expires_at) VALUES ($1, $2, NOW() + make_interval(mins => $3))",
+                        &[&tokens::hash_token(&token), &user_id, &PASSWORD_RESET_TOKEN_MINUTES],
+                    )
+                    .await?;
+                let body = format!(
+                    "Hi {},\n\nSomeone asked to reset the password of your Lumina account. To choose a new password, open the link below:\n\n{}/?reset_password={}\n\nThis link expires in {} minutes. If this wasn't you, you can ignore this email; your password stays the same.",
+                    username,
+                    email::instance_base_url(),
+                    token,
+                    PASSWORD_RESET_TOKEN_MINUTES
+                );
+                email::enqueue(db, &email, "Reset your Lumina password", &body).await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Consume a password reset token and set a new password.
+    ///
+    /// All existing sessions of the account are ended, since the old password may have been
+    /// compromised.
+    pub async fn reset_password(
+        token: String,
+        new_password: String,
+        db: &DbConn,
+    ) -> Result<User, LuminaError> {
+        password_validitycheck(&new_password)?;
+        let password = passwords::hash_password(new_password).await?;
+        match db {
+            DbConn::PgsqlConnection(pg_pool, redis_pool) => {
+                let mut client = pg_pool.get().await?;
+                // The token is only used up if the password is changed with it.
+                let transaction = client.transaction().await?;
+                let row = transaction
+                    .query_opt(
+                        "DELETE FROM password_reset_tokens WHERE token = $1 AND expires_at > NOW() RETURNING user_id",
+                        &[&tokens::hash_token(&token)],
+                    )
+                    .await?
+                    .ok_or(LuminaError::TokenInvalid)?;
+                let user_id: Uuid = row.get(0);
+                // Following the link proves control over the mailbox too.
+                let user = transaction
+                    .query_one(
+                        "UPDATE users SET password = $2, email_verified = TRUE WHERE id = $1 RETURNING id, email, username, COALESCE(foreign_instance_id, ''), role",
+                        &[&user_id, &password],
+                    )
+                    .await?;
+                transaction
+                    .execute(
+                        "DELETE FROM password_reset_tokens WHERE user_id = $1",
+                        &[&user_id],
+                    )
+                    .await?;
+                transaction
+                    .execute("DELETE FROM sessions WHERE user_id = $1", &[&user_id])
+                    .await?;
+                transaction.commit().await?;
+                connections::disconnect(redis_pool, user_id, None, "Password reset").await;
+                Ok(User {
+                    id: user.get(0),
+                    email: user.get(1),
+                    username: user.get(2),
+                    foreign_instance_id: user.get(3),
+                    role: Role::from_db(user.get(4)),
+                })
+            }
+        }
+    }
+    pub async fn get_user_by_id(id: Uuid, db: &DbConn) -> Result<User, LuminaError> {
+        match db {
+            DbConn::PgsqlConnection(pg_pool, _) => {
+                let client = pg_pool.get().await?;
+                let user = client
+                    .query_one(
+                        "SELECT id, email, username, COALESCE(foreign_instance_id, ''), role FROM users WHERE id = $1",
+                        &[&id],
+                    )
+                    .await?;
+                Ok(User {
+                    id: user.get(0),
+                    email: user.get(1),
+                    username: user.get(2),
+                    foreign_instance_id: user.get(3),
+                    role: Role::from_db(user.get(4)),
                 })
             }
         }
@@ -127,7 +699,7 @@ impl User {
                 let client = pg_pool.get().await?;
                 let user = client
 					.query_one(
-						&format!("SELECT id, email, username, COALESCE(foreign_instance_id, '') FROM users WHERE {} = $1", identifyer_type),
+						&format!("SELECT id, email, username, COALESCE(foreign_instance_id, ''), role FROM users WHERE {} = $1", identifyer_type),
 						&[&identifier],
 					)
 					.await
@@ -137,6 +709,7 @@ impl User {
                     email: user.get(1),
                     username: user.get(2),
                     foreign_instance_id: user.get(3),
+                    role: Role::from_db(user.get(4)),
                 })
             }
         }
@@ -146,17 +719,18 @@ impl User {
         self,
         db: &DbConn,
         ev_log: EventLogger,
+        origin: &SessionOrigin,
     ) -> Result<(SessionReference, User), LuminaError> {
         let user = self;
         let user_id = user.id;
         match db {
             DbConn::PgsqlConnection(pg_pool, _) => {
                 let client = pg_pool.get().await?;
-                let session_key = Uuid::new_v4().to_string();
+                let session_key = tokens::generate_token();
                 let id = client
                     .query_one(
-                        "INSERT INTO sessions (user_id, session_key) VALUES ($1, $2) RETURNING id",
-                        &[&user_id, &session_key],
+                        "INSERT INTO sessions (user_id, token_hash, user_agent, ip_address) VALUES ($1, $2, $3, $4) RETURNING id",
+                        &[&user_id, &tokens::hash_token(&session_key), &origin.user_agent, &origin.ip],
                     )
                     .await?;
                 info_elog!(
@@ -175,23 +749,95 @@ impl User {
             }
         }
     }
+    /// Look up the session belonging to this token and mark it as used just now.
+    ///
+    /// Sessions that went unused for longer than [`SESSION_IDLE_EXPIRY_DAYS`] are not revived,
+    /// even if maintenance hasn't cleaned them up yet.
     pub async fn revive_session_from_token(
         token: String,
         db: &DbConn,
-    ) -> Result<User, LuminaError> {
+    ) -> Result<(SessionReference, User), LuminaError> {
         match db {
             DbConn::PgsqlConnection(pg_pool, _) => {
                 let client = pg_pool.get().await?;
-                let user = client
-					.query_one("SELECT users.id, users.email, users.username FROM users JOIN sessions ON users.id = sessions.user_id WHERE sessions.session_key = $1", &[&token])
+                let row = client
+					.query_one("WITH s AS (UPDATE sessions SET last_used_at = NOW() WHERE token_hash = $1 AND last_used_at > NOW() - make_interval(days => $2) RETURNING id, user_id) SELECT s.id, users.id, users.email, users.username, users.role FROM s JOIN users ON users.id = s.user_id", &[&tokens::hash_token(&token), &SESSION_IDLE_EXPIRY_DAYS])
 					.await
 					?;
-                Ok(User {
-                    id: user.get(0),
-                    email: user.get(1),
-                    username: user.get(2),
+                let user = User {
+                    id: row.get(1),
+                    email: row.get(2),
+                    username: row.get(3),
                     foreign_instance_id: "".to_string(), // Default value for revived sessions
-                })
+                    role: Role::from_db(row.get(4)),
+                };
+                user.ensure_may_log_in(db).await?;
+                Ok((
+                    SessionReference {
+                        session_id: row.get(0),
+                        token,
+                    },
+                    user,
+                ))
+            }
+        }
+    }
+
+    /// List the sessions of this user, most recently used first.
+    pub async fn list_sessions(&self, db: &DbConn) -> Result<Vec<SessionInfo>, LuminaError> {
+        match db {
+            DbConn::PgsqlConnection(pg_pool, _) => {
+                let client = pg_pool.get().await?;
+                let rows = client
+                    .query(
+                        "SELECT id, EXTRACT(EPOCH FROM created_at)::BIGINT, EXTRACT(EPOCH FROM last_used_at)::BIGINT, user_agent, ip_address FROM sessions WHERE user_id = $1 ORDER BY last_used_at DESC",
+                        &[&self.id],
+                    )
+                    .await?;
+                Ok(rows
+                    .into_iter()
+                    .map(|row| SessionInfo {
+                        session_id: row.get(0),
+                        created_at: row.get(1),
+                        last_used_at: row.get(2),
+                        user_agent: row.get(3),
+                        ip_address: row.get::<_, Option<IpAddr>>(4).map(|ip| ip.to_string()),
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    /// End one session of this user. Returns whether there was such a session.
+    pub async fn revoke_session(&self, session_id: Uuid, db: &DbConn) -> Result<bool, LuminaError> {
+        match db {
+            DbConn::PgsqlConnection(pg_pool, redis_pool) => {
+                let client = pg_pool.get().await?;
+                let deleted = client
+                    .execute(
+                        "DELETE FROM sessions WHERE id = $1 AND user_id = $2",
+                        &[&session_id, &self.id],
+                    )
+                    .await?;
+                if deleted > 0 {
+                    connections::disconnect(redis_pool, self.id, Some(session_id), "Session ended")
+                        .await;
+                }
+                Ok(deleted > 0)
+            }
+        }
+    }
+
+    /// End every session of this user. Returns how many were ended.
+    pub async fn revoke_all_sessions(&self, db: &DbConn) -> Result<u64, LuminaError> {
+        match db {
+            DbConn::PgsqlConnection(pg_pool, redis_pool) => {
+                let client = pg_pool.get().await?;
+                let deleted = client
+                    .execute("DELETE FROM sessions WHERE user_id = $1", &[&self.id])
+                    .await?;
+                connections::disconnect(redis_pool, self.id, None, "Logged out everywhere").await;
+                Ok(deleted)
             }
         }
     }
@@ -203,6 +849,15 @@ pub(crate) async fn register_validitycheck(
     password: String,
     db: &DbConn,
 ) -> Result<(), LuminaError> {
+    //
+    //
+    // Username checks
+    //
+    let username = UsernamePolicy::from_env()?
+        .check(&username)
+        .map_err(LuminaError::RegisterUsernameInvalid)?;
+    // Usernames are unique by skeleton, so that's what goes in the bloom filter too.
+    let username_skeleton = username_policy::skeleton(&username);
     {
         // Check if the email or username is already in use using fastbloom algorithm with Redis, and fallback to DB check if not found. If not in either, we can go on.
         match db {
@@ -229,14 +884,17 @@ pub(crate) async fn register_validitycheck(
                 }
                 let username_exists: bool = redis::cmd("BF.EXISTS")
                     .arg(&username_key)
-                    .arg(&username)
+                    .arg(&username_skeleton)
                     .query_async(&mut *redis_conn)
                     .await
                     .unwrap_or(false);
                 if username_exists {
                     // Fallback to DB check if in bloom filter
                     let username_db = client
-                        .query("SELECT * FROM users WHERE username = $1", &[&username])
+                        .query(
+                            "SELECT * FROM users WHERE username_skeleton = $1",
+                            &[&username_skeleton],
+                        )
                         .await?;
                     if !username_db.is_empty() {
                         return Err(LuminaError::RegisterUsernameInUse);
@@ -257,12 +915,15 @@ pub(crate) async fn register_validitycheck(
                     return Err(LuminaError::RegisterEmailInUse);
                 }
                 let username_db = client
-                    .query("SELECT * FROM users WHERE username = $1", &[&username])
+                    .query(
+                        "SELECT * FROM users WHERE username_skeleton = $1",
+                        &[&username_skeleton],
+                    )
                     .await?;
                 if !username_db.is_empty() {
                     let _: () = redis::cmd("BF.ADD")
                         .arg(&username_key)
-                        .arg(&username)
+                        .arg(&username_skeleton)
                         .query_async(&mut *redis_conn)
                         .await
                         .unwrap_or(());
@@ -286,64 +947,15 @@ pub(crate) async fn register_validitycheck(
         };
     }
 
-    //
-    //
-    // Username checks
-    //
-    {
-        // Check if username is valid
-        if username.chars().any(|c| {
-            match c {
-                ' ' | '\\' | '/' | '@' | '\n' | '\r' | '\t' | '\x0b' | '\'' | '"' | '(' | ')'
-                | '`' | '%' | '?' | '!' => true,
-                '#' => (
-                    // Make sure, if a # is in the username, only 4 numbers may follow it.
-                    || {
-                        let split_username = username.split('#');
-                        let array_split_username: Vec<&str> = split_username.collect();
-                        let lastbit = username.replacen(array_split_username[0], "", 1);
-                        let firstbit = username.replacen(&*lastbit, "", 1);
-                        let vec_split_username: Vec<&str> = vec![&*firstbit, &*lastbit];
-                        // println!("array: {:?}", array_split_username);
-                        // println!("vec: {:?}", vec_split_username);
-                        if vec_split_username.is_empty() || array_split_username[1].is_empty() {
-                            return true;
-                        };
-                        (!array_split_username[1].chars().all(char::is_numeric))
-                            || !(vec_split_username[1].len() == 5
-                                || vec_split_username[1].len() == 7)
-                    }
-                )(),
-                _ => false,
-            }
-        }) || !username
-            .replace(['_', '-', '.'], "")
-            .replacen('#', "", 1)
-            .chars()
-            .all(char::is_alphanumeric)
-        {
-            return Err(LuminaError::RegisterUsernameInvalid(
-                OnRegisterUsernameInvalid::InvalidCharacters,
-            ));
-        }
-        // Check if the username is too long
-        if username.len() > 20 {
-            return Err(LuminaError::RegisterUsernameInvalid(
-                OnRegisterUsernameInvalid::TooLong,
-            ));
-        }
-        // Check if the username is too short
-        if username.len() < 4 {
-            return Err(LuminaError::RegisterUsernameInvalid(
-                OnRegisterUsernameInvalid::TooShort,
-            ));
-        }
-    }
-
     //
     //
     // Password checks
     //
+    password_validitycheck(&password)
+}
+
+/// The password rules, shared by registration and password resets.
+pub(crate) fn password_validitycheck(password: &str) -> Result<(), LuminaError> {
     {
         if password.len() < 8 {
             return Err(LuminaError::RegisterPasswordNotValid(
@@ -374,11 +986,13 @@ pub(crate) async fn register_validitycheck(
     Ok(())
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub(crate) enum OnRegisterUsernameInvalid {
     TooLong,
     TooShort,
     InvalidCharacters,
+    InvalidDiscriminator,
+    Reserved,
 }
 impl std::fmt::Display for OnRegisterUsernameInvalid {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -391,6 +1005,10 @@ impl std::fmt::Display for OnRegisterUsernameInvalid {
                 OnRegisterUsernameInvalid::InvalidCharacters => {
                     "Username contains invalid characters"
                 }
+                OnRegisterUsernameInvalid::InvalidDiscriminator => {
+                    "Username has an invalid discriminator"
+                }
+                OnRegisterUsernameInvalid::Reserved => "Username is reserved",
             }
         )
     }
@@ -424,3 +1042,115 @@ impl std::fmt::Display for OnRegisterPasswordNotValid {
         )
     }
 }
+
+/// The registration form field an error is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegisterField {
+    Email,
+    Username,
+    Password,
+    InviteCode,
+}
+
+/// Machine-readable reason a registration was refused, for clients to word in their own
+/// language.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegisterErrorCode {
+    EmailInUse,
+    EmailInvalid,
+    UsernameInUse,
+    UsernameTooLong,
+    UsernameTooShort,
+    UsernameInvalidCharacters,
+    UsernameInvalidDiscriminator,
+    UsernameReserved,
+    PasswordTooShort,
+    PasswordTooLong,
+    PasswordMissingUppercase,
+    PasswordMissingLowercase,
+    PasswordMissingNumber,
+    InviteInvalid,
+    RegistrationClosed,
+    /// Not the user's fault. Nothing to correct in the form.
+    Internal,
+}
+
+impl RegisterErrorCode {
+    pub fn field(&self) -> Option<RegisterField> {
+        match self {
+            RegisterErrorCode::EmailInUse | RegisterErrorCode::EmailInvalid => {
+                Some(RegisterField::Email)
+            }
+            RegisterErrorCode::UsernameInUse
+            | RegisterErrorCode::UsernameTooLong
+            | RegisterErrorCode::UsernameTooShort
+            | RegisterErrorCode::UsernameInvalidCharacters
+            | RegisterErrorCode::UsernameInvalidDiscriminator
+            | RegisterErrorCode::UsernameReserved => Some(RegisterField::Username),
+            RegisterErrorCode::PasswordTooShort
+            | RegisterErrorCode::PasswordTooLong
+            | RegisterErrorCode::PasswordMissingUppercase
+            | RegisterErrorCode::PasswordMissingLowercase
+            | RegisterErrorCode::PasswordMissingNumber => Some(RegisterField::Password),
+            RegisterErrorCode::InviteInvalid => Some(RegisterField::InviteCode),
+            RegisterErrorCode::RegistrationClosed | RegisterErrorCode::Internal => None,
+        }
+    }
+}
+
+/// A refused registration, as sent to clients.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RegisterError {
+    pub field: Option<RegisterField>,
+    pub code: RegisterErrorCode,
+    /// English description, for clients that don't know the code.
+    pub message: String,
+}
+
+impl From<&LuminaError> for RegisterError {
+    fn from(err: &LuminaError) -> Self {
+        let code = match err {
+            LuminaError::RegisterEmailInUse => RegisterErrorCode::EmailInUse,
+            LuminaError::RegisterEmailNotValid => RegisterErrorCode::EmailInvalid,
+            LuminaError::RegisterUsernameInUse => RegisterErrorCode::UsernameInUse,
+            LuminaError::RegisterUsernameInvalid(why) => match why {
+                OnRegisterUsernameInvalid::TooLong => RegisterErrorCode::UsernameTooLong,
+                OnRegisterUsernameInvalid::TooShort => RegisterErrorCode::UsernameTooShort,
+                OnRegisterUsernameInvalid::InvalidCharacters => {
+                    RegisterErrorCode::UsernameInvalidCharacters
+                }
+                OnRegisterUsernameInvalid::InvalidDiscriminator => {
+                    RegisterErrorCode::UsernameInvalidDiscriminator
+                }
+                OnRegisterUsernameInvalid::Reserved => RegisterErrorCode::UsernameReserved,
+            },
+            LuminaError::RegisterPasswordNotValid(why) => match why {
+                OnRegisterPasswordNotValid::TooShort => RegisterErrorCode::PasswordTooShort,
+                OnRegisterPasswordNotValid::TooLong => RegisterErrorCode::PasswordTooLong,
+                OnRegisterPasswordNotValid::MissingUppercase => {
+                    RegisterErrorCode::PasswordMissingUppercase
+                }
+                OnRegisterPasswordNotValid::MissingLowercase => {
+                    RegisterErrorCode::PasswordMissingLowercase
+                }
+                OnRegisterPasswordNotValid::MissingNumber => {
+                    RegisterErrorCode::PasswordMissingNumber
+                }
+            },
+            LuminaError::RegisterInviteInvalid => RegisterErrorCode::InviteInvalid,
+            LuminaError::RegistrationClosed => RegisterErrorCode::RegistrationClosed,
+            _ => RegisterErrorCode::Internal,
+        };
+        let message = match code {
+            RegisterErrorCode::Internal => String::from("Something went wrong, try again later"),
+            _ => err.to_string(),
+        };
+        RegisterError {
+            field: code.field(),
+            code,
+            message,
+        }
+    }
+}
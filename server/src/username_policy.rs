@@ -0,0 +1,179 @@
+//! Lumina > Server > Username policy
+//!
+//! What makes a username acceptable, and when two usernames count as the same.
+//!
+//! Usernames are stored NFC-normalised, as typed. For uniqueness they are reduced to a
+//! _skeleton_: case-folded, compatibility-normalised and with confusable characters replaced by
+//! their prototype ([UTS #39](https://www.unicode.org/reports/tr39/#Confusable_Detection)). Two
+//! usernames with the same skeleton can't both exist, so `Strawmelon`, `strawmeIon` and a
+//! Cyrillic-`а` look-alike all claim the same name.
+
+/*
+ *     Lumina/Peonies
+ *     Copyright (C) 2018-2026 MLC 'Strawmelonjuice'  Bloeiman and contributors.
+ *
+ *     This program is free software: you can redistribute it and/or modify
+ *     it under the terms of the GNU Affero General Public License as published
+ *     by the Free Software Foundation, either version 3 of the License, or
+ *     (at your option) any later version.
+ *
+ *     This program is distributed in the hope that it will be useful,
+ *     but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *     MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *     GNU Affero General Public License for more details.
+ *
+ *     You should have received a copy of the GNU Affero General Public License
+ *     along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::EnvVar::*;
+use crate::errors::LuminaError;
+use crate::user::OnRegisterUsernameInvalid;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::confusable_detection::skeleton as confusable_skeleton;
+use unicode_security::{RestrictionLevel, RestrictionLevelDetection};
+
+/// Bounds on the length of a username, discriminator included, in characters.
+const MIN_LENGTH: usize = 4;
+const MAX_LENGTH: usize = 20;
+
+/// Names nobody can register, because they would pass for the instance itself or collide with
+/// routes and timeline names. Compared by skeleton, so look-alikes are covered too.
+pub(crate) const RESERVED_USERNAMES: &[&str] = &[
+    "admin",
+    "administrator",
+    "root",
+    "system",
+    "moderator",
+    "mod",
+    "staff",
+    "support",
+    "lumina",
+    "peonies",
+    "instance",
+    "everyone",
+    "global",
+    "home",
+    "following",
+    "connection",
+    "static",
+    "licence",
+    "license",
+    "favicon.ico",
+    "api",
+    "login",
+    "logout",
+    "register",
+    "settings",
+];
+
+/// Separators allowed in the name, besides letters and digits.
+const SEPARATORS: [char; 3] = ['_', '-', '.'];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct UsernamePolicy {
+    /// The digit counts a `#` discriminator may have. Empty if discriminators aren't allowed.
+    discriminator_digits: Vec<usize>,
+}
+
+impl Default for UsernamePolicy {
+    fn default() -> Self {
+        UsernamePolicy {
+            discriminator_digits: vec![4, 6],
+        }
+    }
+}
+
+impl UsernamePolicy {
+    pub(crate) fn new(discriminator_digits: Vec<usize>) -> Self {
+        UsernamePolicy {
+            discriminator_digits,
+        }
+    }
+
+    /// Reads the allowed discriminator lengths from `LUMINA_DISCRIMINATORS`: a comma-separated
+    /// list of digit counts, or `none`.
+    pub(crate) fn from_env() -> Result<Self, LuminaError> {
+        let Ok(setting) = std::env::var("LUMINA_DISCRIMINATORS") else {
+            return Ok(UsernamePolicy::default());
+        };
+        if setting.trim().eq_ignore_ascii_case("none") {
+            return Ok(UsernamePolicy::new(vec![]));
+        }
+        setting
+            .split(',')
+            .map(|digits| match digits.trim().parse::<usize>() {
+                Ok(n) if n > 0 && n < MAX_LENGTH => Ok(n),
+                _ => Err(LuminaError::ConfInvalid(LUMINA_DISCRIMINATORS)),
+            })
+            .collect::<Result<Vec<usize>, LuminaError>>()
+            .map(UsernamePolicy::new)
+    }
+
+    /// Check a username against the policy, returning it in the form it should be stored in.
+    pub(crate) fn check(&self, username: &str) -> Result<String, OnRegisterUsernameInvalid> {
+        let username = normalise(username);
+        let length = username.chars().count();
+        if length > MAX_LENGTH {
+            return Err(OnRegisterUsernameInvalid::TooLong);
+        }
+        if length < MIN_LENGTH {
+            return Err(OnRegisterUsernameInvalid::TooShort);
+        }
+        let name = match username.split_once('#') {
+            Some((name, discriminator)) => {
+                if !(discriminator.chars().all(|c| c.is_ascii_digit())
+                    && self.discriminator_digits.contains(&discriminator.len()))
+                {
+                    return Err(OnRegisterUsernameInvalid::InvalidDiscriminator);
+                }
+                name
+            }
+            None => username.as_str(),
+        };
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_alphanumeric() || SEPARATORS.contains(&c))
+        {
+            return Err(OnRegisterUsernameInvalid::InvalidCharacters);
+        }
+        // Only characters fit for identifiers, and no mixing of scripts beyond the common
+        // combinations (like Latin with Japanese), which is where look-alikes come from.
+        let letters: String = name.chars().filter(|c| !SEPARATORS.contains(c)).collect();
+        if !letters
+            .as_str()
+            .check_restriction_level(RestrictionLevel::HighlyRestrictive)
+        {
+            return Err(OnRegisterUsernameInvalid::InvalidCharacters);
+        }
+        let name_skeleton = skeleton(name);
+        if RESERVED_USERNAMES
+            .iter()
+            .any(|reserved| skeleton(reserved) == name_skeleton)
+        {
+            return Err(OnRegisterUsernameInvalid::Reserved);
+        }
+        Ok(username)
+    }
+}
+
+/// The form usernames are stored in.
+pub(crate) fn normalise(username: &str) -> String {
+    username.trim().nfc().collect()
+}
+
+/// The key usernames are unique by. See the module documentation.
+pub(crate) fn skeleton(username: &str) -> String {
+    // Prototypes are taken before and after case folding, as some letters only have one in
+    // upper case (`I`) and others only in lower case (`m`, which looks like `rn`).
+    let normalised: String = username.nfkc().collect();
+    let folded: String = confusable_skeleton(&normalised)
+        .flat_map(char::to_lowercase)
+        .collect();
+    // `I` is the same as `i` by case, and the same as `l` by looks, so those three have to be
+    // one letter, or `ADMIN` and `admin` would get different skeletons.
+    confusable_skeleton(&folded)
+        .map(|c| if c == 'i' { 'l' } else { c })
+        .collect()
+}